@@ -1,84 +1,373 @@
 use crate::buckets::Buckets;
-use crate::backends::console;
-use crate::backends::graphite;
-use crate::backends::statsd;
-use crate::backends::statsd_zmq;
+use crate::route::{self, Route, Router};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use time;
 
 /// Defines the interface that backends use to publish
 /// metrics to their storage system.
-pub trait Backend {
+pub trait Backend: Send {
+    /// The type of the backend, instances are named by their config section.
+    fn name(&self) -> &str;
+
+    /// Called once before the first flush, e.g. to bind sockets.
+    fn start(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
     /// This method should flush the current data to the backend.
     ///
     /// Called on server `flush` events, which occur on a timer
     /// (every 10 seconds by default).
-    fn flush_buckets(&mut self, buckets: &Buckets) -> ();
+    fn flush_buckets(&mut self, buckets: &Buckets) -> Result<(), String>;
+
+    /// Called once when the server stops, after the final flush.
+    fn shutdown(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Backend specific state, shown by the `backends` admin command.
+    fn status(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+
+/// The outcome of the flushes of one backend.
+#[derive(Debug, Clone)]
+pub struct BackendStatus {
+    pub name: String,
+    pub flushes: u64,
+    pub failures: u64,
+    pub last_success: Option<time::Timespec>,
+    pub last_error: Option<(time::Timespec, String)>,
+    pub last_duration_ms: i64,
+    pub flushing_since: Option<time::Timespec>,
+    pub details: Vec<(String, String)>,
+    // Failures already sent as the flush_errors counter
+    reported_failures: u64,
+    // Whether the running flush was already failed for its deadline
+    overdue: bool,
+}
+
+/// The status of a backend, shared between its worker and the server.
+pub type SharedStatus = Arc<Mutex<BackendStatus>>;
+
+impl BackendStatus {
+    pub fn new(name: &str) -> BackendStatus {
+        BackendStatus {
+            name: name.to_string(),
+            flushes: 0,
+            failures: 0,
+            last_success: None,
+            last_error: None,
+            last_duration_ms: 0,
+            flushing_since: None,
+            details: Vec::new(),
+            reported_failures: 0,
+            overdue: false,
+        }
+    }
+
+    /// Record the outcome of a flush that ended at `at`.
+    pub fn record(&mut self, at: time::Timespec, duration_ms: i64, result: &Result<(), String>) {
+        self.flushes += 1;
+        self.last_duration_ms = duration_ms;
+        match *result {
+            Ok(_) => self.last_success = Some(at),
+            // The interval already counts as failed.
+            Err(ref e) if self.overdue => self.last_error = Some((at, e.clone())),
+            Err(ref e) => self.record_failure(at, e.clone()),
+        }
+    }
+
+    /// Record an interval that failed, whether flushed or not.
+    pub fn record_failure(&mut self, at: time::Timespec, error: String) {
+        self.failures += 1;
+        self.last_error = Some((at, error));
+    }
+
+    /// Render the status for the admin console.
+    pub fn format(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{}:", self.name).unwrap();
+        writeln!(out, " flushes: {}", self.flushes).unwrap();
+        writeln!(out, " failures: {}", self.failures).unwrap();
+        writeln!(out, " last_duration_ms: {}", self.last_duration_ms).unwrap();
+        if let Some(success) = self.last_success {
+            writeln!(out, " last_success: {}", time::at(success).rfc822()).unwrap();
+        }
+        if let Some((at, ref error)) = self.last_error {
+            writeln!(out, " last_error: {} {}", time::at(at).rfc822(), error).unwrap();
+        }
+        if let Some(since) = self.flushing_since {
+            writeln!(out, " flushing_since: {}", time::at(since).rfc822()).unwrap();
+        }
+        for (key, value) in self.details.iter() {
+            writeln!(out, " {}: {}", key, value).unwrap();
+        }
+        out
+    }
+}
+
+
+/// A backend along with its route, flush deadline and the bookkeeping of
+/// its flushes.
+pub struct Managed {
+    name: String,
+    backend: Box<dyn Backend>,
+    router: Router,
+    timeout: Duration,
+    status: SharedStatus,
+}
+
+impl Managed {
+    /// Manage a backend instance under a unique name.
+    pub fn new(name: &str, backend: Box<dyn Backend>) -> Managed {
+        let status = BackendStatus::new(name);
+        Managed {
+            name: name.to_string(),
+            backend: backend,
+            router: Router::new(Route::default()),
+            timeout: Duration::from_secs(10),
+            status: Arc::new(Mutex::new(status)),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Select the series the backend receives and how often.
+    pub fn set_route(&mut self, route: Route) {
+        self.router = Router::new(route);
+    }
+
+    /// Set how long a flush may take before the interval counts as failed.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn start(&mut self) -> Result<(), String> {
+        self.backend.start()
+    }
+
+    /// Flush the buckets through the route of the backend. Flushes a
+    /// slower interval aggregates are not sent nor recorded.
+    pub fn flush(&mut self, buckets: &Buckets) -> Result<(), String> {
+        if self.router.is_passthrough() {
+            return self.send(buckets);
+        }
+        match self.router.route(buckets) {
+            Some(routed) => self.send(&routed),
+            None => Ok(()),
+        }
+    }
+
+    /// Send the buckets to the backend, recording the outcome.
+    fn send(&mut self, buckets: &Buckets) -> Result<(), String> {
+        let start = time::get_time();
+        let mut result = self.backend.flush_buckets(buckets);
+        let end = time::get_time();
+
+        let taken = (end - start).num_milliseconds();
+        if result.is_ok() && taken > self.timeout.as_millis() as i64 {
+            result = Err(format!("Missed the flush deadline of {} ms, took {} ms",
+                                 self.timeout.as_millis(), taken));
+        }
+        let mut status = self.status.lock().unwrap();
+        status.record(end, taken, &result);
+        status.details = self.backend.status();
+        result
+    }
+
+    /// Send what a slower interval aggregated so far, then shut the
+    /// backend down.
+    pub fn shutdown(&mut self) -> Result<(), String> {
+        let flushed = match self.router.drain() {
+            Some(rest) => self.send(&rest),
+            None => Ok(()),
+        };
+        self.backend.shutdown().and(flushed)
+    }
+
+    pub fn status(&self) -> SharedStatus {
+        self.status.clone()
+    }
+}
+
+
+enum Job {
+    Flush(Arc<Buckets>),
+    Shutdown(Sender<Result<(), String>>),
+}
+
+/// Flushes one backend on its own thread, so a slow or hung backend
+/// doesn't delay the others.
+pub struct Worker {
+    name: String,
+    timeout: Duration,
+    status: SharedStatus,
+    jobs: Sender<Job>,
+    // Signalled whenever a flush is done
+    done: Receiver<()>,
+}
+
+impl Worker {
+    /// Move the backend to a new thread flushing the snapshots it is sent.
+    pub fn spawn(mut managed: Managed) -> Worker {
+        let name = managed.name().to_string();
+        let timeout = managed.timeout;
+        let status = managed.status();
+        let (send, recv) = channel();
+        let (done_send, done) = sync_channel(1);
+        thread::Builder::new()
+            .name(format!("backend-{}", name))
+            .spawn(move || {
+                for job in recv.iter() {
+                    match job {
+                        Job::Flush(buckets) => {
+                            if let Err(e) = managed.flush(&buckets) {
+                                eprintln!("Failed to flush {}: {}", managed.name(), e);
+                            }
+                            {
+                                let mut status = managed.status.lock().unwrap();
+                                status.flushing_since = None;
+                                status.overdue = false;
+                            }
+                            let _ = done_send.try_send(());
+                        }
+                        Job::Shutdown(reply) => {
+                            let _ = reply.send(managed.shutdown());
+                            return;
+                        }
+                    }
+                }
+            })
+            .expect("Cannot start a backend worker thread");
+        Worker {
+            name: name,
+            timeout: timeout,
+            status: status,
+            jobs: send,
+            done: done,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn status(&self) -> SharedStatus {
+        self.status.clone()
+    }
+
+    /// Hand a snapshot to the worker without waiting for the flush.
+    ///
+    /// When the previous flush is still running the snapshot is not
+    /// queued and the interval is recorded as failed.
+    pub fn flush(&self, buckets: Arc<Buckets>) -> Result<(), String> {
+        let now = time::get_time();
+        {
+            let mut status = self.status.lock().unwrap();
+            if let Some(since) = status.flushing_since {
+                let error = format!("Skipped, still flushing an interval after {} ms",
+                                    (now - since).num_milliseconds());
+                status.record_failure(now, error.clone());
+                return Err(error);
+            }
+            status.flushing_since = Some(now);
+        }
+        self.jobs.send(Job::Flush(buckets))
+            .map_err(|_| format!("The {} worker has stopped", self.name))
+    }
+
+    /// Record the interval as failed when its flush is still running past
+    /// the deadline at `now`, so a hung backend fails the interval it
+    /// hangs in rather than when it returns.
+    pub fn check_deadline(&self, now: time::Timespec) {
+        let mut status = self.status.lock().unwrap();
+        let since = match status.flushing_since {
+            Some(since) if !status.overdue => since,
+            _ => return,
+        };
+        let taken = (now - since).num_milliseconds();
+        if taken > self.timeout.as_millis() as i64 {
+            let error = format!("Missed the flush deadline of {} ms, still flushing after {} ms",
+                                self.timeout.as_millis(), taken);
+            status.record_failure(now, error);
+            status.overdue = true;
+        }
+    }
+
+    /// Wait up to the flush deadline for the running flush, if any, to
+    /// be done. Returns whether the worker is idle.
+    pub fn wait(&self) -> bool {
+        let deadline = Instant::now() + self.timeout;
+        while self.status.lock().unwrap().flushing_since.is_some() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if self.done.recv_timeout(left).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Shut the backend down once the queued flush is done, waiting at
+    /// most twice the flush deadline.
+    pub fn shutdown(&self) -> Result<(), String> {
+        let (reply, result) = channel();
+        self.jobs.send(Job::Shutdown(reply))
+            .map_err(|_| format!("The {} worker has stopped", self.name))?;
+        result.recv_timeout(self.timeout * 2)
+            .unwrap_or_else(|_| Err("Timed out waiting for the backend to shut down".to_string()))
+    }
 }
 
 
-/// Creates the collection of backends based on the paraemeters
-///
-pub fn factory(console: &bool,
-               flush_interval_seconds: i32,
-               graphite: &bool,
-               graphite_global_prefix: &str,
-               graphite_counter_prefix: &str,
-               graphite_gauge_prefix: &str,
-               graphite_timer_prefix: &str,
-               graphite_host: &str,
-               graphite_port: &u16,
-               statsd: &bool,
-               statsd_host: &str,
-               statsd_port: &u16,
-               statsd_hosts: &str,
-               statsd_packet_limit: &usize,
-               zeromq_backend: &bool,
-               zeromq_hosts: &str)
-               -> Box<[Box<dyn Backend>]> {
-    let mut backends: Vec<Box<dyn Backend>> = Vec::with_capacity(2);
-    if *console {
-        backends.push(Box::new(console::Console::new()));
-    }
-    if *graphite {
-        backends.push(Box::new(graphite::Graphite::new(
-            graphite_host, *graphite_port,
-            graphite_global_prefix, graphite_counter_prefix,
-            graphite_gauge_prefix, graphite_timer_prefix,
-            flush_interval_seconds
-        )));
-    }
-    if *statsd {
-        let mut hosts = "".to_string();
-        let stats_deprecated_host = format!("{}:{},", statsd_host, statsd_port);
-        if stats_deprecated_host != "127.0.0.1:0," {
-            eprintln!("--statsd-host and --statsd-port flags are deprecated, please use --statsd-hosts instead.");
-            hosts += &stats_deprecated_host;
-        }
-        hosts += &*statsd_hosts;
-        let mut hosts_vec: Vec<String> =
-            hosts
-            .split(",").map(|host| host.trim().to_string())
-            .filter(|host| !host.is_empty())
-            .collect();
-        hosts_vec.sort();
-        hosts_vec.dedup();
-        backends.push(Box::new(statsd::Statsd::new(
-            hosts_vec, *statsd_packet_limit
-        )))
-    }
-    if *zeromq_backend {
-        let hosts = zeromq_hosts;
-        let mut hosts_vec: Vec<String> = hosts
-            .split(",")
-            .map(|host| host.trim().to_string())
-            .filter(|host| !host.is_empty())
-            .collect();
-        hosts_vec.sort();
-        hosts_vec.dedup();
-        if hosts_vec.len() > 0 {
-            backends.push(Box::new(statsd_zmq::StatsdZmq::new(hosts_vec)))
-        }
-    }
-    backends.into_boxed_slice()
+/// Parse flush deadlines in seconds from `backend=seconds` pairs
+/// separated by comma.
+pub fn parse_timeouts(spec: &str) -> Result<HashMap<String, Duration>, String> {
+    let mut timeouts = HashMap::new();
+    for (backend, seconds) in route::pairs(spec, "--backend-timeouts")? {
+        let seconds: f64 = seconds.parse()
+            .map_err(|_| format!("Invalid timeout `{}` for {}", seconds, backend))?;
+        timeouts.insert(backend.to_string(), timeout(backend, seconds)?);
+    }
+    Ok(timeouts)
+}
+
+/// The longest flush deadline accepted, in seconds.
+const MAX_TIMEOUT_SECONDS: f64 = 3600.0;
+
+/// A flush deadline from a positive number of seconds, at most an hour.
+pub fn timeout(backend: &str, seconds: f64) -> Result<Duration, String> {
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return Err(format!("The timeout of {} must be positive", backend));
+    }
+    if seconds > MAX_TIMEOUT_SECONDS {
+        return Err(format!("The timeout of {} can't be over {} seconds",
+                           backend, MAX_TIMEOUT_SECONDS));
+    }
+    Ok(Duration::from_millis((seconds * 1000.0) as u64))
+}
+
+
+/// Record how the flushes of each backend went since the previous call as
+/// internal metrics. Check the deadlines of the workers first.
+pub fn record_metrics(buckets: &mut Buckets, statuses: &[SharedStatus]) {
+    for status in statuses.iter() {
+        let mut status = status.lock().unwrap();
+        let prefix = format!("statsd.backends.{}", status.name);
+        let errors = status.failures - status.reported_failures;
+        status.reported_failures = status.failures;
+        buckets.set_gauge(format!("{}.flush_time", prefix), status.last_duration_ms as f64);
+        buckets.set_counter(format!("{}.flush_errors", prefix), errors as f64);
+    }
 }
 
 
@@ -86,75 +375,110 @@ pub fn factory(console: &bool,
 mod test {
     use super::*;
 
+    struct Flaky {
+        fail: bool,
+        delay: u64,
+    }
+
+    impl Backend for Flaky {
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        fn flush_buckets(&mut self, _buckets: &Buckets) -> Result<(), String> {
+            thread::sleep(Duration::from_millis(self.delay));
+            if self.fail { Err("unreachable".to_string()) } else { Ok(()) }
+        }
+
+        fn status(&self) -> Vec<(String, String)> {
+            vec![("failing".to_string(), self.fail.to_string())]
+        }
+    }
+
     #[test]
-    fn factory_makes_graphite() {
-        let backends = factory(
-            &false,
-            1,
-            &true,
-            "stats",
-            "counters",
-            "gauges",
-            "timers",
-            "127.0.0.1", &2300,
-            &false, &"", &0,
-            "", &1024,
-            &false, ""
-        );
-        assert_eq!(1, backends.len());
+    fn managed_records_status() {
+        let mut buckets = Buckets::new(10., false);
+        let mut backends = [Managed::new("flaky", Box::new(Flaky { fail: false, delay: 0 })),
+                            Managed::new("flaky", Box::new(Flaky { fail: true, delay: 0 }))];
+        assert!(backends[0].flush(&buckets).is_ok());
+        assert!(backends[1].flush(&buckets).is_err());
+
+        let ok = backends[0].status().lock().unwrap().clone();
+        assert_eq!((1, 0), (ok.flushes, ok.failures));
+        assert!(ok.last_success.is_some());
+        let failed = backends[1].status().lock().unwrap().clone();
+        assert_eq!((1, 1), (failed.flushes, failed.failures));
+        assert!(failed.format().contains(" failing: true\n"));
+        assert_eq!("unreachable", failed.last_error.unwrap().1);
+
+        record_metrics(&mut buckets, &[backends[1].status()]);
+        assert_eq!(Some(&1.0), buckets.counters().get("statsd.backends.flaky.flush_errors"));
+        assert!(buckets.gauges().contains_key("statsd.backends.flaky.flush_time"));
+
+        // Only the failures since the last record are counted.
+        assert!(backends[1].flush(&buckets).is_err());
+        assert!(backends[1].flush(&buckets).is_err());
+        record_metrics(&mut buckets, &[backends[1].status()]);
+        assert_eq!(Some(&2.0), buckets.counters().get("statsd.backends.flaky.flush_errors"));
+        record_metrics(&mut buckets, &[backends[1].status()]);
+        assert_eq!(Some(&0.0), buckets.counters().get("statsd.backends.flaky.flush_errors"));
     }
 
     #[test]
-    fn factory_makes_console() {
-        let backends = factory(
-            &true,
-            1,
-            &false,
-            "stats",
-            "counters",
-            "gauges",
-            "timers",
-            "127.0.0.1", &2300,
-            &false, &"", &0,
-            "", &1024,
-            &false, ""
-        );
-        assert_eq!(1, backends.len());
+    fn worker_misses_deadline() {
+        let mut managed = Managed::new("flaky", Box::new(Flaky { fail: false, delay: 200 }));
+        managed.set_timeout(Duration::from_millis(50));
+        let worker = Worker::spawn(managed);
+        let buckets = Arc::new(Buckets::new(10., false));
+
+        // The first flush is still running, so the second is skipped.
+        assert!(worker.flush(buckets.clone()).is_ok());
+        assert!(worker.flush(buckets.clone()).is_err());
+        thread::sleep(Duration::from_millis(300));
+        assert!(worker.shutdown().is_ok());
+
+        let status = worker.status().lock().unwrap().clone();
+        assert_eq!((1, 2), (status.flushes, status.failures));
+        assert!(status.last_error.unwrap().1.starts_with("Missed the flush deadline of 50 ms"));
+        assert!(status.flushing_since.is_none());
     }
 
     #[test]
-    fn factory_makes_statsd() {
-        let backends = factory(
-            &false,
-            1,
-            &false,
-            "stats",
-            "counters",
-            "gauges",
-            "timers",
-            "127.0.0.1", &2300,
-            &true, &"127.0.0.1", &8125,
-            "", &(16 * 1024),
-            &false, ""
-        );
-        assert_eq!(1, backends.len());
+    fn worker_fails_hung_interval() {
+        let mut managed = Managed::new("flaky", Box::new(Flaky { fail: false, delay: 300 }));
+        managed.set_timeout(Duration::from_millis(50));
+        let worker = Worker::spawn(managed);
+        let start = time::get_time();
+        assert!(worker.flush(Arc::new(Buckets::new(10., false))).is_ok());
+
+        // Not failed before the deadline, then once while it hangs.
+        worker.check_deadline(start);
+        assert_eq!(0, worker.status().lock().unwrap().failures);
+        thread::sleep(Duration::from_millis(100));
+        worker.check_deadline(time::get_time());
+        worker.check_deadline(time::get_time());
+        let mut buckets = Buckets::new(10., false);
+        record_metrics(&mut buckets, &[worker.status()]);
+        assert_eq!(Some(&1.0), buckets.counters().get("statsd.backends.flaky.flush_errors"));
+        assert!(!worker.wait());
+
+        // Returning late doesn't fail the interval a second time.
+        thread::sleep(Duration::from_millis(300));
+        assert!(worker.wait());
+        let status = worker.status().lock().unwrap().clone();
+        assert_eq!((1, 1), (status.flushes, status.failures));
+        assert!(worker.shutdown().is_ok());
     }
 
     #[test]
-    fn factory_makes_both() {
-        let backends = factory(
-            &true,
-            1,
-            &true,
-            "stats",
-            "counters",
-            "gauges",
-            "timers",
-            "127.0.0.1", &2300,
-            &false, &"", &0,
-            "", &0,
-            &false, ""
-        );
-        assert_eq!(2, backends.len());
+    fn parses_timeouts() {
+        let timeouts = parse_timeouts("graphite=2.5,statsd=1").unwrap();
+        assert_eq!(Some(&Duration::from_millis(2500)), timeouts.get("graphite"));
+        assert!(parse_timeouts("graphite=0").is_err());
+        assert!(parse_timeouts("graphite=soon").is_err());
+        assert!(parse_timeouts("graphite=NaN").is_err());
+        assert!(parse_timeouts("graphite=inf").is_err());
+        assert!(parse_timeouts("graphite=3601").is_err());
+        assert!(parse_timeouts("graphite=3600").is_ok());
     }
 }
@@ -0,0 +1,439 @@
+//! A local archive of every flush, for looking back at what was sent when
+//! remote storage lost data.
+//!
+//! Each UTC hour gets an append-only file named like `2024031514.archive`.
+//! A flush is a `@<timestamp>` line followed by one `<id> <value>` line per
+//! series. The first time a series is written to a file, an
+//! `=<id> <kind> <key>` line gives it its id, the kind being `c` for
+//! counters, `g` for gauges, `t` for timer statistics and `s` for the
+//! server's own counts. A writer appending to an existing file defines its
+//! ids again.
+//!
+//! A line cut short by a crash is ended by the next writer and marked by a
+//! following `!` line.
+
+use super::super::backend::Backend;
+use super::super::buckets::Buckets;
+use crate::metric::split_tags;
+use crate::pattern::Glob;
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use time;
+
+
+const EXTENSION: &str = "archive";
+
+/// The line marking the line before it as cut short.
+const TORN: &str = "!";
+
+/// A series value read back from the archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub timestamp: i64,
+    pub kind: String,
+    pub key: String,
+    pub value: f64,
+}
+
+#[derive(Debug)]
+pub struct Archive {
+    dir: PathBuf,
+    retention_hours: u64,
+    // The hour the open file is for
+    hour: String,
+    file: Option<File>,
+    // The ids of the series defined in the open file, by `<kind> <key>`
+    ids: HashMap<String, usize>,
+}
+
+impl Archive {
+    /// Create a backend archiving every flush in `dir`, removing the files
+    /// older than `retention_hours`, 0 to keep them all.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let archive = Archive::new(PathBuf::from("/var/lib/statsd"), 168);
+    /// ```
+    pub fn new(dir: PathBuf, retention_hours: u64) -> Archive {
+        Archive {
+            dir: dir,
+            retention_hours: retention_hours,
+            hour: String::new(),
+            file: None,
+            ids: HashMap::new(),
+        }
+    }
+
+    /// Convert the buckets into the lines of one flush, defining the ids
+    /// of the series new to the open file.
+    pub fn format_stats(&mut self, buckets: &Buckets, timestamp: i64) -> String {
+        let mut lines = String::new();
+        writeln!(lines, "@{}", timestamp).unwrap();
+        self.format_point(&mut lines, "s", "statsd.bad_messages", buckets.bad_messages() as f64);
+        self.format_point(&mut lines, "s", "statsd.total_messages", buckets.total_messages() as f64);
+        for (key, value) in buckets.all_counters() {
+            self.format_point(&mut lines, "c", key, *value);
+        }
+        for (key, value) in buckets.all_gauges() {
+            self.format_point(&mut lines, "g", key, *value);
+        }
+        for (key, value) in buckets.timer_data().iter() {
+            self.format_point(&mut lines, "t", key, *value);
+        }
+        lines
+    }
+
+    fn format_point(&mut self, lines: &mut String, kind: &str, key: &str, value: f64) {
+        let series = format!("{} {}", kind, key);
+        let id = match self.ids.get(&series) {
+            Some(&id) => id,
+            None => {
+                let id = self.ids.len();
+                writeln!(lines, "={} {}", id, series).unwrap();
+                self.ids.insert(series, id);
+                id
+            }
+        };
+        writeln!(lines, "{} {}", id, value).unwrap();
+    }
+
+    fn write(&mut self, timestamp: i64, buckets: &Buckets) -> io::Result<()> {
+        self.open(timestamp)?;
+        let lines = self.format_stats(buckets, timestamp);
+        self.file.as_mut().unwrap().write_all(lines.as_bytes())
+    }
+
+    /// Open the file of the hour of `timestamp`, unless it is open already.
+    fn open(&mut self, timestamp: i64) -> io::Result<()> {
+        let hour = hour_of(timestamp);
+        if self.file.is_some() && self.hour == hour {
+            return Ok(());
+        }
+        self.file = None;
+        self.ids.clear();
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("{}.{}", hour, EXTENSION));
+        let mut file = OpenOptions::new().read(true).create(true).append(true).open(path)?;
+        if is_torn(&mut file)? {
+            write!(file, "\n{}\n", TORN)?;
+        }
+        self.file = Some(file);
+        self.hour = hour;
+        self.expire(timestamp)
+    }
+
+    /// Remove the files of the hours past the retention.
+    fn expire(&self, now: i64) -> io::Result<()> {
+        if self.retention_hours == 0 {
+            return Ok(());
+        }
+        let oldest = hour_of(now - self.retention_hours as i64 * 3600);
+        for (hour, path) in archive_files(&self.dir)? {
+            if hour < oldest {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the file ends in a line cut short by a crash.
+fn is_torn(file: &mut File) -> io::Result<bool> {
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+    let mut last = [0; 1];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+/// The lines of an archive file that were written whole, without the ones
+/// marked as torn and a last one missing its newline.
+struct CompleteLines<R> {
+    reader: R,
+    next: Option<String>,
+}
+
+impl<R: BufRead> CompleteLines<R> {
+    fn new(reader: R) -> CompleteLines<R> {
+        CompleteLines { reader: reader, next: None }
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 || line.pop() != Some(b'\n') {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+    }
+}
+
+impl<R: BufRead> Iterator for CompleteLines<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        loop {
+            let line = match self.next.take() {
+                Some(line) => line,
+                None => match self.read_line() {
+                    Ok(Some(line)) => line,
+                    Ok(None) => return None,
+                    Err(e) => return Some(Err(e)),
+                },
+            };
+            match self.read_line() {
+                Ok(Some(ref next)) if next == TORN => continue,
+                Ok(next) => self.next = next,
+                Err(e) => return Some(Err(e)),
+            }
+            if line != TORN {
+                return Some(Ok(line));
+            }
+        }
+    }
+}
+
+/// The UTC hour of a timestamp as `YYYYMMDDHH`, which sorts like time.
+fn hour_of(timestamp: i64) -> String {
+    let tm = time::at_utc(time::Timespec::new(timestamp, 0));
+    format!("{:04}{:02}{:02}{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour)
+}
+
+/// The archive files in `dir` with their hour, oldest first.
+fn archive_files(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+        let hour = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(hour) if hour.len() == 10 && hour.chars().all(|c| c.is_ascii_digit()) => hour.to_string(),
+            _ => continue,
+        };
+        files.push((hour, path));
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Read back the series whose name matches `pattern` between `from` and
+/// `until`, inclusive, oldest first.
+///
+/// Lines cut short by a crash are skipped.
+pub fn query(dir: &Path, pattern: &Glob, from: i64, until: i64) -> io::Result<Vec<Point>> {
+    let (first, last) = (hour_of(from), hour_of(until));
+    let mut points = Vec::new();
+    for (hour, path) in archive_files(dir)? {
+        if hour < first || hour > last {
+            continue;
+        }
+        // The kind and key of the matching series by id.
+        let mut series: HashMap<String, Option<(String, String)>> = HashMap::new();
+        let mut timestamp = None;
+        for line in CompleteLines::new(BufReader::new(File::open(path)?)) {
+            let line = line?;
+            if let Some(rest) = line.strip_prefix('@') {
+                timestamp = rest.parse::<i64>().ok();
+                continue;
+            }
+            if let Some(rest) = line.strip_prefix('=') {
+                let mut parts = rest.splitn(3, ' ');
+                if let (Some(id), Some(kind), Some(key)) = (parts.next(), parts.next(), parts.next()) {
+                    let matches = pattern.matches(split_tags(key).0);
+                    series.insert(id.to_string(), if matches { Some((kind.to_string(), key.to_string())) } else { None });
+                }
+                continue;
+            }
+            let timestamp = match timestamp {
+                Some(timestamp) if timestamp >= from && timestamp <= until => timestamp,
+                _ => continue,
+            };
+            let mut parts = line.splitn(2, ' ');
+            let (kind, key) = match parts.next().and_then(|id| series.get(id)) {
+                Some(&Some((ref kind, ref key))) => (kind, key),
+                _ => continue,
+            };
+            if let Some(Ok(value)) = parts.next().map(|value| value.parse::<f64>()) {
+                points.push(Point {
+                    timestamp: timestamp,
+                    kind: kind.clone(),
+                    key: key.clone(),
+                    value: value,
+                });
+            }
+        }
+    }
+    Ok(points)
+}
+
+/// Parse a query time, a unix timestamp, `now`, or relative to now like
+/// `-90s`, `-30m`, `-6h` or `-2d`.
+pub fn parse_time(spec: &str, now: i64) -> Result<i64, String> {
+    if spec == "now" {
+        return Ok(now);
+    }
+    if spec.starts_with('-') && spec.len() > 2 && spec.is_char_boundary(spec.len() - 1) {
+        let (amount, unit) = spec[1..].split_at(spec.len() - 2);
+        let seconds = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            _ => return Err(format!("Unknown unit in `{}`, use s, m, h or d", spec)),
+        };
+        return amount.parse::<i64>()
+            .map(|amount| now - amount * seconds)
+            .map_err(|_| format!("Invalid time `{}`", spec));
+    }
+    spec.parse().map_err(|_| format!("Invalid time `{}`, use a unix timestamp, now or e.g. -6h", spec))
+}
+
+
+impl Backend for Archive {
+    fn name(&self) -> &str {
+        "archive"
+    }
+
+    fn flush_buckets(&mut self, buckets: &Buckets) -> Result<(), String> {
+        let timestamp = time::get_time().sec;
+        self.write(timestamp, buckets).map_err(|e| {
+            self.file = None;
+            format!("Could not write to the archive in {:?}: {:?}", self.dir, e)
+        })
+    }
+
+    fn shutdown(&mut self) -> Result<(), String> {
+        match self.file.take() {
+            Some(file) => file.sync_all().map_err(|e| format!("Could not sync the archive: {:?}", e)),
+            None => Ok(()),
+        }
+    }
+
+    fn status(&self) -> Vec<(String, String)> {
+        vec![("file".to_string(), format!("{}.{}", self.hour, EXTENSION))]
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::super::metric::{Metric, MetricKind};
+    use super::*;
+
+    fn make_buckets() -> Buckets {
+        let mut buckets = Buckets::new(10., false);
+        buckets.add(&Metric::new("api.requests;host=web1", 4.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("api.queue", 3.5, MetricKind::Gauge));
+        buckets.add(&Metric::new("api.latency", 12.0, MetricKind::Timer));
+        buckets.add(&Metric::new("db.latency", 2.0, MetricKind::Timer));
+        buckets.process();
+        buckets
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("statsd-archive-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_hour_of() {
+        assert_eq!("2024031514", hour_of(1710513000));
+        assert_eq!("1970010100", hour_of(0));
+    }
+
+    #[test]
+    fn test_writes_hourly_files_and_queries() {
+        let dir = temp_dir("query");
+        let mut archive = Archive::new(dir.clone(), 0);
+        let buckets = make_buckets();
+        for &timestamp in [1710513000, 1710513010, 1710516600].iter() {
+            archive.write(timestamp, &buckets).unwrap();
+        }
+        assert!(dir.join("2024031514.archive").exists());
+        assert!(dir.join("2024031515.archive").exists());
+
+        let points = query(&dir, &Glob::new("api.**"), 1710513005, 1710520000).unwrap();
+        let latency: Vec<&Point> = points.iter().filter(|p| p.key == "api.latency.max").collect();
+        assert_eq!(2, latency.len());
+        assert_eq!(1710513010, latency[0].timestamp);
+        assert_eq!(1710516600, latency[1].timestamp);
+        assert_eq!("t", latency[0].kind);
+        assert_eq!(12.0, latency[0].value);
+
+        let requests = query(&dir, &Glob::new("api.requests"), 0, 1710513000).unwrap();
+        assert_eq!(vec![Point { timestamp: 1710513000, kind: "c".to_string(),
+                                key: "api.requests;host=web1".to_string(), value: 4.0 }],
+                   requests);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expires_old_hours() {
+        let dir = temp_dir("expire");
+        let mut archive = Archive::new(dir.clone(), 2);
+        for hour in 0..4 {
+            archive.write(1710513000 + hour * 3600, &Buckets::new(10., false)).unwrap();
+        }
+        let hours: Vec<String> = archive_files(&dir).unwrap().into_iter().map(|(hour, _)| hour).collect();
+        assert_eq!(vec!["2024031515", "2024031516", "2024031517"], hours);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_defines_series_once_per_file() {
+        let dir = temp_dir("define");
+        let mut archive = Archive::new(dir.clone(), 0);
+        let buckets = make_buckets();
+        archive.open(1710513000).unwrap();
+        let first = archive.format_stats(&buckets, 1710513000);
+        let second = archive.format_stats(&buckets, 1710513010);
+        assert!(first.starts_with("@1710513000\n=0 s statsd.bad_messages\n0 0\n"));
+        assert!(first.contains(" c api.requests;host=web1\n"));
+        assert!(!second.contains('='));
+        assert_eq!(first.lines().filter(|line| !line.starts_with('=')).count(), second.lines().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skips_torn_lines() {
+        let dir = temp_dir("torn");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("2024031514.archive"), "1 1\n@1710513000\n=0 c api.a\n=1 c api.b\n0 2\n1 3\n!\n1 4\n@17105").unwrap();
+        let points = query(&dir, &Glob::new("**"), 0, 1710520000).unwrap();
+        assert_eq!(vec![2.0, 4.0], points.iter().map(|p| p.value).collect::<Vec<f64>>());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_appends_after_torn_tail() {
+        let dir = temp_dir("append");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("2024031514.archive"), "@1710513000\n=0 c api.requests;host=web1\n0 4\n0 1.5").unwrap();
+        let mut archive = Archive::new(dir.clone(), 0);
+        archive.write(1710513010, &make_buckets()).unwrap();
+
+        let points = query(&dir, &Glob::new("api.requests"), 0, 1710520000).unwrap();
+        assert_eq!(vec![(1710513000, 4.0), (1710513010, 4.0)],
+                   points.iter().map(|p| (p.timestamp, p.value)).collect::<Vec<(i64, f64)>>());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(Ok(1000), parse_time("now", 1000));
+        assert_eq!(Ok(1000 - 6 * 3600), parse_time("-6h", 1000));
+        assert_eq!(Ok(910), parse_time("-90s", 1000));
+        assert_eq!(Ok(1710513000), parse_time("1710513000", 1000));
+        assert!(parse_time("-6w", 1000).is_err());
+        assert!(parse_time("yesterday", 1000).is_err());
+    }
+}
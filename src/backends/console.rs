@@ -1,56 +1,299 @@
 use super::super::backend::Backend;
 use super::super::buckets::Buckets;
+use super::json_lines::series_object;
+use crate::metric::{statsd_line, with_suffix};
+use crate::metric_processor::TIMER_STATS;
+use crate::pattern::Glob;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Write;
 use time;
 
+/// How the console prints series.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Aligned columns sorted by name.
+    Table,
+    /// One JSON object per series.
+    Json,
+    /// The statsd wire format.
+    Statsd,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format, String> {
+        match name {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "statsd" => Ok(Format::Statsd),
+            x => Err(format!("Unknown console format `{}`, use table, json or statsd", x)),
+        }
+    }
+}
+
+/// Selects the series printed by name.
 #[derive(Debug)]
-pub struct Console;
+pub enum Filter {
+    All,
+    Glob(Glob),
+    Regex(Regex),
+}
+
+impl Filter {
+    /// Parse a glob, or a regex when wrapped in slashes like `/^api\./`.
+    pub fn parse(pattern: &str) -> Result<Filter, String> {
+        if pattern.is_empty() {
+            Ok(Filter::All)
+        } else if pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/') {
+            Regex::new(&pattern[1..pattern.len() - 1])
+                .map(Filter::Regex)
+                .map_err(|e| e.to_string())
+        } else {
+            Ok(Filter::Glob(Glob::new(pattern)))
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match *self {
+            Filter::All => true,
+            Filter::Glob(ref glob) => glob.matches(name),
+            Filter::Regex(ref regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// A series to print.
+struct Row<'a> {
+    kind: &'static str,
+    key: &'a str,
+    value: RowValue<'a>,
+}
+
+enum RowValue<'a> {
+    Number(f64),
+    Samples(&'a [f64]),
+}
+
+impl<'a> RowValue<'a> {
+    /// Text compared between flushes to find changed series.
+    fn fingerprint(&self) -> String {
+        match *self {
+            RowValue::Number(value) => value.to_string(),
+            RowValue::Samples(values) => format!("{:?}", values),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Console {
+    format: Format,
+    filter: Filter,
+    changed_only: bool,
+    previous: HashMap<String, String>,
+}
 
 
 impl Console {
     /// Create a Console formatter that prints to stdout
     ///
+    /// Prints in `format`, only the series matching `filter`, and when
+    /// `changed_only` is set only the ones that changed since the last flush.
+    ///
     /// # Examples
     ///
+    /// ```ignore
+    /// let cons = Console::new(Format::Table, Filter::All, false);
     /// ```
-    /// let cons = Console::new();
-    /// ```
-    pub fn new() -> Console {
-        Console
+    pub fn new(format: Format, filter: Filter, changed_only: bool) -> Console {
+        Console {
+            format: format,
+            filter: filter,
+            changed_only: changed_only,
+            previous: HashMap::new(),
+        }
+    }
+
+    /// The series to print, sorted by name.
+    fn rows<'a>(&mut self, buckets: &'a Buckets) -> Vec<Row<'a>> {
+        let mut rows = Vec::new();
+        for (key, value) in buckets.all_counters() {
+            rows.push(Row { kind: "counter", key: key, value: RowValue::Number(*value) });
+        }
+        for (key, value) in buckets.all_gauges() {
+            rows.push(Row { kind: "gauge", key: key, value: RowValue::Number(*value) });
+        }
+        for (key, values) in buckets.all_timers() {
+            rows.push(Row { kind: "timer", key: key, value: RowValue::Samples(values) });
+        }
+        rows.retain(|row| self.filter.matches(row.key));
+        rows.sort_by(|a, b| a.key.cmp(b.key).then(a.kind.cmp(b.kind)));
+
+        if self.changed_only {
+            // Only the series of this flush are kept, so the map does not
+            // grow with every series ever seen.
+            let previous = &self.previous;
+            let mut current = HashMap::with_capacity(rows.len());
+            rows.retain(|row| {
+                let id = format!("{}:{}", row.kind, row.key);
+                let fingerprint = row.value.fingerprint();
+                let changed = previous.get(&id) != Some(&fingerprint);
+                current.insert(id, fingerprint);
+                changed
+            });
+            self.previous = current;
+        }
+        rows
+    }
+
+    /// Render the buckets in the configured format.
+    pub fn format_stats(&mut self, buckets: &Buckets) -> String {
+        let format = self.format;
+        let rows = self.rows(buckets);
+        let mut out = String::new();
+        match format {
+            Format::Table => {
+                let now = time::get_time();
+                writeln!(out, "Flushing metrics: {}", time::at(now).rfc822()).unwrap();
+                writeln!(out, "  bad_messages: {}", buckets.bad_messages()).unwrap();
+                writeln!(out, "  total_messages: {}", buckets.total_messages()).unwrap();
+                let width = rows.iter().map(|row| row.key.len()).max().unwrap_or(0);
+                for row in rows.iter() {
+                    writeln!(out, "  {:<7}  {:<width$}  {}",
+                             row.kind, row.key, table_value(buckets, row), width = width).unwrap();
+                }
+            }
+            Format::Json => {
+                for row in rows.iter() {
+                    let object = match row.value {
+                        RowValue::Number(value) => series_object(row.kind, row.key, "value", value.into()),
+                        RowValue::Samples(_) => {
+                            let stats = TIMER_STATS.iter()
+                                .filter_map(|field| {
+                                    buckets.timer_data()
+                                        .get(&with_suffix(row.key, field))
+                                        .map(|value| (field.to_string(), Value::from(*value)))
+                                })
+                                .collect();
+                            series_object(row.kind, row.key, "stats", Value::Object(stats))
+                        }
+                    };
+                    out.push_str(&Value::Object(object).to_string());
+                    out.push('\n');
+                }
+            }
+            Format::Statsd => {
+                for row in rows.iter() {
+                    match row.value {
+                        RowValue::Number(value) => {
+                            let kind = if row.kind == "counter" { "c" } else { "g" };
+                            writeln!(out, "{}", statsd_line(row.key, &value.to_string(), kind)).unwrap();
+                        }
+                        RowValue::Samples(values) if !values.is_empty() => {
+                            let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                            writeln!(out, "{}", statsd_line(row.key, &values.join(":"), "ms")).unwrap();
+                        }
+                        RowValue::Samples(_) => {}
+                    }
+                }
+            }
+        }
+        out
     }
 }
 
-/// Print a single stats line.
-fn fmt_line(key: &str, value: &f64) {
-    println!("    {}: {}", key, value)
+/// The value column of the table, a summary for timers.
+fn table_value(buckets: &Buckets, row: &Row) -> String {
+    match row.value {
+        RowValue::Number(value) => value.to_string(),
+        RowValue::Samples(_) => {
+            ["count", "mean", "upper_90", "max"].iter()
+                .filter_map(|field| {
+                    buckets.timer_data()
+                        .get(&with_suffix(row.key, field))
+                        .map(|value| format!("{}={}", field, value))
+                })
+                .collect::<Vec<String>>()
+                .join(" ")
+        }
+    }
 }
 
 
 impl Backend for Console {
-    fn flush_buckets(&mut self, buckets: &Buckets) {
-        let now = time::get_time();
-        println!("Flushing metrics: {}", time::at(now).rfc822().to_string());
+    fn name(&self) -> &str {
+        "console"
+    }
+
+    fn flush_buckets(&mut self, buckets: &Buckets) -> Result<(), String> {
+        print!("{}", self.format_stats(buckets));
+        Ok(())
+    }
+}
 
-        println!("  bad_messages: {}", buckets.bad_messages());
-        println!("  total_messages: {}", buckets.total_messages());
 
-        println!("  counters:");
-        for (key, value) in buckets.counters().iter() {
-            fmt_line(&key, &value);
-        }
+#[cfg(test)]
+mod test {
+    use super::super::super::metric::{Metric, MetricKind};
+    use super::*;
 
-        println!("  gauges:");
-        for (key, value) in buckets.gauges().iter() {
-            fmt_line(&key, &value);
-        }
+    fn make_buckets() -> Buckets {
+        let mut buckets = Buckets::new(10., false);
+        buckets.add(&Metric::new("web.requests", 2.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("api.requests", 1.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("api.queue", 4.0, MetricKind::Gauge));
+        buckets.add(&Metric::new("api.latency", 10.0, MetricKind::Timer));
+        buckets.add(&Metric::new("api.latency", 20.0, MetricKind::Timer));
+        buckets.process();
+        buckets
+    }
 
-        println!("  timers:");
-        for (key, values) in buckets.timers().iter() {
-            println!("    {}: {:?}", key, values);
-        }
+    #[test]
+    fn test_table_sorted_and_filtered() {
+        let mut console = Console::new(Format::Table, Filter::parse("api.*").unwrap(), false);
+        let result = console.format_stats(&make_buckets());
+        let lines: Vec<&str> = result.lines().skip(3).collect();
+        assert_eq!(3, lines.len());
+        assert!(lines[0].starts_with("  timer    api.latency   count=2 mean=15"));
+        assert!(lines[1].starts_with("  gauge    api.queue     4"));
+        assert!(lines[2].starts_with("  counter  api.requests  1"));
+    }
 
-        println!("  timer_data:");
-        for (key, values) in buckets.timer_data().iter() {
-            println!("    {}: {:?}", key, values);
-        }
+    #[test]
+    fn test_statsd_format_with_regex() {
+        let filter = Filter::parse("/^(api\\.latency|web\\.)/").unwrap();
+        let mut console = Console::new(Format::Statsd, filter, false);
+        assert_eq!("api.latency:10:20|ms\nweb.requests:2|c\n", console.format_stats(&make_buckets()));
+        assert!(Filter::parse("/(/").is_err());
+
+        let mut buckets = Buckets::new(10., false);
+        buckets.add(&Metric::new("api.requests;env=prod;host=web1", 1.0, MetricKind::Counter(1.0)));
+        let mut console = Console::new(Format::Statsd, Filter::All, false);
+        assert!(console.format_stats(&buckets).contains("api.requests:1|c|#env:prod,host:web1\n"));
+    }
+
+    #[test]
+    fn test_json_format() {
+        let mut console = Console::new(Format::Json, Filter::parse("api.queue").unwrap(), false);
+        let result = console.format_stats(&make_buckets());
+        let object: Value = serde_json::from_str(&result).unwrap();
+        assert_eq!("gauge", object["type"]);
+        assert_eq!(4.0, object["value"]);
+    }
+
+    #[test]
+    fn test_changed_only() {
+        let mut console = Console::new(Format::Statsd, Filter::All, true);
+        let mut buckets = make_buckets();
+        assert_eq!(5, console.format_stats(&buckets).lines().count());
+
+        buckets.add(&Metric::new("api.queue", 5.0, MetricKind::Gauge));
+        assert_eq!("api.queue:5|g\n", console.format_stats(&buckets));
+        assert_eq!("", console.format_stats(&buckets));
+
+        // Series missing from a flush are forgotten.
+        assert_eq!("", console.format_stats(&Buckets::new(10., false)));
+        assert!(console.previous.is_empty());
+        assert_eq!(5, console.format_stats(&buckets).lines().count());
     }
 }
@@ -1,16 +1,220 @@
 use super::super::backend::Backend;
 use super::super::buckets::Buckets;
-use std::net::{ToSocketAddrs, SocketAddr, TcpStream};
+use super::reliable::{PersistentStream, Spool};
+use crate::hash_ring::CarbonRing;
+use crate::metric::split_tags;
+use crate::pattern::Glob;
+use std::collections::BTreeMap;
+use std::cmp;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::fmt::Write;
-use std::io::Write as IoWrite;
+use std::path::PathBuf;
 use time;
 
 
+/// The wire format used to send metrics to carbon.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    Plaintext,
+    // Batch size
+    Pickle(usize),
+}
+
+/// How data points are spread over the destinations.
+#[derive(Debug, Clone)]
+enum Routing {
+    /// Every destination receives every data point.
+    All,
+    /// Like carbon-relay's consistent-hashing relay method.
+    ConsistentHashing {
+        ring: CarbonRing,
+        replication_factor: usize,
+        diverse_replicas: bool,
+    },
+}
+
+/// A carbon cache or relay data points are sent to.
+#[derive(Debug)]
+struct Destination {
+    // The server and instance name the destination has on carbon's ring
+    server: String,
+    instance: Option<String>,
+    stream: PersistentStream,
+    spool: Spool,
+}
+
+impl Destination {
+    fn new(server: &str, port: u16, instance: Option<String>) -> Result<Destination, String> {
+        let addr = resolve(server, port)?;
+        Ok(Destination {
+            server: server.to_string(),
+            instance: instance,
+            stream: PersistentStream::new(addr),
+            spool: Spool::new("graphite", 0),
+        })
+    }
+
+    /// The destination as given in `DESTINATIONS`, like `host:port:instance`.
+    fn label(&self) -> String {
+        match self.instance {
+            Some(ref instance) => format!("{}:{}:{}", self.server, self.stream.addr().port(), instance),
+            None => format!("{}:{}", self.server, self.stream.addr().port()),
+        }
+    }
+
+    /// Queue the payload and send everything queued, oldest first,
+    /// stopping at the first failure so the order carbon receives them in
+    /// is preserved.
+    fn send(&mut self, payload: Vec<u8>, total_messages: usize) -> Result<(), String> {
+        let start = time::get_time();
+        let dropped = self.spool.dropped();
+        self.spool.push(payload);
+
+        let mut flushed = 0;
+        let mut len = 0;
+        let mut error = None;
+        while let Some(payload) = self.spool.front() {
+            match self.stream.write_all(&payload) {
+                Ok(_) => {
+                    self.spool.pop_front();
+                    flushed += 1;
+                    len += payload.len();
+                }
+                Err(e) => {
+                    error = Some(format!("Could not write to graphite {}: {:?}", self.label(), e));
+                    break;
+                }
+            }
+        }
+        self.spool.trim();
+
+        if flushed > 0 {
+            let taken = time::get_time() - start;
+            println!("Successfully flushed {} bytes in {} flushes, {} total metrics to graphite {} in {} milliseconds",
+                     len, flushed, total_messages, self.label(), taken.num_milliseconds())
+        }
+        if self.spool.len() > 0 {
+            eprintln!("{} flushes queued for graphite {}, {} of them on disk",
+                      self.spool.len(), self.label(), self.spool.spilled());
+        }
+        if self.spool.dropped() > dropped {
+            eprintln!("Dropped {} flushes for graphite {}, the retry queue is full",
+                      self.spool.dropped() - dropped, self.label());
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+fn resolve(host: &str, port: u16) -> Result<SocketAddr, String> {
+    format!("{}:{}", host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Could not resolve {}: {}", host, e))?
+        .last()
+        .ok_or_else(|| format!("Could not resolve {}", host))
+}
+
+/// One segment of a tag pattern.
+#[derive(Debug, Clone)]
+enum TagSegment {
+    Name(Glob),
+    Tag(String),
+}
+
+/// A pattern like `app.<env>.<host>.*` turning name segments into tags.
+///
+/// The pattern matches the leading segments of a metric name. Segments
+/// written as `<tag>` are removed from the name and become tags, the others
+/// are globs that must match and are kept.
+#[derive(Debug, Clone)]
+pub struct TagPattern {
+    segments: Vec<TagSegment>,
+}
+
+impl TagPattern {
+    pub fn parse(pattern: &str) -> Result<TagPattern, String> {
+        let mut segments = Vec::new();
+        for segment in pattern.split('.') {
+            if segment.is_empty() {
+                return Err(format!("Empty segment in tag pattern `{}`", pattern));
+            }
+            if segment.starts_with('<') && segment.ends_with('>') && segment.len() > 2 {
+                let tag = &segment[1..segment.len() - 1];
+                if tag == "name" {
+                    return Err(format!("`name` is reserved by graphite, in tag pattern `{}`", pattern));
+                }
+                segments.push(TagSegment::Tag(clean_tag_key(tag)));
+            } else if segment.contains('<') || segment.contains('>') {
+                return Err(format!("Tags must be a whole segment, in tag pattern `{}`", pattern));
+            } else {
+                segments.push(TagSegment::Name(Glob::new(segment)));
+            }
+        }
+        Ok(TagPattern { segments: segments })
+    }
+
+    /// Parse comma separated patterns.
+    pub fn parse_list(spec: &str) -> Result<Vec<TagPattern>, String> {
+        spec.split(',')
+            .map(|pattern| pattern.trim())
+            .filter(|pattern| !pattern.is_empty())
+            .map(TagPattern::parse)
+            .collect()
+    }
+
+    /// Split `name` into the remaining name and the tags taken from it.
+    fn extract(&self, name: &str) -> Option<(String, Vec<(String, String)>)> {
+        let parts: Vec<&str> = name.split('.').collect();
+        if parts.len() < self.segments.len() {
+            return None;
+        }
+        let mut kept = Vec::with_capacity(parts.len());
+        let mut tags = Vec::new();
+        for (segment, part) in self.segments.iter().zip(parts.iter()) {
+            match *segment {
+                TagSegment::Name(ref glob) => {
+                    if !glob.matches(part) {
+                        return None;
+                    }
+                    kept.push(*part);
+                }
+                TagSegment::Tag(ref tag) => tags.push((tag.clone(), part.to_string())),
+            }
+        }
+        kept.extend_from_slice(&parts[self.segments.len()..]);
+        Some((kept.join("."), tags))
+    }
+}
+
+/// Graphite tag names may not contain `;!^=`.
+fn clean_tag_key(key: &str) -> String {
+    key.replace(|c| ";!^=".contains(c), "_")
+}
+
+/// A single path segment, without dots nor whitespace.
+fn clean_path_segment(segment: &str) -> String {
+    segment.replace(|c: char| c == '.' || c.is_whitespace(), "_")
+}
+
+/// Graphite tag values may not contain `;` nor start with `~`.
+fn clean_tag_value(value: &str) -> String {
+    let value = value.replace(';', "_");
+    match value.strip_prefix('~') {
+        Some(rest) => format!("_{}", rest),
+        None => value,
+    }
+}
+
 #[derive(Debug)]
 pub struct Graphite {
-    addr: SocketAddr,
-    last_flush_time: u64,
-    last_flush_length: u64,
+    destinations: Vec<Destination>,
+    routing: Routing,
+    protocol: Protocol,
+    // Set when sending tagged series
+    tag_patterns: Option<Vec<TagPattern>>,
     flush_interval_seconds: i32,
     global_prefix: String,
     counter_prefix: String,
@@ -31,8 +235,8 @@ impl Graphite {
     ///
     /// # Examples
     ///
-    /// ```
-    /// let graph = Graphite::new(host, port);
+    /// ```ignore
+    /// let graph = Graphite::new("127.0.0.1", 2003, "stats", "counters", "gauges", "timers", 10).unwrap();
     /// ```
     pub fn new(host: &str, port: u16,
                global_prefix: &str,
@@ -40,89 +244,308 @@ impl Graphite {
                gauge_prefix: &str,
                timer_prefix: &str,
                flush_interval_seconds: i32,
-    ) -> Graphite {
-        let addr = format!("{}:{}", host, port)
-            .to_socket_addrs().unwrap().last().unwrap();
+    ) -> Result<Graphite, String> {
         let glob_prefix = default_prefix_str(global_prefix);
-        Graphite {
-            addr: addr,
-            last_flush_time: 0,
-            last_flush_length: 0,
+        Ok(Graphite {
+            destinations: vec![Destination::new(host, port, None)?],
+            routing: Routing::All,
+            protocol: Protocol::Plaintext,
+            tag_patterns: None,
             flush_interval_seconds: flush_interval_seconds,
             global_prefix: glob_prefix.clone(),
             counter_prefix: format!("{}{}", glob_prefix, default_prefix_str(counter_prefix)),
             gauge_prefix: format!("{}{}", glob_prefix, default_prefix_str(gauge_prefix)),
             timer_prefix: format!("{}{}", glob_prefix, default_prefix_str(timer_prefix)),
+        })
+    }
+
+    /// Send batches in graphite's pickle protocol to the given port
+    /// instead of plaintext lines.
+    ///
+    /// The port only applies to the host given to `new`, so call it before
+    /// `use_destinations`, whose destinations keep their own ports.
+    pub fn use_pickle(&mut self, port: u16, batch_size: usize) {
+        if self.destinations.len() == 1 {
+            let stream = &mut self.destinations[0].stream;
+            let mut addr = stream.addr();
+            addr.set_port(port);
+            stream.set_addr(addr);
+        }
+        self.protocol = Protocol::Pickle(cmp::max(1, batch_size));
+    }
+
+    /// Send to several carbon caches or relays instead of the host given
+    /// to `new`, written like carbon's `DESTINATIONS` as comma separated
+    /// `host:port[:instance]`.
+    pub fn use_destinations(&mut self, spec: &str) -> Result<(), String> {
+        let mut destinations: Vec<Destination> = Vec::new();
+        for destination in spec.split(',').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+            let parts: Vec<&str> = destination.split(':').collect();
+            let (server, port, instance) = match parts.len() {
+                2 => (parts[0], parts[1], None),
+                3 => (parts[0], parts[1], Some(parts[2].to_string())),
+                _ => return Err(format!("Graphite destination `{}` must be host:port[:instance]", destination)),
+            };
+            let port = port.parse()
+                .map_err(|_| format!("Invalid port in graphite destination `{}`", destination))?;
+            if destinations.iter().any(|d| d.server == server && d.instance == instance) {
+                return Err(format!("Graphite destination `{}` is given twice", destination));
+            }
+            destinations.push(Destination::new(server, port, instance)?);
+        }
+        if destinations.is_empty() {
+            return Err("No graphite destinations given".to_string());
+        }
+        self.destinations = destinations;
+        if let Routing::ConsistentHashing { replication_factor, diverse_replicas, .. } = self.routing {
+            self.use_consistent_hashing(replication_factor, diverse_replicas);
+        }
+        Ok(())
+    }
+
+    /// Send each data point to `replication_factor` destinations chosen
+    /// like carbon-relay's consistent-hashing relay method, instead of to
+    /// all of them. With `diverse_replicas` the replicas of a data point
+    /// go to different servers.
+    pub fn use_consistent_hashing(&mut self, replication_factor: usize, diverse_replicas: bool) {
+        let nodes: Vec<(String, Option<String>)> = self.destinations.iter()
+            .map(|d| (d.server.clone(), d.instance.clone()))
+            .collect();
+        self.routing = Routing::ConsistentHashing {
+            ring: CarbonRing::new(&nodes),
+            replication_factor: cmp::max(1, replication_factor),
+            diverse_replicas: diverse_replicas,
+        };
+    }
+
+    /// Send Graphite 1.1 tagged series, taking extra tags from the name
+    /// segments matched by the first matching pattern.
+    pub fn use_tags(&mut self, patterns: Vec<TagPattern>) {
+        self.tag_patterns = Some(patterns);
+    }
+
+    /// The graphite path of a bucket key under `prefix`.
+    ///
+    /// Without tagged series, the tags of a key are folded into its path as
+    /// one `tag_value` segment each, sorted by tag.
+    fn path(&self, prefix: &str, key: &str) -> String {
+        let (name, metric_tags) = split_tags(key);
+        let patterns = match self.tag_patterns {
+            Some(ref patterns) => patterns,
+            None => {
+                let mut tags: Vec<(String, String)> = metric_tags.iter()
+                    .map(|&(tag, value)| (clean_path_segment(&clean_tag_key(tag)),
+                                          clean_path_segment(&clean_tag_value(value))))
+                    .filter(|(tag, value)| !tag.is_empty() && !value.is_empty())
+                    .collect();
+                tags.sort();
+                let mut path = format!("{}{}", prefix, name);
+                for (tag, value) in tags.iter() {
+                    write!(path, ".{}_{}", tag, value).unwrap();
+                }
+                return path;
+            }
+        };
+        let (name, extracted) = patterns.iter()
+            .filter_map(|pattern| pattern.extract(name))
+            .next()
+            .unwrap_or_else(|| (name.to_string(), Vec::new()));
+
+        // Tags sent with the metric win over the ones taken from its name.
+        let mut tags = BTreeMap::new();
+        for (tag, value) in extracted.into_iter() {
+            tags.insert(tag, value);
+        }
+        for &(tag, value) in metric_tags.iter() {
+            tags.insert(clean_tag_key(tag), value.to_string());
+        }
+
+        let mut path = format!("{}{}", prefix, name);
+        for (tag, value) in tags.iter() {
+            let value = clean_tag_value(value);
+            if !tag.is_empty() && !value.is_empty() {
+                write!(path, ";{}={}", tag, value).unwrap();
+            }
         }
+        path
     }
 
-    /// Convert the buckets into a String that
-    /// can be sent to graphite's newline API
-    pub fn format_stats(&self, buckets: &Buckets) -> String {
-        let start = time::get_time().sec;
-        let mut stats = String::new();
-
-        write!(stats,
-               "{}{} {} {}\n",
-               self.global_prefix,
-               "statsd.bad_messages",
-               buckets.bad_messages(),
-               start)
-            .unwrap();
-        write!(stats,
-               "{}{} {} {}\n",
-               self.global_prefix,
-               "statsd.total_messages",
-               buckets.total_messages(),
-               start)
-            .unwrap();
-        write!(stats,
-               "{}{} {} {}\n",
-               self.global_prefix,
-               "statsd.unique_metrics",
-               buckets.unique_metrics(),
-               start)
-            .unwrap();
-
-        for (key, value) in buckets.counters().iter() {
-            write!(stats, "{}{} {} {}\n", self.counter_prefix, key,
-                   value / self.flush_interval_seconds as f64, start).unwrap();
-        }
-
-        for (key, value) in buckets.gauges().iter() {
-            write!(stats, "{}{} {} {}\n", self.gauge_prefix, key, value, start).unwrap();
+    /// Keep up to `queue_size` unsent flushes in memory to replay once
+    /// carbon is reachable again, and up to `spill_limit` more in files
+    /// in `spill_dir`. Each destination has its own queue, its files named
+    /// after the backend instance `name` and the destination.
+    pub fn set_retry_queue(&mut self,
+                           name: &str,
+                           queue_size: usize,
+                           spill_dir: Option<PathBuf>,
+                           spill_limit: usize)
+                           -> io::Result<()> {
+        let name = name.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_', "_");
+        for destination in self.destinations.iter_mut() {
+            let addr = destination.stream.addr();
+            let mut spool = Spool::new(&format!("{}-{}-{}", name, addr.ip(), addr.port()), queue_size);
+            if let Some(ref dir) = spill_dir {
+                spool.spill_to(dir.clone(), spill_limit)?;
+            }
+            destination.spool = spool;
+        }
+        Ok(())
+    }
+
+    /// Collect the `(path, value)` pairs sent to graphite.
+    pub fn collect_stats(&self, buckets: &Buckets) -> Vec<(String, f64)> {
+        let mut stats = Vec::with_capacity(3 + buckets.counters().len() +
+                                           buckets.gauges().len() +
+                                           buckets.timer_data().len());
+
+        stats.push((format!("{}{}", self.global_prefix, "statsd.bad_messages"),
+                    buckets.bad_messages() as f64));
+        stats.push((format!("{}{}", self.global_prefix, "statsd.total_messages"),
+                    buckets.total_messages() as f64));
+        stats.push((format!("{}{}", self.global_prefix, "statsd.unique_metrics"),
+                    buckets.unique_metrics() as f64));
+
+        for (key, value) in buckets.all_counters() {
+            stats.push((self.path(&self.counter_prefix, key),
+                        value / self.flush_interval_seconds as f64));
+        }
+
+        for (key, value) in buckets.all_gauges() {
+            stats.push((self.path(&self.gauge_prefix, key), *value));
         }
 
         // The raw timer data is not sent to graphite.
         for (key, value) in buckets.timer_data().iter() {
-            write!(stats, "{}{} {} {}\n", self.timer_prefix, key, value, start).unwrap();
+            stats.push((self.path(&self.timer_prefix, key), *value));
         }
         stats
     }
+
+    /// The data points of each destination.
+    fn route(&self, stats: Vec<(String, f64)>) -> Vec<Vec<(String, f64)>> {
+        match self.routing {
+            Routing::All => vec![stats; self.destinations.len()],
+            Routing::ConsistentHashing { ref ring, replication_factor, diverse_replicas } => {
+                let mut routed = vec![Vec::new(); self.destinations.len()];
+                for (path, value) in stats.into_iter() {
+                    for idx in ring.nodes_for(&path, replication_factor, diverse_replicas) {
+                        routed[idx].push((path.clone(), value));
+                    }
+                }
+                routed
+            }
+        }
+    }
+}
+
+
+/// Lines for graphite's plaintext protocol.
+fn format_plaintext(stats: &[(String, f64)], timestamp: i64) -> String {
+    let mut out = String::new();
+    for &(ref path, value) in stats.iter() {
+        writeln!(out, "{} {} {}", path, value, timestamp).unwrap();
+    }
+    out
+}
+
+/// Length prefixed pickle messages of at most `batch_size` data points each.
+fn format_pickle(stats: &[(String, f64)], timestamp: i64, batch_size: usize) -> Vec<u8> {
+    let mut message = Vec::new();
+    for batch in stats.chunks(batch_size) {
+        let payload = pickle_batch(batch, timestamp);
+        message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        message.extend_from_slice(&payload);
+    }
+    message
+}
+
+
+/// Pickle `[(path, (timestamp, value)), ...]` using pickle protocol 2.
+fn pickle_batch(batch: &[(String, f64)], timestamp: i64) -> Vec<u8> {
+    let mut out = vec![0x80, 2];    // PROTO 2
+    out.push(b']');                 // EMPTY_LIST
+    out.push(b'(');                 // MARK
+    for &(ref path, value) in batch.iter() {
+        out.push(b'X');             // BINUNICODE
+        out.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        out.extend_from_slice(path.as_bytes());
+        if timestamp >= i32::MIN as i64 && timestamp <= i32::MAX as i64 {
+            out.push(b'J');         // BININT
+            out.extend_from_slice(&(timestamp as i32).to_le_bytes());
+        } else {
+            out.push(0x8a);         // LONG1
+            out.push(8);
+            out.extend_from_slice(&timestamp.to_le_bytes());
+        }
+        out.push(b'G');             // BINFLOAT
+        out.extend_from_slice(&value.to_bits().to_be_bytes());
+        out.push(0x86);             // TUPLE2 (timestamp, value)
+        out.push(0x86);             // TUPLE2 (path, datapoint)
+    }
+    out.push(b'e');                 // APPENDS
+    out.push(b'.');                 // STOP
+    out
 }
 
 
 impl Backend for Graphite {
-    fn flush_buckets(&mut self, buckets: &Buckets) {
-        let stats = self.format_stats(&buckets);
+    fn name(&self) -> &str {
+        "graphite"
+    }
 
-        let start = time::get_time();
-        let len = stats.as_bytes().len();
-
-        match TcpStream::connect(self.addr) {
-            Ok(mut stream) => {
-                match stream.write_all(stats.as_bytes()) {
-                    Ok(_) => {
-                        let end = time::get_time();
-                        let taken = end - start;
-                        println!("Successfully flushed {} bytes, {} total metrics to graphite in {} milliseconds",
-                                 len, taken.num_milliseconds(), buckets.total_messages())
-                    },
-                    Err(e) => eprintln!("Could not complete write to graphite: {:?}", e),
+    fn flush_buckets(&mut self, buckets: &Buckets) -> Result<(), String> {
+        let timestamp = time::get_time().sec;
+        let routed = self.route(self.collect_stats(buckets));
+        let protocol = self.protocol;
+
+        // A destination that is down doesn't hold back the others.
+        let mut errors = Vec::new();
+        for (destination, stats) in self.destinations.iter_mut().zip(routed.iter()) {
+            let payload = match protocol {
+                Protocol::Plaintext => format_plaintext(stats, timestamp).into_bytes(),
+                Protocol::Pickle(batch_size) => format_pickle(stats, timestamp, batch_size),
+            };
+            if let Err(e) = destination.send(payload, buckets.total_messages()) {
+                errors.push(e);
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+
+    /// Give carbon one last chance to receive the queued flushes. Spilled
+    /// flushes are kept on disk for the next start.
+    fn shutdown(&mut self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for destination in self.destinations.iter_mut() {
+            while let Some(payload) = destination.spool.front() {
+                if let Err(e) = destination.stream.write_all(&payload) {
+                    errors.push(format!("{} flushes left unsent to graphite {}: {:?}",
+                                        destination.spool.len(), destination.label(), e));
+                    break;
                 }
-            },
-            Err(err) => eprintln!("Cannot connect to graphite server: {:?}", err),
-        };
+                destination.spool.pop_front();
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+
+    /// The health of every destination.
+    fn status(&self) -> Vec<(String, String)> {
+        self.destinations.iter()
+            .map(|d| {
+                (d.label(),
+                 format!("connected={} queued={} spilled={} dropped={}",
+                         d.stream.is_connected(), d.spool.len(), d.spool.spilled(), d.spool.dropped()))
+            })
+            .collect()
     }
 }
 
@@ -159,8 +582,8 @@ mod test {
          "gauges",
          "timers",
          2,
-        );
-        let result = graphite.format_stats(&buckets);
+        ).unwrap();
+        let result = format_plaintext(&graphite.collect_stats(&buckets), 1);
         let lines: Vec<&str> = result.lines().collect();
 
         assert_eq!(5, lines.len());
@@ -182,8 +605,8 @@ mod test {
             "gauges",
             "timers",
             2
-        );
-        let result = graphite.format_stats(&buckets);
+        ).unwrap();
+        let result = format_plaintext(&graphite.collect_stats(&buckets), 1);
         let lines: Vec<&str> = result.lines().collect();
 
         assert_eq!(16, lines.len());
@@ -192,4 +615,196 @@ mod test {
         assert!(result.contains("stats.timers.test.timer.min 1.101"));
         assert!(result.contains("stats.timers.test.timer.count 3"));
     }
+
+    #[test]
+    fn test_folds_tags_into_untagged_paths() {
+        let mut buckets = Buckets::new(10., false);
+        buckets.add(&Metric::new("api.requests;host=web 1;dc=eu.west;empty=", 5.0, MetricKind::Gauge));
+        let graphite = Graphite::new("127.0.0.1", 2003, "stats", "counters", "gauges", "timers", 1).unwrap();
+        let result = format_plaintext(&graphite.collect_stats(&buckets), 1);
+        assert!(result.contains("\nstats.gauges.api.requests.dc_eu_west.host_web_1 5 1\n"), "{}", result);
+    }
+
+    #[test]
+    fn test_unresolvable_host() {
+        let error = Graphite::new("", 2003, "stats", "counters", "gauges", "timers", 1).err().unwrap();
+        assert!(error.starts_with("Could not resolve"), "{}", error);
+    }
+
+    #[test]
+    fn test_spill_files_per_instance() {
+        let dir = std::env::temp_dir().join(format!("statsd-graphite-spill-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let graphite = |name: &str| {
+            let mut graphite = Graphite::new("127.0.0.1", 2003, "stats", "counters", "gauges", "timers", 1).unwrap();
+            graphite.set_retry_queue(name, 0, Some(dir.clone()), 5).unwrap();
+            graphite
+        };
+        let mut eu = graphite("graphite-eu");
+        eu.destinations[0].spool.push(b"queued 1 1\n".to_vec());
+        eu.destinations[0].spool.trim();
+
+        assert_eq!(0, graphite("graphite-us").destinations[0].spool.spilled());
+        assert_eq!(1, graphite("graphite-eu").destinations[0].spool.spilled());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_pickle() {
+        let buckets = make_buckets();
+        let mut graphite = Graphite::new("127.0.0.1", 2003,
+            "stats",
+            "counters",
+            "gauges",
+            "timers",
+            2
+        ).unwrap();
+        graphite.use_pickle(2004, 2);
+        assert_eq!(2004, graphite.destinations[0].stream.addr().port());
+
+        let result = format_pickle(&graphite.collect_stats(&buckets), 1, 2);
+
+        // 5 data points in batches of 2.
+        let mut frames = 0;
+        let mut pos = 0;
+        while pos < result.len() {
+            let mut len = [0; 4];
+            len.copy_from_slice(&result[pos..pos + 4]);
+            let len = u32::from_be_bytes(len) as usize;
+            let frame = &result[pos + 4..pos + 4 + len];
+            assert_eq!(&[0x80, 2, b']', b'('], &frame[..4]);
+            assert_eq!(b"e.", &frame[len - 2..]);
+            pos += 4 + len;
+            frames += 1;
+        }
+        assert_eq!(3, frames);
+    }
+
+    #[test]
+    fn test_pickle_batch() {
+        let batch = vec![("a.b".to_string(), 1.5)];
+        let mut expected = vec![0x80, 2, b']', b'(', b'X', 3, 0, 0, 0, b'a', b'.', b'b',
+                                b'J', 0x10, 0, 0, 0, b'G'];
+        expected.extend_from_slice(&1.5f64.to_bits().to_be_bytes());
+        expected.extend_from_slice(&[0x86, 0x86, b'e', b'.']);
+        assert_eq!(expected, pickle_batch(&batch, 16));
+    }
+
+    #[test]
+    fn test_replays_queued_flushes_in_order() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut graphite = Graphite::new("127.0.0.1", port, "stats", "counters", "gauges", "timers", 1).unwrap();
+        graphite.set_retry_queue("graphite", 5, None, 0).unwrap();
+        graphite.destinations[0].spool.push(b"queued 1 1\n".to_vec());
+        graphite.destinations[0].spool.push(b"queued 2 2\n".to_vec());
+
+        graphite.flush_buckets(&make_buckets()).unwrap();
+        assert_eq!(0, graphite.destinations[0].spool.len());
+        drop(graphite);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert!(received.starts_with("queued 1 1\nqueued 2 2\nstats.statsd.bad_messages"));
+    }
+
+    #[test]
+    fn test_queues_when_unreachable() {
+        use std::net::TcpListener;
+
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut graphite = Graphite::new("127.0.0.1", port, "stats", "counters", "gauges", "timers", 1).unwrap();
+        graphite.set_retry_queue("graphite", 2, None, 0).unwrap();
+        for _ in 0..3 {
+            assert!(graphite.flush_buckets(&make_buckets()).is_err());
+        }
+        assert_eq!(2, graphite.destinations[0].spool.len());
+        assert_eq!(1, graphite.destinations[0].spool.dropped());
+    }
+
+    #[test]
+    fn test_routes_to_destinations() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listeners = [TcpListener::bind("127.0.0.1:0").unwrap(), TcpListener::bind("127.0.0.1:0").unwrap()];
+        let ports: Vec<u16> = listeners.iter().map(|l| l.local_addr().unwrap().port()).collect();
+        let mut graphite = Graphite::new("127.0.0.1", 2003, "stats", "counters", "gauges", "timers", 1).unwrap();
+        graphite.use_destinations(&format!("127.0.0.1:{}:a, 127.0.0.1:{}:b", ports[0], ports[1])).unwrap();
+        graphite.use_consistent_hashing(1, false);
+        let expected = graphite.route(graphite.collect_stats(&make_buckets()));
+        assert_eq!(5, expected[0].len() + expected[1].len());
+
+        graphite.flush_buckets(&make_buckets()).unwrap();
+        let status = graphite.status();
+        assert_eq!(format!("127.0.0.1:{}:a", ports[0]), status[0].0);
+        assert_eq!("connected=true queued=0 spilled=0 dropped=0", status[0].1);
+        drop(graphite);
+
+        for (listener, stats) in listeners.iter().zip(expected.iter()) {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = String::new();
+            stream.read_to_string(&mut received).unwrap();
+            let paths: Vec<&str> = received.lines().map(|line| line.split(' ').next().unwrap()).collect();
+            assert_eq!(stats.iter().map(|(path, _)| path.as_str()).collect::<Vec<_>>(), paths);
+        }
+    }
+
+    #[test]
+    fn test_replicates_to_destinations() {
+        let mut graphite = Graphite::new("127.0.0.1", 2003, "stats", "counters", "gauges", "timers", 1).unwrap();
+        graphite.use_destinations("127.0.0.1:2004:a,127.0.0.1:2104:b,127.0.0.2:2004").unwrap();
+        let stats = graphite.collect_stats(&make_buckets());
+        assert!(graphite.route(stats.clone()).iter().all(|routed| routed.len() == 5));
+
+        graphite.use_consistent_hashing(2, true);
+        for (path, _) in stats.iter() {
+            let routed: Vec<usize> = graphite.route(vec![(path.clone(), 1.0)])
+                .iter()
+                .enumerate()
+                .filter(|&(_, points)| !points.is_empty())
+                .map(|(idx, _)| idx)
+                .collect();
+            // One replica on each server
+            assert_eq!(2, routed.len());
+            assert!(routed.contains(&2));
+        }
+
+        assert!(graphite.use_destinations("127.0.0.1:2004:a,127.0.0.1:2104:a").is_err());
+        assert!(graphite.use_destinations("127.0.0.1").is_err());
+        assert!(graphite.use_destinations("").is_err());
+    }
+
+    #[test]
+    fn test_tag_pattern() {
+        let pattern = TagPattern::parse("app.<env>.<host>.*").unwrap();
+        assert_eq!(Some(("app.requests.count".to_string(),
+                         vec![("env".to_string(), "prod".to_string()),
+                              ("host".to_string(), "web1".to_string())])),
+                   pattern.extract("app.prod.web1.requests.count"));
+        assert_eq!(None, pattern.extract("app.prod.web1"));
+        assert_eq!(None, pattern.extract("db.prod.web1.queries"));
+        assert!(TagPattern::parse("app.<name>").is_err());
+        assert!(TagPattern::parse("app.x<env>").is_err());
+        assert!(TagPattern::parse("app..x").is_err());
+    }
+
+    #[test]
+    fn test_format_tagged() {
+        let mut buckets = Buckets::new(0., true);
+        buckets.add(&Metric::new("app.prod.web1.requests", 2.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("app.prod.web1.load;host=web9;dc=~eu", 1.5, MetricKind::Gauge));
+        buckets.add(&Metric::new("other.gauge", 1.0, MetricKind::Gauge));
+        let mut graphite = Graphite::new("127.0.0.1", 2003, "stats", "counters", "gauges", "timers", 1).unwrap();
+        graphite.use_tags(TagPattern::parse_list("app.<env>.<host>.*, db.<cluster>.*").unwrap());
+        let result = format_plaintext(&graphite.collect_stats(&buckets), 1);
+
+        assert!(result.contains("stats.counters.app.requests;env=prod;host=web1 2 "));
+        assert!(result.contains("stats.gauges.app.load;dc=_eu;env=prod;host=web9 1.5 "));
+        assert!(result.contains("stats.gauges.other.gauge 1 "));
+    }
 }
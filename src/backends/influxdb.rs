@@ -0,0 +1,265 @@
+use super::super::backend::Backend;
+use super::super::buckets::Buckets;
+use crate::http;
+use crate::metric::{split_tags, with_suffix};
+use crate::metric_processor::TIMER_STATS;
+use std::fmt::Write;
+use std::io::Write as IoWrite;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+use time;
+
+/// Largest UDP payload sent in one datagram.
+const MAX_UDP_PAYLOAD: usize = 1400;
+
+
+#[derive(Debug)]
+enum Transport {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    Http(http::Url),
+}
+
+#[derive(Debug)]
+pub struct InfluxDb {
+    transport: Transport,
+    timeout: Duration,
+}
+
+fn resolve(address: &str) -> Result<SocketAddr, String> {
+    address.to_socket_addrs()
+        .map_err(|e| format!("Cannot resolve {}: {}", address, e))?
+        .next()
+        .ok_or_else(|| format!("Cannot resolve {}", address))
+}
+
+impl InfluxDb {
+    /// Create an InfluxDB line protocol backend.
+    ///
+    /// The URL selects the transport: `udp://host:port`, `tcp://host:port`
+    /// or `http://host:port/write?db=name`.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let influx = InfluxDb::new("http://127.0.0.1:8086/write?db=statsd").unwrap();
+    /// ```
+    pub fn new(url: &str) -> Result<InfluxDb, String> {
+        let transport = if let Some(address) = url.strip_prefix("udp://") {
+            Transport::Udp(resolve(address)?)
+        } else if let Some(address) = url.strip_prefix("tcp://") {
+            Transport::Tcp(resolve(address)?)
+        } else {
+            Transport::Http(http::Url::parse(url)?)
+        };
+        Ok(InfluxDb {
+            transport: transport,
+            timeout: Duration::from_secs(5),
+        })
+    }
+
+    /// Convert the buckets into InfluxDB line protocol.
+    ///
+    /// Every timer becomes one measurement with its derived values as fields.
+    /// NaN and infinite values cannot be written and are left out.
+    pub fn format_stats(&self, buckets: &Buckets) -> String {
+        let timestamp = time::get_time().sec * 1_000_000_000;
+        let mut stats = String::new();
+
+        writeln!(stats,
+                 "statsd bad_messages={},total_messages={},unique_metrics={} {}",
+                 buckets.bad_messages(),
+                 buckets.total_messages(),
+                 buckets.unique_metrics(),
+                 timestamp)
+            .unwrap();
+
+        for (key, value) in buckets.all_counters() {
+            let fields = vec![("value", *value), ("rate", value / buckets.flush_interval())];
+            if let Some(fields) = format_fields(fields.into_iter()) {
+                writeln!(stats, "{} {} {}", series_key(key), fields, timestamp).unwrap();
+            }
+        }
+
+        for (key, value) in buckets.all_gauges() {
+            if let Some(fields) = format_fields(Some(("value", *value)).into_iter()) {
+                writeln!(stats, "{} {} {}", series_key(key), fields, timestamp).unwrap();
+            }
+        }
+
+        for (key, _) in buckets.all_timers() {
+            let fields = TIMER_STATS.iter().filter_map(|field| {
+                buckets.timer_data()
+                    .get(&with_suffix(key, field))
+                    .map(|value| (*field, *value))
+            });
+            if let Some(fields) = format_fields(fields) {
+                writeln!(stats, "{} {} {}", series_key(key), fields, timestamp).unwrap();
+            }
+        }
+        stats
+    }
+
+    fn send(&self, stats: &str) -> Result<(), String> {
+        match self.transport {
+            Transport::Udp(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+                for packet in chunk_lines(stats, MAX_UDP_PAYLOAD) {
+                    socket.send_to(packet.as_bytes(), addr).map_err(|e| e.to_string())?;
+                }
+                Ok(())
+            }
+            Transport::Tcp(addr) => {
+                let mut stream = TcpStream::connect_timeout(&addr, self.timeout)
+                    .map_err(|e| e.to_string())?;
+                stream.set_write_timeout(Some(self.timeout)).map_err(|e| e.to_string())?;
+                stream.write_all(stats.as_bytes()).map_err(|e| e.to_string())
+            }
+            Transport::Http(ref url) => {
+                let headers = vec![("Content-Type".to_string(), "text/plain; charset=utf-8".to_string())];
+                let response = http::post(url, &headers, stats.as_bytes(), self.timeout)
+                    .map_err(|e| e.to_string())?;
+                if response.is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("HTTP {}: {}", response.status, response.body.trim()))
+                }
+            }
+        }
+    }
+}
+
+/// Build `measurement,tag=value` from a possibly tagged metric name.
+fn series_key(key: &str) -> String {
+    let (name, tags) = split_tags(key);
+    let mut result = escape(name, &[',', ' ']);
+    for &(tag, value) in tags.iter() {
+        write!(result, ",{}={}", escape(tag, &[',', '=', ' ']), escape(value, &[',', '=', ' '])).unwrap();
+    }
+    result
+}
+
+/// Join the finite values as `name=value` fields, `None` when none is left.
+fn format_fields<'a, I: Iterator<Item = (&'a str, f64)>>(fields: I) -> Option<String> {
+    let fields: Vec<String> = fields
+        .filter(|&(_, value)| value.is_finite())
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    if fields.is_empty() {
+        None
+    } else {
+        Some(fields.join(","))
+    }
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Split newline separated lines into packets of at most `limit` bytes.
+fn chunk_lines(stats: &str, limit: usize) -> Vec<String> {
+    let mut packets = vec![String::new()];
+    for line in stats.lines() {
+        let last_idx = packets.len() - 1;
+        if !packets[last_idx].is_empty() && packets[last_idx].len() + line.len() + 1 > limit {
+            packets.push(String::new());
+        }
+        let last_idx = packets.len() - 1;
+        packets[last_idx].push_str(line);
+        packets[last_idx].push('\n');
+    }
+    packets
+}
+
+
+impl Backend for InfluxDb {
+    fn name(&self) -> &str {
+        "influxdb"
+    }
+
+    fn flush_buckets(&mut self, buckets: &Buckets) -> Result<(), String> {
+        let stats = self.format_stats(buckets);
+        let start = time::get_time();
+        self.send(&stats).map_err(|e| format!("Could not write to influxdb: {}", e))?;
+        let taken = time::get_time() - start;
+        println!("Successfully flushed {} bytes to influxdb in {} milliseconds",
+                 stats.len(), taken.num_milliseconds());
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::super::metric::{Metric, MetricKind};
+    use super::super::super::buckets::Buckets;
+    use super::*;
+
+    fn make_buckets() -> Buckets {
+        let mut buckets = Buckets::new(2., true);
+        buckets.add(&Metric::new("test.counter", 4.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("test.gauge;host=web 1", 3.211, MetricKind::Gauge));
+        buckets.add(&Metric::new("test.timer", 12.0, MetricKind::Timer));
+        buckets.add(&Metric::new("test.timer", 2.0, MetricKind::Timer));
+        buckets.process();
+        buckets
+    }
+
+    #[test]
+    fn test_format_stats() {
+        let influx = InfluxDb::new("udp://127.0.0.1:8089").unwrap();
+        let result = influx.format_stats(&make_buckets());
+        let lines: Vec<&str> = result.lines().collect();
+
+        assert_eq!(5, lines.len());
+        assert!(lines[0].starts_with("statsd bad_messages=0,total_messages=5,unique_metrics="));
+        assert!(result.contains("\ntest.counter value=4,rate=2 "));
+        assert!(result.contains("\ntest.gauge,host=web\\ 1 value=3.211 "));
+        assert!(result.contains("\ntest.timer count=2,count_ps=1,max=12,mean=7,median=7,min=2,"));
+    }
+
+    #[test]
+    fn test_skips_values_that_are_not_finite() {
+        let influx = InfluxDb::new("udp://127.0.0.1:8089").unwrap();
+        let mut buckets = Buckets::new(0., true);
+        buckets.add(&Metric::new("test.counter", 4.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("test.nan", f64::NAN, MetricKind::Gauge));
+        buckets.add(&Metric::new("test.inf", f64::INFINITY, MetricKind::Gauge));
+        let result = influx.format_stats(&buckets);
+
+        assert!(result.contains("\ntest.counter value=4 "));
+        assert!(!result.contains("test.nan"));
+        assert!(!result.contains("test.inf"));
+        assert!(!result.contains("inf") && !result.contains("NaN"));
+    }
+
+    #[test]
+    fn test_chunk_lines() {
+        let packets = chunk_lines("aaaa\nbbbb\ncc\n", 10);
+        assert_eq!(vec!["aaaa\nbbbb\n".to_string(), "cc\n".to_string()], packets);
+    }
+
+    #[test]
+    fn test_invalid_url() {
+        assert!(InfluxDb::new("https://localhost:8086/write").is_err());
+    }
+
+    #[test]
+    fn test_flush_over_http() {
+        let (port, requests) = http::serve_one(204);
+        let mut influx = InfluxDb::new(&format!("http://127.0.0.1:{}/write?db=statsd", port)).unwrap();
+        influx.flush_buckets(&make_buckets()).unwrap();
+
+        let request = requests.recv().unwrap();
+        assert_eq!("POST /write?db=statsd HTTP/1.1", request.request_line);
+        let body = String::from_utf8(request.body).unwrap();
+        assert!(body.contains("\ntest.counter value=4,rate=2 "));
+    }
+}
@@ -0,0 +1,285 @@
+use super::super::backend::Backend;
+use super::super::buckets::Buckets;
+use crate::metric::{split_tags, with_suffix};
+use crate::metric_processor::TIMER_STATS;
+use serde_json::{Map, Value};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use time;
+
+
+/// Where the JSON lines are written.
+#[derive(Debug)]
+pub enum Output {
+    Stdout,
+    File(PathBuf),
+    /// Rotate to `<path>.1`, `<path>.2`, ... once the file reaches
+    /// `max_bytes`, keeping `keep` old files.
+    Rotating { path: PathBuf, max_bytes: u64, keep: usize },
+}
+
+impl Output {
+    /// Build the output from the CLI options, `-` being stdout.
+    pub fn new(path: &str, rotate_bytes: u64, rotate_keep: usize) -> Output {
+        if path == "-" {
+            Output::Stdout
+        } else if rotate_bytes == 0 {
+            Output::File(PathBuf::from(path))
+        } else {
+            Output::Rotating {
+                path: PathBuf::from(path),
+                max_bytes: rotate_bytes,
+                keep: rotate_keep,
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct JsonLines {
+    output: Output,
+    per_flush: bool,
+    file: Option<File>,
+    file_len: u64,
+}
+
+impl JsonLines {
+    /// Create a backend writing one JSON object per series, or one per
+    /// flush when `per_flush` is set.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let json = JsonLines::new(Output::Stdout, false);
+    /// ```
+    pub fn new(output: Output, per_flush: bool) -> JsonLines {
+        JsonLines {
+            output: output,
+            per_flush: per_flush,
+            file: None,
+            file_len: 0,
+        }
+    }
+
+    /// Convert the buckets into newline terminated JSON objects.
+    pub fn format_stats(&self, buckets: &Buckets) -> String {
+        let timestamp = time::get_time().sec;
+        let series = series(buckets);
+
+        let mut lines = String::new();
+        if self.per_flush {
+            let mut flush = Map::new();
+            flush.insert("timestamp".to_string(), timestamp.into());
+            flush.insert("series".to_string(),
+                         Value::Array(series.into_iter().map(Value::Object).collect()));
+            lines.push_str(&Value::Object(flush).to_string());
+            lines.push('\n');
+        } else {
+            for mut object in series.into_iter() {
+                object.insert("timestamp".to_string(), timestamp.into());
+                lines.push_str(&Value::Object(object).to_string());
+                lines.push('\n');
+            }
+        }
+        lines
+    }
+
+    fn write(&mut self, lines: &str) -> io::Result<()> {
+        let (path, rotation) = match self.output {
+            Output::Stdout => {
+                let stdout = io::stdout();
+                let mut handle = stdout.lock();
+                handle.write_all(lines.as_bytes())?;
+                return handle.flush();
+            }
+            Output::File(ref path) => (path.clone(), None),
+            Output::Rotating { ref path, max_bytes, keep } => (path.clone(), Some((max_bytes, keep))),
+        };
+
+        if let Some((max_bytes, keep)) = rotation {
+            if self.file.is_some() && self.file_len + lines.len() as u64 > max_bytes {
+                self.file = None;
+                rotate(&path, keep)?;
+            }
+        }
+        if self.file.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            self.file_len = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        self.file.as_mut().unwrap().write_all(lines.as_bytes())?;
+        self.file_len += lines.len() as u64;
+        Ok(())
+    }
+}
+
+/// The series of a flush as JSON objects without the timestamp, the
+/// internal counts first, then counters, gauges and timers sorted by name.
+pub fn series(buckets: &Buckets) -> Vec<Map<String, Value>> {
+    let mut series = Vec::with_capacity(3 + buckets.counters().len() +
+                                        buckets.gauges().len() +
+                                        buckets.timers().len());
+
+    series.push(series_object("statsd", "statsd.bad_messages", "value",
+                              (buckets.bad_messages() as f64).into()));
+    series.push(series_object("statsd", "statsd.total_messages", "value",
+                              (buckets.total_messages() as f64).into()));
+    series.push(series_object("statsd", "statsd.unique_metrics", "value",
+                              (buckets.unique_metrics() as f64).into()));
+
+    for (key, value) in sorted(buckets.all_counters()) {
+        let mut object = series_object("counter", key, "value", (*value).into());
+        object.insert("rate".to_string(), (value / buckets.flush_interval()).into());
+        series.push(object);
+    }
+
+    for (key, value) in sorted(buckets.all_gauges()) {
+        series.push(series_object("gauge", key, "value", (*value).into()));
+    }
+
+    for (key, _) in sorted(buckets.all_timers()) {
+        let mut stats = Map::new();
+        for field in TIMER_STATS.iter() {
+            if let Some(value) = buckets.timer_data().get(&with_suffix(key, field)) {
+                stats.insert(field.to_string(), (*value).into());
+            }
+        }
+        series.push(series_object("timer", key, "stats", Value::Object(stats)));
+    }
+    series
+}
+
+/// One series object, without the timestamp.
+pub fn series_object(kind: &str, key: &str, field: &str, value: Value) -> Map<String, Value> {
+    let (name, tags) = split_tags(key);
+    let mut object = Map::new();
+    object.insert("type".to_string(), kind.into());
+    object.insert("name".to_string(), name.into());
+    object.insert(field.to_string(), value);
+    if !tags.is_empty() {
+        let tags = tags.iter()
+            .map(|&(tag, value)| (tag.to_string(), Value::from(value)))
+            .collect();
+        object.insert("tags".to_string(), Value::Object(tags));
+    }
+    object
+}
+
+fn sorted<'a, I, V>(items: I) -> Vec<(&'a String, V)>
+    where I: Iterator<Item = (&'a String, V)>
+{
+    let mut items: Vec<(&'a String, V)> = items.collect();
+    items.sort_by(|a, b| a.0.cmp(b.0));
+    items
+}
+
+/// Shift `path` to `path.1`, `path.1` to `path.2` and so on, removing the
+/// file beyond `keep`.
+fn rotate(path: &PathBuf, keep: usize) -> io::Result<()> {
+    let numbered = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    let _ = fs::remove_file(numbered(keep));
+    for n in (1..keep).rev() {
+        if numbered(n).exists() {
+            fs::rename(numbered(n), numbered(n + 1))?;
+        }
+    }
+    fs::rename(path, numbered(1))
+}
+
+
+impl Backend for JsonLines {
+    fn name(&self) -> &str {
+        "json_lines"
+    }
+
+    fn flush_buckets(&mut self, buckets: &Buckets) -> Result<(), String> {
+        let lines = self.format_stats(buckets);
+        self.write(&lines).map_err(|e| {
+            self.file = None;
+            format!("Could not write json lines to {:?}: {:?}", self.output, e)
+        })
+    }
+
+    fn shutdown(&mut self) -> Result<(), String> {
+        match self.file.take() {
+            Some(file) => file.sync_all().map_err(|e| format!("Could not sync json lines: {:?}", e)),
+            None => Ok(()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::super::metric::{Metric, MetricKind};
+    use super::super::super::buckets::Buckets;
+    use super::*;
+
+    fn make_buckets() -> Buckets {
+        let mut buckets = Buckets::new(2., true);
+        buckets.add(&Metric::new("test.counter;host=web1", 4.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("test.gauge", 3.5, MetricKind::Gauge));
+        buckets.add(&Metric::new("test.timer", 12.0, MetricKind::Timer));
+        buckets.add(&Metric::new("test.timer", 2.0, MetricKind::Timer));
+        buckets.process();
+        buckets
+    }
+
+    #[test]
+    fn test_format_series() {
+        let json = JsonLines::new(Output::Stdout, false);
+        let result = json.format_stats(&make_buckets());
+        let objects: Vec<Value> = result.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        // The internal series and statsd.processing_time come first.
+        assert_eq!(7, objects.len());
+        assert_eq!("statsd.bad_messages", objects[0]["name"]);
+        let counter = &objects[4];
+        assert_eq!("counter", counter["type"]);
+        assert_eq!("test.counter", counter["name"]);
+        assert_eq!(4.0, counter["value"]);
+        assert_eq!(2.0, counter["rate"]);
+        assert_eq!("web1", counter["tags"]["host"]);
+        assert!(counter["timestamp"].is_i64());
+        assert_eq!(3.5, objects[5]["value"]);
+        assert_eq!(12.0, objects[6]["stats"]["max"]);
+        assert_eq!(7.0, objects[6]["stats"]["mean"]);
+    }
+
+    #[test]
+    fn test_format_per_flush() {
+        let json = JsonLines::new(Output::Stdout, true);
+        let result = json.format_stats(&make_buckets());
+        assert_eq!(1, result.lines().count());
+        let flush: Value = serde_json::from_str(&result).unwrap();
+        assert!(flush["timestamp"].is_i64());
+        assert_eq!(7, flush["series"].as_array().unwrap().len());
+    }
+
+    #[test]
+    fn test_rotating_files() {
+        let dir = std::env::temp_dir().join(format!("statsd-json-lines-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("flush.jsonl");
+
+        let mut json = JsonLines::new(Output::new(path.to_str().unwrap(), 100, 2), true);
+        for _ in 0..4 {
+            json.flush_buckets(&make_buckets()).unwrap();
+        }
+        assert!(path.exists());
+        assert!(dir.join("flush.jsonl.1").exists());
+        assert!(dir.join("flush.jsonl.2").exists());
+        assert!(!dir.join("flush.jsonl.3").exists());
+        assert_eq!(1, fs::read_to_string(&path).unwrap().lines().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
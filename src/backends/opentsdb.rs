@@ -0,0 +1,255 @@
+use super::super::backend::Backend;
+use super::super::buckets::Buckets;
+use crate::metric::split_tags;
+use std::fmt::Write;
+use std::fs;
+use std::io::Write as IoWrite;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use time;
+
+
+#[derive(Debug)]
+pub struct OpenTsdb {
+    addr: SocketAddr,
+    stream: Option<TcpStream>,
+    timeout: Duration,
+    flush_interval_seconds: i32,
+    global_prefix: String,
+    default_tags: Vec<(String, String)>,
+}
+
+/// The name of this machine, used for the default `host` tag.
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// Replace characters OpenTSDB does not accept in names and tags.
+fn sanitize(value: &str) -> String {
+    value.chars()
+        .map(|c| if c.is_alphanumeric() || "-_./".contains(c) { c } else { '_' })
+        .collect()
+}
+
+/// Parse `key=value` pairs separated by comma.
+///
+/// When empty, the tags default to `host=<hostname>`. A `host` tag is
+/// required as every OpenTSDB data point needs at least one tag.
+pub fn parse_tags(spec: &str) -> Result<Vec<(String, String)>, String> {
+    let mut tags = Vec::new();
+    for pair in spec.split(',').map(|pair| pair.trim()).filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !key.is_empty() && !value.is_empty() => {
+                tags.push((sanitize(key), sanitize(value)));
+            }
+            _ => return Err(format!("OpenTSDB tag `{}` must be key=value", pair)),
+        }
+    }
+    if tags.is_empty() {
+        tags.push(("host".to_string(), sanitize(&hostname())));
+    }
+    if !tags.iter().any(|(key, _)| key == "host") {
+        return Err("OpenTSDB tags must include host".to_string());
+    }
+    Ok(tags)
+}
+
+impl OpenTsdb {
+    /// Create an OpenTSDB telnet `put` backend
+    ///
+    /// Fails when the host cannot be resolved.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let tags = parse_tags("host=web1").unwrap();
+    /// let tsdb = OpenTsdb::new("127.0.0.1", 4242, "stats", tags, 10).unwrap();
+    /// ```
+    pub fn new(host: &str, port: u16,
+               global_prefix: &str,
+               default_tags: Vec<(String, String)>,
+               flush_interval_seconds: i32,
+    ) -> Result<OpenTsdb, String> {
+        let addr = (host, port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Cannot resolve OpenTSDB host {}: {}", host, e))?
+            .last()
+            .ok_or_else(|| format!("Cannot resolve OpenTSDB host {}", host))?;
+        Ok(OpenTsdb {
+            addr: addr,
+            stream: None,
+            timeout: Duration::from_secs(5),
+            flush_interval_seconds: flush_interval_seconds,
+            global_prefix: if global_prefix.is_empty() {
+                "".to_owned()
+            } else {
+                format!("{}.", global_prefix)
+            },
+            default_tags: default_tags,
+        })
+    }
+
+    /// Format one `put` line, merging the metric's tags over the defaults.
+    fn put_line(&self, out: &mut String, prefix: &str, key: &str, value: f64, timestamp: i64) {
+        let (name, tags) = split_tags(key);
+        write!(out, "put {} {} {}", sanitize(&format!("{}{}{}", self.global_prefix, prefix, name)),
+               timestamp, value).unwrap();
+        for (tag, tag_value) in self.default_tags.iter() {
+            if !tags.iter().any(|&(metric_tag, _)| sanitize(metric_tag) == *tag) {
+                write!(out, " {}={}", tag, tag_value).unwrap();
+            }
+        }
+        for &(tag, tag_value) in tags.iter() {
+            write!(out, " {}={}", sanitize(tag), sanitize(tag_value)).unwrap();
+        }
+        out.push('\n');
+    }
+
+    /// Convert the buckets into `put` commands
+    pub fn format_stats(&self, buckets: &Buckets) -> String {
+        let start = time::get_time().sec;
+        let mut stats = String::new();
+
+        self.put_line(&mut stats, "", "statsd.bad_messages", buckets.bad_messages() as f64, start);
+        self.put_line(&mut stats, "", "statsd.total_messages", buckets.total_messages() as f64, start);
+        self.put_line(&mut stats, "", "statsd.unique_metrics", buckets.unique_metrics() as f64, start);
+
+        for (key, value) in buckets.all_counters() {
+            self.put_line(&mut stats, "counters.", key,
+                          value / self.flush_interval_seconds as f64, start);
+        }
+
+        for (key, value) in buckets.all_gauges() {
+            self.put_line(&mut stats, "gauges.", key, *value, start);
+        }
+
+        // The raw timer data is not sent to opentsdb.
+        for (key, value) in buckets.timer_data().iter() {
+            self.put_line(&mut stats, "timers.", key, *value, start);
+        }
+        stats
+    }
+
+    /// Write to the persistent connection, reconnecting once if it broke.
+    fn send(&mut self, stats: &str) -> Result<(), String> {
+        for attempt in 0..2 {
+            if self.stream.is_none() {
+                let stream = TcpStream::connect_timeout(&self.addr, self.timeout)
+                    .map_err(|e| format!("Cannot connect to opentsdb server: {:?}", e))?;
+                stream.set_write_timeout(Some(self.timeout))
+                    .map_err(|e| format!("Cannot configure opentsdb connection: {:?}", e))?;
+                self.stream = Some(stream);
+            }
+            let result = self.stream.as_mut().unwrap().write_all(stats.as_bytes());
+            match result {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    self.stream = None;
+                    if attempt == 1 {
+                        return Err(format!("Could not complete write to opentsdb: {:?}", e));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+impl Backend for OpenTsdb {
+    fn name(&self) -> &str {
+        "opentsdb"
+    }
+
+    fn flush_buckets(&mut self, buckets: &Buckets) -> Result<(), String> {
+        let stats = self.format_stats(buckets);
+        let start = time::get_time();
+
+        self.send(&stats)?;
+        let taken = time::get_time() - start;
+        println!("Successfully flushed {} bytes to opentsdb in {} milliseconds",
+                 stats.len(), taken.num_milliseconds());
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> Result<(), String> {
+        self.stream = None;
+        Ok(())
+    }
+
+    fn status(&self) -> Vec<(String, String)> {
+        vec![("connected".to_string(), self.stream.is_some().to_string())]
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::super::metric::{Metric, MetricKind};
+    use super::super::super::buckets::Buckets;
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    fn make_buckets() -> Buckets {
+        let mut buckets = Buckets::new(0., true);
+        buckets.add(&Metric::new("test.counter", 1.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("test.gauge;host=web2;dc=eu", 3.211, MetricKind::Gauge));
+        buckets.add(&Metric::new("test.timer", 12.101, MetricKind::Timer));
+        buckets
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        assert_eq!(vec![("host".to_string(), "web1".to_string()), ("dc".to_string(), "eu".to_string())],
+                   parse_tags("host=web1, dc=eu").unwrap());
+        assert_eq!("host", parse_tags("").unwrap()[0].0);
+        assert!(parse_tags("dc=eu").is_err());
+        assert!(parse_tags("host").is_err());
+    }
+
+    #[test]
+    fn test_unresolvable_host() {
+        let error = OpenTsdb::new("", 4242, "stats", parse_tags("host=web1").unwrap(), 1).err().unwrap();
+        assert!(error.starts_with("Cannot resolve OpenTSDB host"), "{}", error);
+    }
+
+    #[test]
+    fn test_format_stats() {
+        let mut buckets = make_buckets();
+        buckets.process();
+        let tsdb = OpenTsdb::new("127.0.0.1", 4242, "stats",
+                                 parse_tags("host=web1,env=prod").unwrap(), 2).unwrap();
+        let result = tsdb.format_stats(&buckets);
+        let lines: Vec<&str> = result.lines().collect();
+
+        assert!(lines[0].starts_with("put stats.statsd.bad_messages "));
+        assert!(lines[0].ends_with(" 0 host=web1 env=prod"));
+        assert!(result.contains(" 0.5 host=web1 env=prod\n"));
+        assert!(result.contains("put stats.gauges.test.gauge "));
+        assert!(result.contains(" 3.211 env=prod host=web2 dc=eu\n"));
+        assert!(result.contains("put stats.timers.test.timer.max "));
+    }
+
+    #[test]
+    fn test_keeps_connection_open() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut tsdb = OpenTsdb::new("127.0.0.1", port, "stats", parse_tags("host=web1").unwrap(), 1).unwrap();
+
+        tsdb.flush_buckets(&make_buckets()).unwrap();
+        tsdb.flush_buckets(&make_buckets()).unwrap();
+        drop(tsdb);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!(2, received.matches("put stats.statsd.bad_messages").count());
+    }
+}
@@ -0,0 +1,441 @@
+//! Export flushes as OpenTelemetry metrics over OTLP/HTTP.
+//!
+//! The `ExportMetricsServiceRequest` protobuf message is encoded by hand,
+//! only the fields the backend sends are implemented.
+
+use super::super::backend::Backend;
+use super::super::buckets::Buckets;
+use crate::http;
+use crate::metric::{split_tags, with_suffix};
+use std::time::Duration;
+use time;
+
+/// Summary quantiles and the timer data they are read from.
+const QUANTILES: [(f64, &str); 6] = [
+    (0.0, "min"),
+    (0.5, "median"),
+    (0.9, "upper_90"),
+    (0.95, "upper_95"),
+    (0.99, "upper_99"),
+    (1.0, "max"),
+];
+
+/// `AGGREGATION_TEMPORALITY_DELTA`
+const TEMPORALITY_DELTA: u64 = 1;
+
+// Protobuf wire types.
+const VARINT: u32 = 0;
+const FIXED64: u32 = 1;
+const LENGTH_DELIMITED: u32 = 2;
+
+
+/// Append protobuf fields to a buffer.
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn new() -> Encoder {
+        Encoder { buf: Vec::new() }
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(u64::from(field << 3 | wire_type));
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.key(field, VARINT);
+        self.varint(value);
+    }
+
+    fn fixed64(&mut self, field: u32, value: u64) {
+        self.key(field, FIXED64);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn double(&mut self, field: u32, value: f64) {
+        self.fixed64(field, value.to_bits());
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, LENGTH_DELIMITED);
+        self.varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    /// Encode a nested message built by `build`.
+    fn message<F: FnOnce(&mut Encoder)>(&mut self, field: u32, build: F) {
+        let mut nested = Encoder::new();
+        build(&mut nested);
+        self.bytes(field, &nested.buf);
+    }
+
+    /// A `KeyValue` with a string `AnyValue`.
+    fn attribute(&mut self, field: u32, key: &str, value: &str) {
+        self.message(field, |kv| {
+            kv.string(1, key);
+            kv.message(2, |any| any.string(1, value));
+        });
+    }
+}
+
+
+#[derive(Debug)]
+pub struct Otlp {
+    url: http::Url,
+    resource: Vec<(String, String)>,
+    timeout: Duration,
+}
+
+/// Parse `key=value` resource attributes separated by comma.
+pub fn parse_attributes(spec: &str) -> Result<Vec<(String, String)>, String> {
+    spec.split(',')
+        .map(|pair| pair.trim())
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+                _ => Err(format!("OTLP resource attribute `{}` must be key=value", pair)),
+            }
+        })
+        .collect()
+}
+
+impl Otlp {
+    /// Create a backend posting OTLP protobuf to an OTLP/HTTP endpoint.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let resource = parse_attributes("service.name=statsd").unwrap();
+    /// let otlp = Otlp::new("http://127.0.0.1:4318/v1/metrics", resource).unwrap();
+    /// ```
+    pub fn new(url: &str, resource: Vec<(String, String)>) -> Result<Otlp, String> {
+        Ok(Otlp {
+            url: http::Url::parse(url)?,
+            resource: resource,
+            timeout: Duration::from_secs(5),
+        })
+    }
+
+    /// Encode the buckets as an `ExportMetricsServiceRequest`.
+    ///
+    /// Counters become delta sums, gauges gauges and timers summaries.
+    pub fn format_stats(&self, buckets: &Buckets) -> Vec<u8> {
+        let now = time::get_time();
+        let end = now.sec as u64 * 1_000_000_000 + now.nsec as u64;
+        let start = end.saturating_sub((buckets.flush_interval() * 1e9) as u64);
+
+        let mut request = Encoder::new();
+        request.message(1, |resource_metrics| {
+            resource_metrics.message(1, |resource| {
+                for (key, value) in self.resource.iter() {
+                    resource.attribute(1, key, value);
+                }
+            });
+            resource_metrics.message(2, |scope_metrics| {
+                scope_metrics.message(1, |scope| {
+                    scope.string(1, "statsd");
+                    scope.string(2, env!("CARGO_PKG_VERSION"));
+                });
+
+                let internal = [
+                    ("statsd.bad_messages", buckets.bad_messages() as f64),
+                    ("statsd.total_messages", buckets.total_messages() as f64),
+                    ("statsd.unique_metrics", buckets.unique_metrics() as f64),
+                ];
+                for &(name, value) in internal.iter() {
+                    gauge(scope_metrics, name, value, end);
+                }
+
+                for (key, value) in buckets.all_counters() {
+                    let (name, tags) = split_tags(key);
+                    scope_metrics.message(2, |metric| {
+                        metric.string(1, name);
+                        metric.message(7, |sum| {
+                            sum.message(1, |point| {
+                                for &(tag, tag_value) in tags.iter() {
+                                    point.attribute(7, tag, tag_value);
+                                }
+                                point.fixed64(2, start);
+                                point.fixed64(3, end);
+                                point.double(4, *value);
+                            });
+                            sum.uint(2, TEMPORALITY_DELTA);
+                            // Not monotonic, statsd counters accept negative increments.
+                            sum.uint(3, 0);
+                        });
+                    });
+                }
+
+                for (key, value) in buckets.all_gauges() {
+                    gauge(scope_metrics, key, *value, end);
+                }
+
+                for (key, values) in buckets.all_timers() {
+                    if values.is_empty() {
+                        continue;
+                    }
+                    let (name, tags) = split_tags(key);
+                    scope_metrics.message(2, |metric| {
+                        metric.string(1, name);
+                        metric.string(3, "ms");
+                        metric.message(11, |summary| {
+                            summary.message(1, |point| {
+                                for &(tag, tag_value) in tags.iter() {
+                                    point.attribute(7, tag, tag_value);
+                                }
+                                point.fixed64(2, start);
+                                point.fixed64(3, end);
+                                point.fixed64(4, values.len() as u64);
+                                point.double(5, values.iter().fold(0.0, |sum, x| sum + x));
+                                for &(quantile, suffix) in QUANTILES.iter() {
+                                    if let Some(value) = buckets.timer_data().get(&with_suffix(key, suffix)) {
+                                        point.message(6, |at| {
+                                            at.double(1, quantile);
+                                            at.double(2, *value);
+                                        });
+                                    }
+                                }
+                            });
+                        });
+                    });
+                }
+            });
+        });
+        request.buf
+    }
+}
+
+/// Add a `Gauge` metric with one data point.
+fn gauge(scope_metrics: &mut Encoder, key: &str, value: f64, end: u64) {
+    let (name, tags) = split_tags(key);
+    scope_metrics.message(2, |metric| {
+        metric.string(1, name);
+        metric.message(5, |gauge| {
+            gauge.message(1, |point| {
+                for &(tag, tag_value) in tags.iter() {
+                    point.attribute(7, tag, tag_value);
+                }
+                point.fixed64(3, end);
+                point.double(4, value);
+            });
+        });
+    });
+}
+
+
+impl Backend for Otlp {
+    fn name(&self) -> &str {
+        "otlp"
+    }
+
+    fn flush_buckets(&mut self, buckets: &Buckets) -> Result<(), String> {
+        let body = self.format_stats(buckets);
+        let headers = vec![("Content-Type".to_string(), "application/x-protobuf".to_string())];
+        let start = time::get_time();
+        match http::post(&self.url, &headers, &body, self.timeout) {
+            Ok(ref response) if response.is_success() => {
+                let taken = time::get_time() - start;
+                println!("Successfully flushed {} bytes to otlp in {} milliseconds",
+                         body.len(), taken.num_milliseconds());
+                Ok(())
+            }
+            Ok(response) => Err(format!("Could not write to otlp: HTTP {}: {}",
+                                        response.status, response.body.trim())),
+            Err(e) => Err(format!("Could not write to otlp: {:?}", e)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::super::metric::{Metric, MetricKind};
+    use super::super::super::buckets::Buckets;
+    use super::*;
+
+    /// A decoded protobuf field value.
+    #[derive(Debug, Clone)]
+    enum Field {
+        Int(u64),
+        Bytes(Vec<u8>),
+    }
+
+    fn read_varint(buf: &[u8], pos: &mut usize) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = buf[*pos];
+            *pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Vec<(u32, Field)> {
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let key = read_varint(buf, &mut pos);
+            let field = (key >> 3) as u32;
+            match (key & 7) as u32 {
+                VARINT => fields.push((field, Field::Int(read_varint(buf, &mut pos)))),
+                FIXED64 => {
+                    let mut bytes = [0; 8];
+                    bytes.copy_from_slice(&buf[pos..pos + 8]);
+                    pos += 8;
+                    fields.push((field, Field::Int(u64::from_le_bytes(bytes))));
+                }
+                LENGTH_DELIMITED => {
+                    let len = read_varint(buf, &mut pos) as usize;
+                    fields.push((field, Field::Bytes(buf[pos..pos + len].to_vec())));
+                    pos += len;
+                }
+                wire_type => panic!("Unexpected wire type {}", wire_type),
+            }
+        }
+        fields
+    }
+
+    fn get(fields: &[(u32, Field)], number: u32) -> Vec<Field> {
+        fields.iter()
+            .filter(|&&(field, _)| field == number)
+            .map(|(_, value)| value.clone())
+            .collect()
+    }
+
+    fn message(field: &Field) -> Vec<(u32, Field)> {
+        match *field {
+            Field::Bytes(ref bytes) => decode(bytes),
+            _ => panic!("Not a message"),
+        }
+    }
+
+    fn string(field: &Field) -> String {
+        match *field {
+            Field::Bytes(ref bytes) => String::from_utf8(bytes.clone()).unwrap(),
+            _ => panic!("Not a string"),
+        }
+    }
+
+    fn double(field: &Field) -> f64 {
+        match *field {
+            Field::Int(bits) => f64::from_bits(bits),
+            _ => panic!("Not a double"),
+        }
+    }
+
+    /// The metrics of the first scope, by name.
+    fn metrics(body: &[u8]) -> Vec<(String, Vec<(u32, Field)>)> {
+        let request = decode(body);
+        let resource_metrics = message(&get(&request, 1)[0]);
+        let scope_metrics = message(&get(&resource_metrics, 2)[0]);
+        get(&scope_metrics, 2).iter()
+            .map(|metric| {
+                let metric = message(metric);
+                (string(&get(&metric, 1)[0]), metric)
+            })
+            .collect()
+    }
+
+    fn make_buckets() -> Buckets {
+        let mut buckets = Buckets::new(10., false);
+        buckets.add(&Metric::new("api.requests;host=web1", 5.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("queue.depth", 7.5, MetricKind::Gauge));
+        buckets.add(&Metric::new("api.latency", 10.0, MetricKind::Timer));
+        buckets.add(&Metric::new("api.latency", 30.0, MetricKind::Timer));
+        buckets.process();
+        buckets
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut encoder = Encoder::new();
+        encoder.varint(300);
+        assert_eq!(vec![0xac, 0x02], encoder.buf);
+    }
+
+    #[test]
+    fn test_format_stats() {
+        let otlp = Otlp::new("http://127.0.0.1:4318/v1/metrics",
+                             parse_attributes("service.name=statsd").unwrap()).unwrap();
+        let metrics = metrics(&otlp.format_stats(&make_buckets()));
+
+        let (_, ref counter) = metrics.iter().find(|(name, _)| name == "api.requests").unwrap();
+        let sum = message(&get(counter, 7)[0]);
+        match get(&sum, 2)[0] {
+            Field::Int(temporality) => assert_eq!(TEMPORALITY_DELTA, temporality),
+            _ => panic!("Missing temporality"),
+        }
+        match get(&sum, 3)[0] {
+            Field::Int(monotonic) => assert_eq!(0, monotonic),
+            _ => panic!("Missing is_monotonic"),
+        }
+        let point = message(&get(&sum, 1)[0]);
+        assert_eq!(5.0, double(&get(&point, 4)[0]));
+        let attribute = message(&get(&point, 7)[0]);
+        assert_eq!("host", string(&get(&attribute, 1)[0]));
+
+        let (_, ref gauge) = metrics.iter().find(|(name, _)| name == "queue.depth").unwrap();
+        let point = message(&get(&message(&get(gauge, 5)[0]), 1)[0]);
+        assert_eq!(7.5, double(&get(&point, 4)[0]));
+
+        let (_, ref timer) = metrics.iter().find(|(name, _)| name == "api.latency").unwrap();
+        let point = message(&get(&message(&get(timer, 11)[0]), 1)[0]);
+        match get(&point, 4)[0] {
+            Field::Int(count) => assert_eq!(2, count),
+            _ => panic!("Missing count"),
+        }
+        assert_eq!(40.0, double(&get(&point, 5)[0]));
+        let quantiles = get(&point, 6);
+        assert_eq!(6, quantiles.len());
+        let max = message(&quantiles[5]);
+        assert_eq!(1.0, double(&get(&max, 1)[0]));
+        assert_eq!(30.0, double(&get(&max, 2)[0]));
+    }
+
+    #[test]
+    fn test_flush_to_collector() {
+        let (port, requests) = http::serve_one(200);
+        let mut otlp = Otlp::new(&format!("http://127.0.0.1:{}/v1/metrics", port),
+                                 parse_attributes("service.name=statsd").unwrap()).unwrap();
+        otlp.flush_buckets(&make_buckets()).unwrap();
+
+        let request = requests.recv().unwrap();
+        assert_eq!("POST /v1/metrics HTTP/1.1", request.request_line);
+        assert!(request.headers.contains(&("content-type".to_string(),
+                                           "application/x-protobuf".to_string())));
+        let names: Vec<String> = metrics(&request.body).into_iter().map(|(name, _)| name).collect();
+        assert!(names.contains(&"api.requests".to_string()));
+        assert!(names.contains(&"statsd.bad_messages".to_string()));
+
+        let resource = message(&get(&message(&get(&decode(&request.body), 1)[0]), 1)[0]);
+        let attribute = message(&get(&resource, 1)[0]);
+        assert_eq!("service.name", string(&get(&attribute, 1)[0]));
+    }
+
+    #[test]
+    fn test_parse_attributes() {
+        assert_eq!(vec![("service.name".to_string(), "statsd".to_string()),
+                        ("env".to_string(), "prod".to_string())],
+                   parse_attributes("service.name=statsd, env=prod").unwrap());
+        assert!(parse_attributes("service.name").is_err());
+    }
+}
@@ -0,0 +1,339 @@
+use super::super::backend::Backend;
+use super::super::buckets::Buckets;
+use crate::metric::{split_tags, with_suffix};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::io;
+use std::io::{BufRead, BufReader, Write as IoWrite};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Summary quantiles and the timer data they are read from.
+const QUANTILES: [(&str, &str); 4] = [
+    ("0.5", "median"),
+    ("0.9", "upper_90"),
+    ("0.95", "upper_95"),
+    ("0.99", "upper_99"),
+];
+
+/// All samples of one metric name, preceded by a single `# TYPE` line.
+struct Family {
+    kind: &'static str,
+    samples: Vec<String>,
+}
+
+pub struct Prometheus {
+    host: String,
+    port: u16,
+    exposition: Arc<Mutex<String>>,
+    counters: HashMap<String, f64>,
+    timer_counts: HashMap<String, f64>,
+    timer_sums: HashMap<String, f64>,
+}
+
+impl Prometheus {
+    /// Create a Prometheus backend serving `/metrics` over HTTP.
+    ///
+    /// The endpoint is bound when the backend is started.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let prom = Prometheus::new("0.0.0.0", 9102);
+    /// ```
+    pub fn new(host: &str, port: u16) -> Prometheus {
+        Prometheus {
+            host: host.to_string(),
+            port: port,
+            exposition: Arc::new(Mutex::new(String::new())),
+            counters: HashMap::new(),
+            timer_counts: HashMap::new(),
+            timer_sums: HashMap::new(),
+        }
+    }
+
+    /// Render the buckets in the Prometheus text exposition format.
+    ///
+    /// Counters and timer sums/counts are accumulated across flushes, as
+    /// Prometheus expects them to only ever increase, and are exposed
+    /// after every flush once seen.
+    pub fn format_stats(&mut self, buckets: &Buckets) -> String {
+        let mut families: BTreeMap<String, Family> = BTreeMap::new();
+
+        add_sample(&mut families, "statsd.bad_messages", "gauge", "", None,
+                   buckets.bad_messages() as f64);
+        add_sample(&mut families, "statsd.total_messages", "gauge", "", None,
+                   buckets.total_messages() as f64);
+        add_sample(&mut families, "statsd.unique_metrics", "gauge", "", None,
+                   buckets.unique_metrics() as f64);
+
+        // Update the totals first, then expose every series seen so far,
+        // also the ones without samples in this flush.
+        for (key, value) in buckets.all_counters() {
+            *self.counters.entry(key.clone()).or_insert(0.0) += value;
+        }
+        for (key, values) in buckets.all_timers() {
+            *self.timer_sums.entry(key.clone()).or_insert(0.0) += values.iter().fold(0.0, |sum, x| sum + x);
+            *self.timer_counts.entry(key.clone()).or_insert(0.0) += values.len() as f64;
+        }
+
+        let mut counters: Vec<(&String, &f64)> = self.counters.iter().collect();
+        counters.sort_by(|a, b| a.0.cmp(b.0));
+        for (key, total) in counters {
+            add_sample(&mut families, key, "counter", "", None, *total);
+        }
+
+        for (key, value) in buckets.all_gauges() {
+            add_sample(&mut families, key, "gauge", "", None, *value);
+        }
+
+        let mut timers: Vec<&String> = self.timer_sums.keys().collect();
+        timers.sort();
+        for key in timers {
+            for &(quantile, suffix) in QUANTILES.iter() {
+                if let Some(value) = buckets.timer_data().get(&with_suffix(key, suffix)) {
+                    add_sample(&mut families, key, "summary", "", Some(quantile), *value);
+                }
+            }
+            add_sample(&mut families, key, "summary", "_sum", None, self.timer_sums[key]);
+            add_sample(&mut families, key, "summary", "_count", None, self.timer_counts[key]);
+        }
+
+        let mut stats = String::new();
+        for (name, family) in families.iter() {
+            writeln!(stats, "# TYPE {} {}", name, family.kind).unwrap();
+            for sample in family.samples.iter() {
+                stats.push_str(sample);
+            }
+        }
+        stats
+    }
+}
+
+/// Add one sample line to the family of its sanitized name.
+///
+/// Samples whose name is already used by a metric of another type
+/// are skipped, as a name can only have one type.
+fn add_sample(families: &mut BTreeMap<String, Family>,
+              key: &str,
+              kind: &'static str,
+              suffix: &str,
+              quantile: Option<&str>,
+              value: f64) {
+    let (name, tags) = split_tags(key);
+    let name = sanitize_name(name);
+    let family = families.entry(name.clone()).or_insert_with(|| Family {
+        kind: kind,
+        samples: Vec::new(),
+    });
+    if family.kind != kind {
+        return;
+    }
+
+    let mut labels: Vec<String> = tags.iter()
+        .map(|&(key, value)| format!("{}=\"{}\"", sanitize_label(key), escape_label_value(value)))
+        .collect();
+    if let Some(quantile) = quantile {
+        labels.push(format!("quantile=\"{}\"", quantile));
+    }
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    };
+    family.samples.push(format!("{}{}{} {}\n", name, suffix, labels, format_value(value)));
+}
+
+/// Replace characters not allowed in Prometheus metric names.
+pub fn sanitize_name(name: &str) -> String {
+    let mut result: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == ':' { c } else { '_' })
+        .collect();
+    if result.is_empty() || result.starts_with(|c: char| c.is_ascii_digit()) {
+        result.insert(0, '_');
+    }
+    result
+}
+
+/// Replace characters not allowed in Prometheus label names.
+fn sanitize_label(name: &str) -> String {
+    let mut result: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    if result.is_empty() || result.starts_with(|c: char| c.is_ascii_digit()) {
+        result.insert(0, '_');
+    }
+    result
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf".to_string() } else { "-Inf".to_string() }
+    } else {
+        format!("{}", value)
+    }
+}
+
+/// Answer a single HTTP request with the latest exposition.
+fn respond(stream: TcpStream, exposition: &Mutex<String>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Skip the request headers.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("").split('?').next().unwrap_or("");
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK",
+                                "text/plain; version=0.0.4",
+                                exposition.lock().unwrap().clone()),
+        ("GET", _) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "Method not allowed\n".to_string()),
+    };
+
+    let stream = reader.get_mut();
+    write!(stream,
+           "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+           status, content_type, body.len())?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()
+}
+
+
+impl Backend for Prometheus {
+    fn name(&self) -> &str {
+        "prometheus"
+    }
+
+    /// Bind the metrics endpoint and serve it from a thread.
+    fn start(&mut self) -> Result<(), String> {
+        let listener = TcpListener::bind((self.host.as_str(), self.port))
+            .map_err(|e| format!("Cannot bind the prometheus metrics endpoint on {}:{}: {}",
+                                 self.host, self.port, e))?;
+
+        println!("Prometheus metrics on http://{}:{}/metrics", self.host, self.port);
+        let served = self.exposition.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = respond(stream, &served) {
+                            eprintln!("Failed to serve prometheus metrics: {:?}", e);
+                        }
+                    }
+                    Err(e) => eprintln!("Prometheus connection failed: {:?}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn flush_buckets(&mut self, buckets: &Buckets) -> Result<(), String> {
+        let stats = self.format_stats(buckets);
+        *self.exposition.lock().unwrap() = stats;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::super::metric::Metric;
+    use super::super::super::buckets::Buckets;
+    use super::*;
+    use std::io::Read;
+
+    fn make_buckets() -> Buckets {
+        let mut buckets = Buckets::new(10., false);
+        let metrics = Metric::parse("test.counter:2|c\n\
+                                     test.gauge:3.5|g|#host:web1\n\
+                                     test.timer:1|ms|#host:web1\n\
+                                     test.timer:3|ms|#host:web1\n\
+                                     7days:1|g").unwrap();
+        for metric in metrics.iter() {
+            buckets.add(metric);
+        }
+        buckets.process();
+        buckets
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!("api_web_1_requests", sanitize_name("api.web-1.requests"));
+        assert_eq!("_7days", sanitize_name("7days"));
+        assert_eq!("a:b_c", sanitize_name("a:b/c"));
+    }
+
+    #[test]
+    fn test_format_stats() {
+        let mut prom = Prometheus::new("127.0.0.1", 0);
+        let buckets = make_buckets();
+        let result = prom.format_stats(&buckets);
+
+        assert!(result.contains("# TYPE test_counter counter\ntest_counter 2\n"));
+        assert!(result.contains("# TYPE test_gauge gauge\ntest_gauge{host=\"web1\"} 3.5\n"));
+        assert!(result.contains("# TYPE test_timer summary\n"));
+        assert!(result.contains("test_timer{host=\"web1\",quantile=\"0.5\"} 2\n"));
+        assert!(result.contains("test_timer_sum{host=\"web1\"} 4\n"));
+        assert!(result.contains("test_timer_count{host=\"web1\"} 2\n"));
+        assert!(result.contains("_7days 1\n"));
+
+        // Counters and timer totals keep increasing between flushes.
+        let result = prom.format_stats(&buckets);
+        assert!(result.contains("test_counter 4\n"));
+        assert!(result.contains("test_timer_count{host=\"web1\"} 4\n"));
+    }
+
+    #[test]
+    fn test_keeps_series_without_samples() {
+        let mut prom = Prometheus::new("127.0.0.1", 0);
+        prom.format_stats(&make_buckets());
+
+        let mut buckets = Buckets::new(10., false);
+        buckets.add(&Metric::parse("test.gauge:1|g").unwrap()[0]);
+        buckets.process();
+        let result = prom.format_stats(&buckets);
+        assert!(result.contains("# TYPE test_counter counter\ntest_counter 2\n"));
+        assert!(result.contains("test_timer_sum{host=\"web1\"} 4\n"));
+        assert!(result.contains("test_timer_count{host=\"web1\"} 2\n"));
+        assert!(!result.contains("quantile"));
+    }
+
+    #[test]
+    fn test_serves_metrics() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut prom = Prometheus::new("127.0.0.1", port);
+        prom.start().unwrap();
+        prom.flush_buckets(&make_buckets()).unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("test_counter 2\n"));
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(b"GET /other HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,361 @@
+//! Building blocks for backends that must not lose flushes while their
+//! destination is down: a TCP connection that is kept open and reconnects
+//! with exponential backoff, and a bounded queue of unsent payloads that
+//! spills to disk.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// A long-lived TCP connection that reconnects with exponential backoff.
+#[derive(Debug)]
+pub struct PersistentStream {
+    addr: SocketAddr,
+    stream: Option<TcpStream>,
+    timeout: Duration,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+    next_attempt: Option<Instant>,
+}
+
+impl PersistentStream {
+    pub fn new(addr: SocketAddr) -> PersistentStream {
+        PersistentStream {
+            addr: addr,
+            stream: None,
+            timeout: Duration::from_secs(5),
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            backoff: Duration::from_secs(1),
+            next_attempt: None,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn set_addr(&mut self, addr: SocketAddr) {
+        self.addr = addr;
+        self.stream = None;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Write all the data, connecting first if needed.
+    ///
+    /// While waiting for the backoff to expire no connection is attempted
+    /// and an error is returned straight away.
+    pub fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_from(data, &mut 0)
+    }
+
+    /// Write the data from offset `written` on, like `write_all`, counting
+    /// the bytes written in `written`, also when the write fails part way.
+    pub fn write_from(&mut self, data: &[u8], written: &mut usize) -> io::Result<()> {
+        self.connect()?;
+        let result = {
+            let stream = self.stream.as_mut().unwrap();
+            let mut result = Ok(());
+            while *written < data.len() {
+                match stream.write(&data[*written..]) {
+                    Ok(0) => {
+                        result = Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed"));
+                        break;
+                    }
+                    Ok(len) => *written += len,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            result
+        };
+        if result.is_err() {
+            self.failed();
+        }
+        result
+    }
+
+    fn connect(&mut self) -> io::Result<()> {
+        if self.stream.is_none() {
+            if let Some(next_attempt) = self.next_attempt {
+                if Instant::now() < next_attempt {
+                    return Err(io::Error::new(io::ErrorKind::NotConnected,
+                                              "waiting to reconnect"));
+                }
+            }
+            match TcpStream::connect_timeout(&self.addr, self.timeout)
+                .and_then(|stream| stream.set_write_timeout(Some(self.timeout)).map(|_| stream)) {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    self.backoff = self.min_backoff;
+                    self.next_attempt = None;
+                }
+                Err(e) => {
+                    self.failed();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn failed(&mut self) {
+        self.stream = None;
+        self.next_attempt = Some(Instant::now() + self.backoff);
+        self.backoff = cmp::min(self.backoff * 2, self.max_backoff);
+    }
+}
+
+
+/// A FIFO of unsent payloads.
+///
+/// Up to `max_memory` payloads are kept in memory. Older payloads are
+/// written to files in the spill directory, if one is configured, and are
+/// always replayed before the ones in memory.
+#[derive(Debug)]
+pub struct Spool {
+    name: String,
+    memory: VecDeque<Vec<u8>>,
+    max_memory: usize,
+    dir: Option<PathBuf>,
+    spilled: VecDeque<PathBuf>,
+    max_spilled: usize,
+    next_seq: u64,
+    dropped: usize,
+}
+
+impl Spool {
+    /// Create a spool keeping `max_memory` payloads in memory.
+    pub fn new(name: &str, max_memory: usize) -> Spool {
+        Spool {
+            name: name.to_string(),
+            memory: VecDeque::new(),
+            max_memory: max_memory,
+            dir: None,
+            spilled: VecDeque::new(),
+            max_spilled: 0,
+            next_seq: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Spill up to `max_spilled` payloads to files in `dir`.
+    ///
+    /// Payloads spilled by a previous run with the same name are picked up
+    /// again so they are replayed after a restart.
+    pub fn spill_to(&mut self, dir: PathBuf, max_spilled: usize) -> io::Result<()> {
+        fs::create_dir_all(&dir)?;
+        let prefix = format!("{}-", self.name);
+        let mut existing: Vec<(u64, PathBuf)> = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let seq = path.file_name()
+                .and_then(|name| name.to_str())
+                .filter(|name| name.starts_with(&prefix) && name.ends_with(".spill"))
+                .and_then(|name| name[prefix.len()..name.len() - ".spill".len()].parse::<u64>().ok());
+            if let Some(seq) = seq {
+                existing.push((seq, path));
+            }
+        }
+        existing.sort();
+        self.next_seq = existing.last().map(|&(seq, _)| seq + 1).unwrap_or(0);
+        self.spilled = existing.into_iter().map(|(_, path)| path).collect();
+        self.dir = Some(dir);
+        self.max_spilled = max_spilled;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.spilled.len() + self.memory.len()
+    }
+
+    pub fn spilled(&self) -> usize {
+        self.spilled.len()
+    }
+
+    /// Number of payloads discarded because the spool was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    pub fn push(&mut self, payload: Vec<u8>) {
+        self.memory.push_back(payload);
+    }
+
+    /// The oldest payload.
+    pub fn front(&mut self) -> Option<Vec<u8>> {
+        while let Some(path) = self.spilled.front().cloned() {
+            match fs::read(&path) {
+                Ok(payload) => return Some(payload),
+                Err(e) => {
+                    eprintln!("Cannot read spilled payload {:?}, skipping it: {:?}", path, e);
+                    self.spilled.pop_front();
+                }
+            }
+        }
+        self.memory.front().cloned()
+    }
+
+    /// Replace the oldest payload by the part of it left to send.
+    pub fn replace_front(&mut self, payload: Vec<u8>) {
+        match self.spilled.front() {
+            Some(path) => {
+                if let Err(e) = fs::write(path, &payload) {
+                    eprintln!("Cannot rewrite spilled payload {:?}: {:?}", path, e);
+                }
+            }
+            None => {
+                if let Some(front) = self.memory.front_mut() {
+                    *front = payload;
+                }
+            }
+        }
+    }
+
+    /// Remove the oldest payload after it has been sent.
+    pub fn pop_front(&mut self) {
+        match self.spilled.pop_front() {
+            Some(path) => {
+                if let Err(e) = fs::remove_file(&path) {
+                    eprintln!("Cannot remove spilled payload {:?}: {:?}", path, e);
+                }
+            }
+            None => {
+                self.memory.pop_front();
+            }
+        }
+    }
+
+    /// Move payloads over the memory limit to disk, dropping the oldest
+    /// ones once the disk limit is reached too.
+    pub fn trim(&mut self) {
+        while self.memory.len() > self.max_memory {
+            let payload = self.memory.pop_front().unwrap();
+            if !self.spill(&payload) {
+                self.dropped += 1;
+            }
+        }
+        while self.spilled.len() > self.max_spilled {
+            let path = self.spilled.pop_front().unwrap();
+            let _ = fs::remove_file(&path);
+            self.dropped += 1;
+        }
+    }
+
+    fn spill(&mut self, payload: &[u8]) -> bool {
+        let dir = match self.dir {
+            Some(ref dir) => dir,
+            None => return false,
+        };
+        let path = dir.join(format!("{}-{:020}.spill", self.name, self.next_seq));
+        match fs::write(&path, payload) {
+            Ok(_) => {
+                self.next_seq += 1;
+                self.spilled.push_back(path);
+                true
+            }
+            Err(e) => {
+                eprintln!("Cannot spill payload to {:?}: {:?}", path, e);
+                false
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("statsd-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn drain(spool: &mut Spool) -> Vec<Vec<u8>> {
+        let mut result = Vec::new();
+        while let Some(payload) = spool.front() {
+            result.push(payload);
+            spool.pop_front();
+        }
+        result
+    }
+
+    #[test]
+    fn test_spool_memory_only_drops_oldest() {
+        let mut spool = Spool::new("test", 2);
+        for i in 0..4u8 {
+            spool.push(vec![i]);
+            spool.trim();
+        }
+        assert_eq!(2, spool.dropped());
+        assert_eq!(vec![vec![2], vec![3]], drain(&mut spool));
+    }
+
+    #[test]
+    fn test_spool_spills_and_replays_in_order() {
+        let dir = temp_dir("order");
+        let mut spool = Spool::new("test", 1);
+        spool.spill_to(dir.clone(), 2).unwrap();
+        for i in 0..4u8 {
+            spool.push(vec![i]);
+            spool.trim();
+        }
+        assert_eq!(1, spool.dropped());
+        assert_eq!(2, spool.spilled());
+
+        // A new spool picks up the spilled payloads.
+        let mut restarted = Spool::new("test", 1);
+        restarted.spill_to(dir.clone(), 2).unwrap();
+        assert_eq!(vec![vec![1], vec![2]], drain(&mut restarted));
+
+        spool.spilled.clear();
+        assert_eq!(vec![vec![3]], drain(&mut spool));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_spool_replaces_front() {
+        let dir = temp_dir("replace");
+        let mut spool = Spool::new("test", 1);
+        spool.spill_to(dir.clone(), 2).unwrap();
+        for payload in [b"ab".to_vec(), b"cd".to_vec()].iter() {
+            spool.push(payload.clone());
+            spool.trim();
+        }
+        assert_eq!(1, spool.spilled());
+        spool.replace_front(b"b".to_vec());
+        assert_eq!(Some(b"b".to_vec()), spool.front());
+        spool.pop_front();
+        spool.replace_front(b"d".to_vec());
+        assert_eq!(vec![b"d".to_vec()], drain(&mut spool));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_persistent_stream_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let mut stream = PersistentStream::new(addr);
+        assert!(stream.write_all(b"a").is_err());
+        let err = stream.write_all(b"a").unwrap_err();
+        assert_eq!(io::ErrorKind::NotConnected, err.kind());
+        assert_eq!(Duration::from_secs(2), stream.backoff);
+    }
+}
@@ -0,0 +1,305 @@
+//! Forward every received packet, unaggregated, to other statsd servers.
+//!
+//! Unlike the backends the repeater works at ingestion time: each UDP packet
+//! is handed to one sender thread per destination before it is parsed, and
+//! ZeroMQ batches are repeated as statsd lines. The threads are fed through
+//! bounded channels so a slow destination drops packets instead of stalling
+//! ingestion.
+
+use super::reliable::PersistentStream;
+use crate::pattern::Glob;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+
+/// Packets waiting to be sent to one destination.
+const QUEUE_SIZE: usize = 10000;
+
+/// Largest packet built when repeating the metrics of a ZeroMQ batch.
+const MAX_PACKET_SIZE: usize = 1400;
+
+/// Where packets are repeated to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+impl Destination {
+    /// Parse `udp://host:port`, `tcp://host:port` or `host:port` for UDP.
+    pub fn parse(spec: &str) -> Result<Destination, String> {
+        let (tcp, address) = if let Some(address) = spec.strip_prefix("tcp://") {
+            (true, address)
+        } else if let Some(address) = spec.strip_prefix("udp://") {
+            (false, address)
+        } else {
+            (false, spec)
+        };
+        let addr = address.to_socket_addrs()
+            .map_err(|e| format!("Cannot resolve {}: {}", address, e))?
+            .next()
+            .ok_or_else(|| format!("Cannot resolve {}", address))?;
+        Ok(if tcp { Destination::Tcp(addr) } else { Destination::Udp(addr) })
+    }
+
+    /// Parse comma separated destinations.
+    pub fn parse_list(spec: &str) -> Result<Vec<Destination>, String> {
+        spec.split(',')
+            .map(|dest| dest.trim())
+            .filter(|dest| !dest.is_empty())
+            .map(Destination::parse)
+            .collect()
+    }
+}
+
+
+/// Selects the lines of a packet to repeat by metric name.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+}
+
+impl Filter {
+    /// Build a filter from comma separated globs. An empty include list
+    /// repeats every metric not excluded.
+    pub fn new(include: &str, exclude: &str) -> Filter {
+        let globs = |spec: &str| -> Vec<Glob> {
+            spec.split(',')
+                .map(|pattern| pattern.trim())
+                .filter(|pattern| !pattern.is_empty())
+                .map(Glob::new)
+                .collect()
+        };
+        Filter {
+            include: globs(include),
+            exclude: globs(exclude),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    fn accepts(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|glob| glob.matches(name))) &&
+            !self.exclude.iter().any(|glob| glob.matches(name))
+    }
+
+    /// The lines of the packet to repeat, or `None` when nothing is left.
+    ///
+    /// Without patterns the packet is repeated as it was received.
+    pub fn apply(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        if self.is_empty() {
+            return Some(packet.to_vec());
+        }
+        let mut result = Vec::with_capacity(packet.len());
+        for line in packet.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            let name = line.split(|b| *b == b':').next().unwrap_or(line);
+            let accepted = std::str::from_utf8(name)
+                .map(|name| self.accepts(name))
+                .unwrap_or(false);
+            if accepted {
+                result.extend_from_slice(line);
+                result.push(b'\n');
+            }
+        }
+        if result.is_empty() { None } else { Some(result) }
+    }
+}
+
+
+pub struct Repeater {
+    filter: Filter,
+    senders: Vec<(Destination, SyncSender<Vec<u8>>)>,
+    dropped: usize,
+}
+
+impl Repeater {
+    /// Start one sender thread per destination.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let destinations = Destination::parse_list("10.0.0.2:8125,tcp://10.0.0.3:8125").unwrap();
+    /// let repeater = Repeater::new(destinations, Filter::new("api.**", ""));
+    /// ```
+    pub fn new(destinations: Vec<Destination>, filter: Filter) -> Repeater {
+        let senders = destinations.into_iter()
+            .map(|destination| {
+                println!("Repeating packets to {:?}", destination);
+                let (send, recv) = sync_channel(QUEUE_SIZE);
+                let thread_destination = destination.clone();
+                thread::spawn(move || send_packets(thread_destination, recv));
+                (destination, send)
+            })
+            .collect();
+        Repeater {
+            filter: filter,
+            senders: senders,
+            dropped: 0,
+        }
+    }
+
+    /// Queue a received packet for every destination.
+    ///
+    /// A destination whose sender thread has stopped is no longer repeated
+    /// to.
+    pub fn repeat(&mut self, packet: &[u8]) {
+        let packet = match self.filter.apply(packet) {
+            Some(packet) => packet,
+            None => return,
+        };
+        let dropped = &mut self.dropped;
+        self.senders.retain(|(destination, sender)| {
+            match sender.try_send(packet.clone()) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    *dropped += 1;
+                    if *dropped % 1000 == 1 {
+                        eprintln!("Repeater queue to {:?} is full, {} packets dropped so far",
+                                  destination, dropped);
+                    }
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    eprintln!("Repeater thread for {:?} has stopped, no longer repeating to it",
+                              destination);
+                    false
+                }
+            }
+        });
+    }
+
+    /// Queue statsd lines received without a packet, like the metrics of
+    /// a ZeroMQ batch, packed into packets of at most `MAX_PACKET_SIZE`.
+    pub fn repeat_lines(&mut self, lines: &[String]) {
+        let mut packet = String::new();
+        for line in lines.iter() {
+            if !packet.is_empty() && packet.len() + line.len() + 1 > MAX_PACKET_SIZE {
+                self.repeat(packet.as_bytes());
+                packet.clear();
+            }
+            packet.push_str(line);
+            packet.push('\n');
+        }
+        if !packet.is_empty() {
+            self.repeat(packet.as_bytes());
+        }
+    }
+}
+
+fn send_packets(destination: Destination, packets: Receiver<Vec<u8>>) {
+    match destination {
+        Destination::Udp(addr) => {
+            let socket = UdpSocket::bind("0.0.0.0:0").expect("Cannot open repeater socket");
+            for packet in packets.iter() {
+                if let Err(e) = socket.send_to(&packet, addr) {
+                    eprintln!("Failed to repeat packet to {}: {:?}", addr, e);
+                }
+            }
+        }
+        Destination::Tcp(addr) => {
+            let mut stream = PersistentStream::new(addr);
+            for mut packet in packets.iter() {
+                // Lines must stay separated across packets on a stream.
+                if packet.last() != Some(&b'\n') {
+                    packet.push(b'\n');
+                }
+                if let Err(e) = stream.write_all(&packet) {
+                    eprintln!("Failed to repeat packet to tcp://{}: {:?}", addr, e);
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metric::{Metric, MetricKind};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_destination() {
+        assert_eq!(Destination::Udp("127.0.0.1:8125".parse().unwrap()),
+                   Destination::parse("127.0.0.1:8125").unwrap());
+        assert_eq!(Destination::Udp("127.0.0.1:8125".parse().unwrap()),
+                   Destination::parse("udp://127.0.0.1:8125").unwrap());
+        assert_eq!(Destination::Tcp("127.0.0.1:8126".parse().unwrap()),
+                   Destination::parse("tcp://127.0.0.1:8126").unwrap());
+        assert!(Destination::parse("tcp://127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let packet = b"api.requests:1|c\napi.debug.x:2|c\ndb.queries:3|ms";
+        assert_eq!(Some(packet.to_vec()), Filter::default().apply(packet));
+
+        let filter = Filter::new("api.**", "api.debug.*");
+        assert_eq!(Some(b"api.requests:1|c\n".to_vec()), filter.apply(packet));
+        assert_eq!(None, filter.apply(b"db.queries:3|ms"));
+    }
+
+    #[test]
+    fn test_drops_stopped_destinations() {
+        let (send, recv) = sync_channel(1);
+        drop(recv);
+        let mut repeater = Repeater {
+            filter: Filter::default(),
+            senders: vec![(Destination::Udp("127.0.0.1:8125".parse().unwrap()), send)],
+            dropped: 0,
+        };
+        repeater.repeat(b"a.b:1|c");
+        assert!(repeater.senders.is_empty());
+    }
+
+    #[test]
+    fn test_repeats_lines_in_packets() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut repeater = Repeater::new(vec![Destination::Udp(udp.local_addr().unwrap())],
+                                         Filter::default());
+        let lines: Vec<String> = (0..200)
+            .map(|i| Metric::new(format!("api.server{};host=web1", i), 1.0, MetricKind::Timer).to_line())
+            .collect();
+        repeater.repeat_lines(&lines);
+
+        let mut buf = [0; 2000];
+        let mut lines = Vec::new();
+        while lines.len() < 200 {
+            let (len, _) = udp.recv_from(&mut buf).unwrap();
+            assert!(len <= MAX_PACKET_SIZE);
+            let packet = std::str::from_utf8(&buf[..len]).unwrap();
+            lines.extend(packet.lines().map(|line| line.to_string()));
+        }
+        assert_eq!("api.server0:1|ms|#host:web1", lines[0]);
+        assert_eq!("api.server199:1|ms|#host:web1", lines[199]);
+    }
+
+    #[test]
+    fn test_repeats_over_udp_and_tcp() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destinations = vec![
+            Destination::Udp(udp.local_addr().unwrap()),
+            Destination::Tcp(tcp.local_addr().unwrap()),
+        ];
+        let mut repeater = Repeater::new(destinations, Filter::new("", "skip.*"));
+        repeater.repeat(b"skip.me:1|c");
+        repeater.repeat(b"a.b:1|c");
+
+        let mut buf = [0; 100];
+        let (len, _) = udp.recv_from(&mut buf).unwrap();
+        assert_eq!(b"a.b:1|c\n", &buf[..len]);
+
+        let (stream, _) = tcp.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!("a.b:1|c\n", line);
+    }
+}
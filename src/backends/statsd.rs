@@ -1,10 +1,22 @@
 use super::super::backend::Backend;
 use super::super::buckets::Buckets;
-use std::net::UdpSocket;
+use super::reliable::{PersistentStream, Spool};
+use crate::hash_ring::HashRing;
+use crate::metric::statsd_line;
+use std::net::{ToSocketAddrs, UdpSocket};
+
+/// How packets reach another statsd server.
+#[derive(Debug)]
+enum Transport {
+    Udp(UdpSocket),
+    /// Newline delimited lines over a connection kept open, queueing the
+    /// writes that could not be sent until the server is reachable again.
+    Tcp { stream: PersistentStream, queue: Box<Spool> },
+}
 
 #[derive(Debug)]
 struct StatsdConnection {
-    socket: UdpSocket,
+    transport: Transport,
     remote_statsd: String,
 }
 
@@ -12,6 +24,9 @@ struct StatsdConnection {
 pub struct Statsd {
     packet_limit: usize,
     connections: Vec<StatsdConnection>,
+    // Set when each series goes to a single host
+    ring: Option<HashRing>,
+    multi_value: bool,
 }
 
 fn open_new_udp_port() -> UdpSocket {
@@ -42,81 +57,413 @@ impl Statsd {
     ///
     /// # Examples
     ///
-    /// ```
-    /// let cons = Statsd::new("127.0.0.1", 8125, 1024);
+    /// ```ignore
+    /// let statsd = Statsd::new(vec!["127.0.0.1:8125".to_string()], 1024);
     /// ```
     pub fn new(statsd_hosts: Vec<String>, packet_limit: usize) -> Statsd {
         let connections = statsd_hosts.iter().map(|host| {
             println!("Opening socket to another statsd server {}", host);
             StatsdConnection {
-                socket: open_new_udp_connection(host.clone()),
+                transport: Transport::Udp(open_new_udp_connection(host.clone())),
                 remote_statsd: host.clone(),
             }
         }).collect();
+        Statsd::with_connections(connections, packet_limit)
+    }
+
+    /// Create a Statsd relaying over TCP instead of UDP.
+    ///
+    /// Every packet is one write of at most `packet_limit` bytes. Writes
+    /// block while the other server doesn't keep up, and up to
+    /// `queue_size` writes per host are kept while it can't be reached.
+    /// The queue counts packets, not flushes, so a flush larger than
+    /// `packet_limit` takes several places in it.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let statsd = Statsd::new_tcp(vec!["127.0.0.1:8125".to_string()], 16384, 1000).unwrap();
+    /// ```
+    pub fn new_tcp(statsd_hosts: Vec<String>, packet_limit: usize, queue_size: usize) -> Result<Statsd, String> {
+        let mut connections = Vec::with_capacity(statsd_hosts.len());
+        for host in statsd_hosts.iter() {
+            let addr = host.to_socket_addrs()
+                .map_err(|e| format!("Could not resolve statsd host {}: {}", host, e))?
+                .next()
+                .ok_or_else(|| format!("Could not resolve statsd host {}", host))?;
+            connections.push(StatsdConnection {
+                transport: Transport::Tcp {
+                    stream: PersistentStream::new(addr),
+                    queue: Box::new(Spool::new(&format!("statsd-{}", host), queue_size)),
+                },
+                remote_statsd: host.clone(),
+            });
+        }
+        Ok(Statsd::with_connections(connections, packet_limit))
+    }
+
+    fn with_connections(connections: Vec<StatsdConnection>, packet_limit: usize) -> Statsd {
         Statsd {
             connections: connections,
             packet_limit: packet_limit,
+            ring: None,
+            multi_value: false,
         }
     }
 
-    pub fn format_stats(&self, buckets: &Buckets) -> Vec<String> {
-        let mut stats = vec![String::new()];
+    /// Send all samples of a timer as `key:v1:v2:...|ms` lines instead of
+    /// one line per sample.
+    pub fn use_multi_value(&mut self) {
+        self.multi_value = true;
+    }
+
+    /// Send each series to one host picked by consistent hashing of its
+    /// key, instead of sending everything to every host.
+    pub fn use_sharding(&mut self) {
+        let hosts: Vec<String> = self.connections.iter()
+            .map(|connection| connection.remote_statsd.clone())
+            .collect();
+        self.ring = Some(HashRing::new(&hosts));
+    }
 
-        {
-            let mut push_str = |new_str: String| {
-                let last_idx = stats.len() - 1;
-                if stats[last_idx].len() + new_str.len() <= self.packet_limit {
-                    stats[last_idx].push_str(&new_str);
-                } else {
-                    stats.push(new_str);
+    /// The `(key, line)` pairs sent for the buckets.
+    fn format_lines(&self, buckets: &Buckets) -> Vec<(String, String)> {
+        let mut lines = Vec::with_capacity(2 + buckets.counters().len() + buckets.gauges().len());
+        lines.push(("statsd.bad_messages".to_string(),
+                    format!("{}:{}|c\n", "statsd.bad_messages", buckets.bad_messages())));
+        lines.push(("statsd.total_messages".to_string(),
+                    format!("{}:{}|c\n", "statsd.total_messages", buckets.total_messages())));
+
+        for (key, value) in buckets.counters().iter() {
+            lines.push((key.clone(), format!("{}\n", statsd_line(key, &value.to_string(), "c"))));
+        }
+
+        for (key, value) in buckets.gauges().iter() {
+            lines.push((key.clone(), format!("{}\n", statsd_line(key, &value.to_string(), "g"))));
+        }
+
+        for (key, values) in buckets.timers().iter() {
+            if self.multi_value {
+                for line in multi_value_lines(key, values, self.packet_limit) {
+                    lines.push((key.clone(), line));
+                }
+            } else {
+                for value in values {
+                    lines.push((key.clone(), format!("{}\n", statsd_line(key, &value.to_string(), "ms"))));
                 }
-            };
-
-            push_str(
-                format!("{}:{}|c\n",
-                    "statsd.bad_messages",
-                    buckets.bad_messages())
-            );
-
-            push_str(
-                format!("{}:{}|c\n",
-                    "statsd.total_messages",
-                    buckets.total_messages())
-            );
-
-            for (key, value) in buckets.counters().iter() {
-                push_str(format!("{}:{}|c\n", key, value));
             }
+        }
+        lines
+    }
 
-            for (key, value) in buckets.gauges().iter() {
-                push_str(format!("{}:{}|g\n", key, value));
+    /// Pack lines into packets of at most `packet_limit` bytes.
+    fn pack<I: Iterator<Item = String>>(&self, lines: I) -> Vec<String> {
+        let mut stats = vec![String::new()];
+        for line in lines {
+            let last_idx = stats.len() - 1;
+            if stats[last_idx].len() + line.len() <= self.packet_limit {
+                stats[last_idx].push_str(&line);
+            } else {
+                stats.push(line);
             }
+        }
+        stats
+    }
 
-            for (key, values) in buckets.timers().iter() {
-                for value in values {
-                    push_str(format!("{}:{}|ms\n", key, value));
+    /// The packets for each connection when sharding.
+    pub fn format_shards(&self, buckets: &Buckets) -> Vec<Vec<String>> {
+        let mut shards = vec![Vec::new(); self.connections.len()];
+        if let Some(ref ring) = self.ring {
+            for (key, line) in self.format_lines(buckets).into_iter() {
+                if let Some(idx) = ring.node_for(&key) {
+                    shards[idx].push(line);
                 }
             }
         }
+        shards.into_iter()
+            .map(|lines| self.pack(lines.into_iter()))
+            .collect()
+    }
 
-        stats
+    pub fn format_stats(&self, buckets: &Buckets) -> Vec<String> {
+        self.pack(self.format_lines(buckets).into_iter().map(|(_, line)| line))
     }
 }
 
-impl Backend for Statsd {
-    fn flush_buckets(&mut self, buckets: &Buckets) {
-        for packet in self.format_stats(buckets) {
-            for i in 0..self.connections.len() {
-                let connection = &mut self.connections[i];
-                let result = connection.socket.send(packet.as_bytes());
-                match result {
-                    Err(e) => {
-                        eprintln!("Failed to send udp packet, reopening connection: {:?}", e);
-                        connection.socket = open_new_udp_connection(connection.remote_statsd.clone());
+/// Join timer samples into `key:v1:v2|ms` lines of at most `limit` bytes,
+/// always putting at least one sample on a line.
+fn multi_value_lines(key: &str, values: &[f64], limit: usize) -> Vec<String> {
+    let overhead = statsd_line(key, "", "ms").len() + 1;
+    let mut lines = Vec::new();
+    let mut joined = String::new();
+    for value in values.iter() {
+        let value = value.to_string();
+        if !joined.is_empty() && overhead + joined.len() + 1 + value.len() > limit {
+            lines.push(format!("{}\n", statsd_line(key, &joined, "ms")));
+            joined.clear();
+        }
+        if !joined.is_empty() {
+            joined.push(':');
+        }
+        joined.push_str(&value);
+    }
+    if !joined.is_empty() {
+        lines.push(format!("{}\n", statsd_line(key, &joined, "ms")));
+    }
+    lines
+}
+
+/// The length of the complete lines among the first `written` bytes of a
+/// write. A line written in part is sent again whole on the next
+/// connection, rather than continued with a fragment.
+fn sent_lines(chunk: &[u8], written: usize) -> usize {
+    chunk[..written].iter().rposition(|&byte| byte == b'\n').map_or(0, |pos| pos + 1)
+}
+
+impl StatsdConnection {
+    /// Send the packets of a flush.
+    fn send(&mut self, packets: &[String]) -> Result<(), String> {
+        match self.transport {
+            Transport::Udp(ref mut socket) => {
+                let mut result = Ok(());
+                for packet in packets.iter().filter(|packet| !packet.is_empty()) {
+                    if let Err(e) = socket.send(packet.as_bytes()) {
+                        // Reopen the socket when it fails.
+                        *socket = open_new_udp_connection(self.remote_statsd.clone());
+                        result = result.and(Err(format!("Failed to send udp packet to {}, reopened connection: {:?}",
+                                                        self.remote_statsd, e)));
                     }
-                    Ok(_) => {}
                 }
+                result
+            }
+            Transport::Tcp { ref mut stream, ref mut queue } => {
+                for packet in packets.iter().filter(|packet| !packet.is_empty()) {
+                    queue.push(packet.as_bytes().to_vec());
+                }
+
+                // Send queued writes oldest first, stopping at the first
+                // failure so lines arrive in order. Of a write that failed
+                // part way only the lines not sent in full are kept.
+                let dropped = queue.dropped();
+                let mut result = Ok(());
+                while let Some(chunk) = queue.front() {
+                    let mut written = 0;
+                    if let Err(e) = stream.write_from(&chunk, &mut written) {
+                        let sent = sent_lines(&chunk, written);
+                        if sent > 0 {
+                            queue.replace_front(chunk[sent..].to_vec());
+                        }
+                        result = Err(format!("Failed to write to statsd tcp://{}, {} writes queued: {:?}",
+                                             self.remote_statsd, queue.len(), e));
+                        break;
+                    }
+                    queue.pop_front();
+                }
+                queue.trim();
+                if queue.dropped() > dropped {
+                    eprintln!("Dropped {} writes for statsd tcp://{}, the queue is full",
+                              queue.dropped() - dropped, self.remote_statsd);
+                }
+                result
             }
         }
     }
 }
+
+impl Backend for Statsd {
+    fn name(&self) -> &str {
+        "statsd"
+    }
+
+    /// Sends every packet, reporting the first failure.
+    fn flush_buckets(&mut self, buckets: &Buckets) -> Result<(), String> {
+        let mut result = Ok(());
+        if self.ring.is_some() {
+            let shards = self.format_shards(buckets);
+            for (connection, packets) in self.connections.iter_mut().zip(shards.iter()) {
+                result = result.and(connection.send(packets));
+            }
+            return result;
+        }
+        let packets = self.format_stats(buckets);
+        for connection in self.connections.iter_mut() {
+            result = result.and(connection.send(&packets));
+        }
+        result
+    }
+
+    /// Give the TCP hosts one last chance to receive the queued writes.
+    fn shutdown(&mut self) -> Result<(), String> {
+        let mut result = Ok(());
+        for connection in self.connections.iter_mut() {
+            if let Transport::Tcp { .. } = connection.transport {
+                result = result.and(connection.send(&[]));
+            }
+        }
+        result
+    }
+
+    fn status(&self) -> Vec<(String, String)> {
+        self.connections.iter()
+            .filter_map(|connection| match connection.transport {
+                Transport::Udp(_) => None,
+                Transport::Tcp { ref stream, ref queue } => {
+                    Some((format!("tcp://{}", connection.remote_statsd),
+                          format!("connected={} queued={} dropped={}",
+                                  stream.is_connected(), queue.len(), queue.dropped())))
+                }
+            })
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::super::metric::{Metric, MetricKind};
+    use super::super::super::rollup::Rollups;
+    use super::*;
+
+    #[test]
+    fn test_sent_lines() {
+        assert_eq!(0, sent_lines(b"a:1|c\nb:2|c\n", 0));
+        assert_eq!(0, sent_lines(b"a:1|c\nb:2|c\n", 3));
+        assert_eq!(6, sent_lines(b"a:1|c\nb:2|c\n", 6));
+        assert_eq!(6, sent_lines(b"a:1|c\nb:2|c\n", 9));
+    }
+
+    #[test]
+    fn test_multi_value_lines() {
+        let lines = multi_value_lines("a.b", &[1.0, 2.5, 3.0, 4.0], 16);
+        assert_eq!(vec!["a.b:1:2.5:3|ms\n".to_string(), "a.b:4|ms\n".to_string()], lines);
+        assert_eq!(vec!["a.b:1|ms\n".to_string(), "a.b:2|ms\n".to_string()],
+                   multi_value_lines("a.b", &[1.0, 2.0], 1));
+    }
+
+    #[test]
+    fn test_relays_tags_as_dogstatsd() {
+        let mut statsd = Statsd::new(vec!["127.0.0.1:18125".to_string()], 1024);
+        let mut buckets = Buckets::new(10., false);
+        for metric in Metric::parse("api.requests:1|c|#host:web1\napi.latency:5|ms|#dc:eu,host:web1").unwrap().iter() {
+            buckets.add(metric);
+        }
+        let packets = statsd.format_stats(&buckets).join("");
+        assert!(packets.contains("api.requests:1|c|#host:web1\n"));
+        assert!(packets.contains("api.latency:5|ms|#dc:eu,host:web1\n"));
+
+        statsd.use_multi_value();
+        assert!(statsd.format_stats(&buckets).join("").contains("api.latency:5|ms|#dc:eu,host:web1\n"));
+        assert_eq!(vec!["a:1:2|ms|#h:x\n".to_string(), "a:3|ms|#h:x\n".to_string()],
+                   multi_value_lines("a;h=x", &[1.0, 2.0, 3.0], 14));
+    }
+
+    #[test]
+    fn test_multi_value_round_trip() {
+        let mut statsd = Statsd::new(vec!["127.0.0.1:18125".to_string()], 64);
+        statsd.use_multi_value();
+        let mut buckets = Buckets::new(10., false);
+        for i in 0..100 {
+            buckets.add(&Metric::new("api.latency", i as f64, MetricKind::Timer));
+        }
+
+        let packets = statsd.format_stats(&buckets);
+        assert!(packets.len() < 100);
+        let mut relayed = Buckets::new(10., false);
+        for packet in packets.iter().filter(|packet| !packet.is_empty()) {
+            for metric in Metric::parse(packet).unwrap().iter() {
+                relayed.add(metric);
+            }
+        }
+        assert_eq!(buckets.timers()["api.latency"], relayed.timers()["api.latency"]);
+    }
+
+    #[test]
+    fn test_does_not_relay_rollups() {
+        let statsd = Statsd::new(vec!["127.0.0.1:18125".to_string()], 1024);
+        let mut buckets = Buckets::new(10., false);
+        buckets.set_rollups(Rollups::parse("sum:api.*.latency=api.all.latency").unwrap());
+        buckets.add(&Metric::new("api.web1.latency", 1.0, MetricKind::Timer));
+        buckets.add(&Metric::new("api.web2.latency", 2.0, MetricKind::Timer));
+        buckets.process();
+
+        // The roll-up is left to the downstream statsd.
+        let packets = statsd.format_stats(&buckets).join("");
+        assert_eq!(2, packets.lines().filter(|line| line.ends_with("|ms")).count());
+        assert!(!packets.contains("api.all.latency"));
+    }
+
+    #[test]
+    fn test_format_shards() {
+        let hosts = vec!["127.0.0.1:18125".to_string(), "127.0.0.1:18126".to_string()];
+        let mut statsd = Statsd::new(hosts, 1024);
+        statsd.use_sharding();
+
+        let mut buckets = Buckets::new(10., false);
+        for i in 0..50 {
+            buckets.add(&Metric::new(format!("api.server{}.latency", i), 1.0, MetricKind::Timer));
+            buckets.add(&Metric::new(format!("api.server{}.latency", i), 2.0, MetricKind::Timer));
+        }
+        let shards = statsd.format_shards(&buckets);
+        assert_eq!(2, shards.len());
+
+        // Every line is sent once, and all samples of a key go to one host.
+        let lines: Vec<Vec<&str>> = shards.iter()
+            .map(|packets| packets.iter().flat_map(|packet| packet.lines()).collect())
+            .collect();
+        assert_eq!(102, lines[0].len() + lines[1].len());
+        assert!(!lines[0].is_empty() && !lines[1].is_empty());
+        for i in 0..50 {
+            let key = format!("api.server{}.latency:", i);
+            let on_first = lines[0].iter().filter(|line| line.starts_with(&key)).count();
+            let on_second = lines[1].iter().filter(|line| line.starts_with(&key)).count();
+            assert!((on_first, on_second) == (2, 0) || (on_first, on_second) == (0, 2));
+        }
+    }
+    #[test]
+    fn test_relays_over_tcp() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let mut statsd = Statsd::new_tcp(vec![host.clone()], 64, 10).unwrap();
+        let mut buckets = Buckets::new(10., false);
+        for i in 0..20 {
+            buckets.add(&Metric::new(format!("api.server{}.requests", i), 1.0, MetricKind::Counter(1.0)));
+        }
+        statsd.flush_buckets(&buckets).unwrap();
+        assert_eq!(vec![(format!("tcp://{}", host), "connected=true queued=0 dropped=0".to_string())],
+                   statsd.status());
+        drop(statsd);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        let mut relayed = Buckets::new(10., false);
+        for line in received.lines() {
+            for metric in Metric::parse(line).unwrap().iter() {
+                relayed.add(metric);
+            }
+        }
+        for (key, value) in buckets.counters().iter() {
+            assert_eq!(Some(value), relayed.counters().get(key));
+        }
+        assert_eq!(Some(&20.0), relayed.counters().get("statsd.total_messages"));
+    }
+
+    #[test]
+    fn test_queues_tcp_writes_when_unreachable() {
+        use std::net::TcpListener;
+
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut statsd = Statsd::new_tcp(vec![format!("127.0.0.1:{}", port)], 32, 3).unwrap();
+        let mut buckets = Buckets::new(10., false);
+        buckets.add(&Metric::new("api.requests", 1.0, MetricKind::Counter(1.0)));
+        assert!(statsd.flush_buckets(&buckets).is_err());
+        assert!(statsd.flush_buckets(&buckets).is_err());
+        let status = statsd.status();
+        assert_eq!("connected=false queued=3 dropped=3", status[0].1);
+        assert!(statsd.shutdown().is_err());
+        assert!(Statsd::new_tcp(vec!["statsd.invalid".to_string()], 32, 3).is_err());
+    }
+}
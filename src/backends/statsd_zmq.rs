@@ -245,7 +245,11 @@ impl StatsdZmq {
 }
 
 impl Backend for StatsdZmq {
-    fn flush_buckets(&mut self, buckets: &Buckets) {
+    fn name(&self) -> &str {
+        "statsd_zmq"
+    }
+
+    fn flush_buckets(&mut self, buckets: &Buckets) -> Result<(), String> {
         let (stats, uncompressed_size) = buckets_to_packed_message(buckets);
         let compressed_size = stats.len();
         println!("Sending compressed stats batch from {} to {} bytes ({:.2} ratio)",
@@ -262,6 +266,7 @@ impl Backend for StatsdZmq {
             let i = &self.connections[i];
             i.socket.send(stats.as_slice(), zmq::DONTWAIT).unwrap_or_default();
         }
+        Ok(())
     }
 }
 
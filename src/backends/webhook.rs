@@ -0,0 +1,348 @@
+use super::super::backend::Backend;
+use super::super::buckets::Buckets;
+use super::json_lines;
+use crate::http;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{Map, Value};
+use std::cmp;
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+use time;
+
+
+/// Room left in a batch for the fields around the series.
+const ENVELOPE_BYTES: usize = 128;
+
+/// The longest wait between two retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct Webhook {
+    urls: Vec<http::Url>,
+    headers: Vec<(String, String)>,
+    gzip: bool,
+    // Maximum bytes of JSON per request, 0 for no limit
+    batch_size: usize,
+    retries: usize,
+    backoff: Duration,
+    timeout: Duration,
+    retried: u64,
+}
+
+/// Parse `Name: value` headers, one per item. Values may contain commas.
+pub fn parse_headers(headers: &[String]) -> Result<Vec<(String, String)>, String> {
+    headers.iter()
+        .map(|header| header.trim())
+        .filter(|header| !header.is_empty())
+        .map(|header| {
+            let mut parts = header.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if !name.trim().is_empty() && !name.contains(char::is_whitespace) => {
+                    Ok((name.trim().to_string(), value.trim().to_string()))
+                }
+                _ => Err(format!("Webhook header `{}` must be Name: value", header)),
+            }
+        })
+        .collect()
+}
+
+impl Webhook {
+    /// Create a backend posting every flush as JSON to each of `urls`.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let webhook = Webhook::new(&["http://127.0.0.1:8080/statsd".to_string()]).unwrap();
+    /// ```
+    pub fn new(urls: &[String]) -> Result<Webhook, String> {
+        if urls.is_empty() {
+            return Err("No webhook URLs given".to_string());
+        }
+        Ok(Webhook {
+            urls: urls.iter().map(|url| http::Url::parse(url)).collect::<Result<_, _>>()?,
+            headers: Vec::new(),
+            gzip: false,
+            batch_size: 0,
+            retries: 0,
+            backoff: Duration::from_millis(500),
+            timeout: Duration::from_secs(5),
+            retried: 0,
+        })
+    }
+
+    /// Send these headers with every request.
+    pub fn set_headers(&mut self, headers: Vec<(String, String)>) {
+        self.headers = headers;
+    }
+
+    /// Compress the requests with gzip.
+    pub fn use_gzip(&mut self) {
+        self.gzip = true;
+    }
+
+    /// Split a flush into requests of at most `bytes` of JSON, 0 to send
+    /// every flush in one request. A single series is never split.
+    pub fn set_batch_size(&mut self, bytes: usize) {
+        self.batch_size = bytes;
+    }
+
+    /// Retry a failed request up to `retries` times, waiting `backoff`
+    /// before the first retry and twice as long before each next one.
+    pub fn set_retries(&mut self, retries: usize, backoff: Duration) {
+        self.retries = retries;
+        self.backoff = backoff;
+    }
+
+    /// How long to wait for a connection and a response.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Convert the buckets into the JSON documents of one flush.
+    ///
+    /// Every document has the `timestamp` and `flush_interval` of the flush,
+    /// its `batch` number counting from 1, the number of `batches` and the
+    /// `series` as written by the JSON lines backend.
+    pub fn format_stats(&self, buckets: &Buckets, timestamp: i64) -> Vec<String> {
+        let series: Vec<String> = json_lines::series(buckets)
+            .into_iter()
+            .map(|object| Value::Object(object).to_string())
+            .collect();
+
+        let mut batches: Vec<&[String]> = Vec::new();
+        let mut start = 0;
+        let mut len = ENVELOPE_BYTES;
+        for (idx, object) in series.iter().enumerate() {
+            if self.batch_size > 0 && idx > start && len + object.len() + 1 > self.batch_size {
+                batches.push(&series[start..idx]);
+                start = idx;
+                len = ENVELOPE_BYTES;
+            }
+            len += object.len() + 1;
+        }
+        batches.push(&series[start..]);
+
+        let count = batches.len();
+        batches.iter()
+            .enumerate()
+            .map(|(idx, batch)| {
+                let mut document = Map::new();
+                document.insert("timestamp".to_string(), timestamp.into());
+                document.insert("flush_interval".to_string(), buckets.flush_interval().into());
+                document.insert("batch".to_string(), (idx + 1).into());
+                document.insert("batches".to_string(), count.into());
+                let mut json = Value::Object(document).to_string();
+                // Insert the already serialized series before the closing brace.
+                json.pop();
+                json.push_str(",\"series\":[");
+                json.push_str(&batch.join(","));
+                json.push_str("]}");
+                json
+            })
+            .collect()
+    }
+
+    fn encode(&self, document: String) -> Result<Vec<u8>, String> {
+        if !self.gzip {
+            return Ok(document.into_bytes());
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(document.as_bytes())
+            .and_then(|_| encoder.finish())
+            .map_err(|e| format!("Could not compress webhook request: {:?}", e))
+    }
+
+    /// Post `body`, retrying connection errors, throttling and server
+    /// errors.
+    fn post(&mut self, url: &http::Url, headers: &[(String, String)], body: &[u8]) -> Result<(), String> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let error = match http::post(url, headers, body, self.timeout) {
+                Ok(ref response) if response.is_success() => return Ok(()),
+                Ok(response) => {
+                    let error = format!("HTTP {}: {}", response.status, response.body.trim());
+                    if response.status != 429 && response.status < 500 {
+                        return Err(error);
+                    }
+                    error
+                }
+                Err(e) => format!("{:?}", e),
+            };
+            if attempt >= self.retries {
+                return Err(error);
+            }
+            thread::sleep(backoff);
+            backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+            attempt += 1;
+            self.retried += 1;
+        }
+    }
+}
+
+
+impl Backend for Webhook {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn flush_buckets(&mut self, buckets: &Buckets) -> Result<(), String> {
+        let start = time::get_time();
+        let documents = self.format_stats(buckets, start.sec);
+        let mut headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+        if self.gzip {
+            headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+        }
+        headers.extend(self.headers.iter().cloned());
+        let bodies = documents.into_iter()
+            .map(|document| self.encode(document))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Every URL gets every batch of the flush, even when another URL or
+        // batch failed.
+        let mut errors = Vec::new();
+        for url in self.urls.clone().iter() {
+            let (mut len, mut requests) = (0, 0);
+            for (idx, body) in bodies.iter().enumerate() {
+                match self.post(url, &headers, body) {
+                    Ok(_) => {
+                        len += body.len();
+                        requests += 1;
+                    }
+                    Err(e) => {
+                        errors.push(format!("Could not post batch {} of {} to webhook http://{}{}: {}",
+                                            idx + 1, bodies.len(), url.authority(), url.path, e));
+                    }
+                }
+            }
+            if requests > 0 {
+                let taken = time::get_time() - start;
+                println!("Successfully flushed {} bytes in {} requests to webhook http://{}{} in {} milliseconds",
+                         len, requests, url.authority(), url.path, taken.num_milliseconds());
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+
+    fn status(&self) -> Vec<(String, String)> {
+        vec![("retries".to_string(), self.retried.to_string())]
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::super::metric::{Metric, MetricKind};
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn make_buckets() -> Buckets {
+        let mut buckets = Buckets::new(10., false);
+        buckets.add(&Metric::new("api.requests;host=web1", 5.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("api.queue", 3.5, MetricKind::Gauge));
+        buckets.add(&Metric::new("api.latency", 12.0, MetricKind::Timer));
+        buckets.add(&Metric::new("api.latency", 2.0, MetricKind::Timer));
+        buckets.process();
+        buckets
+    }
+
+    fn make_webhook(port: u16) -> Webhook {
+        Webhook::new(&[format!("http://127.0.0.1:{}/statsd", port)]).unwrap()
+    }
+
+    fn headers(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        assert_eq!(vec![("Authorization".to_string(), "Bearer abc".to_string()),
+                        ("Accept".to_string(), "text/plain, application/json".to_string())],
+                   parse_headers(&headers(&["Authorization: Bearer abc", "Accept:text/plain, application/json", " "])).unwrap());
+        assert!(parse_headers(&headers(&["Authorization"])).is_err());
+        assert!(parse_headers(&headers(&["Bad Name: x"])).is_err());
+    }
+
+    #[test]
+    fn test_batches_by_size() {
+        let mut webhook = make_webhook(80);
+        let documents = webhook.format_stats(&make_buckets(), 1500000000);
+        assert_eq!(1, documents.len());
+        let document: Value = serde_json::from_str(&documents[0]).unwrap();
+        assert_eq!(1500000000, document["timestamp"]);
+        assert_eq!(10.0, document["flush_interval"]);
+        assert_eq!(7, document["series"].as_array().unwrap().len());
+        assert_eq!("web1", document["series"][3]["tags"]["host"]);
+
+        webhook.set_batch_size(300);
+        let documents = webhook.format_stats(&make_buckets(), 1500000000);
+        assert!(documents.len() > 1);
+        let mut series = 0;
+        for (idx, document) in documents.iter().enumerate() {
+            let document: Value = serde_json::from_str(document).unwrap();
+            assert_eq!(idx + 1, document["batch"].as_u64().unwrap() as usize);
+            assert_eq!(documents.len(), document["batches"].as_u64().unwrap() as usize);
+            series += document["series"].as_array().unwrap().len();
+        }
+        assert_eq!(7, series);
+    }
+
+    #[test]
+    fn test_posts_gzip_with_headers() {
+        let (port, requests) = http::serve_one(200);
+        let mut webhook = make_webhook(port);
+        webhook.use_gzip();
+        webhook.set_headers(parse_headers(&headers(&["Authorization: Bearer abc"])).unwrap());
+        webhook.flush_buckets(&make_buckets()).unwrap();
+
+        let request = requests.recv().unwrap();
+        assert_eq!("POST /statsd HTTP/1.1", request.request_line);
+        assert!(request.headers.contains(&("content-encoding".to_string(), "gzip".to_string())));
+        assert!(request.headers.contains(&("authorization".to_string(), "Bearer abc".to_string())));
+        let mut json = String::new();
+        GzDecoder::new(&request.body[..]).read_to_string(&mut json).unwrap();
+        let document: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(7, document["series"].as_array().unwrap().len());
+    }
+
+    #[test]
+    fn test_retries_server_errors() {
+        let (port, requests) = http::serve(vec![503, 429, 204]);
+        let mut webhook = make_webhook(port);
+        webhook.set_retries(2, Duration::from_millis(1));
+        webhook.flush_buckets(&make_buckets()).unwrap();
+        assert_eq!(3, requests.iter().take(3).count());
+        assert_eq!(vec![("retries".to_string(), "2".to_string())], webhook.status());
+
+        let (port, _requests) = http::serve(vec![400]);
+        let mut webhook = make_webhook(port);
+        webhook.set_retries(2, Duration::from_millis(1));
+        let error = webhook.flush_buckets(&make_buckets()).err().unwrap();
+        assert!(error.contains("HTTP 400"), "{}", error);
+    }
+
+    #[test]
+    fn test_posts_batches_after_a_failed_one() {
+        let mut webhook = make_webhook(80);
+        webhook.set_batch_size(300);
+        let batches = webhook.format_stats(&make_buckets(), 1500000000).len();
+        assert!(batches > 2);
+        let mut statuses = vec![204; batches];
+        statuses[0] = 400;
+        let (port, requests) = http::serve(statuses);
+        let mut webhook = make_webhook(port);
+        webhook.set_batch_size(300);
+        let error = webhook.flush_buckets(&make_buckets()).err().unwrap();
+        assert_eq!(batches, requests.iter().take(batches).count());
+        assert!(error.starts_with(&format!("Could not post batch 1 of {} to webhook http://127.0.0.1:{}/statsd",
+                                           batches, port)), "{}", error);
+        assert!(!error.contains("batch 2 of"), "{}", error);
+    }
+}
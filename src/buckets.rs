@@ -3,9 +3,13 @@
 //! Each bucket contains a set of hashmaps containing
 //! each set of metrics received by clients.
 
+use std::cmp;
 use std::collections::HashMap;
-use super::metric::{Metric, MetricKind};
-use super::metric_processor;
+use super::gauge::{GaugeModes, GaugeStats};
+use super::heavy_hitters::HeavyHitters;
+use super::metric::{with_suffix, Metric, MetricKind};
+use super::metric_processor::{self, TIMER_STATS};
+use super::rollup::Rollups;
 use time;
 
 
@@ -15,7 +19,12 @@ pub struct Buckets {
     gauges: HashMap<String, f64>,
     timers: HashMap<String, Vec<f64>>,
 
+    gauge_stats: HashMap<String, GaugeStats>,
     timer_data: HashMap<String, f64>,
+    // Series derived by `process`, only kept until the next reset
+    derived_counters: HashMap<String, f64>,
+    derived_gauges: HashMap<String, f64>,
+    derived_timers: HashMap<String, Vec<f64>>,
 
     server_start_time: time::Timespec,
     last_message: time::Timespec,
@@ -23,6 +32,10 @@ pub struct Buckets {
     total_messages: usize,
     flush_interval_seconds: f64,
     delete_gauges_after_flush: bool,
+    processing_threads: usize,
+    gauge_modes: GaugeModes,
+    rollups: Rollups,
+    heavy_hitters: HeavyHitters,
 }
 
 impl Buckets {
@@ -32,7 +45,9 @@ impl Buckets {
     /// # Examples
     ///
     /// ```
-    /// let bucket = Buckets::new();
+    /// use statsd::buckets::Buckets;
+    ///
+    /// let bucket = Buckets::new(10., false);
     /// assert_eq!(0, bucket.counters().len());
     /// ```
     pub fn new(flush_interval_seconds: f64, delete_gauges: bool) -> Buckets {
@@ -40,13 +55,21 @@ impl Buckets {
             counters: HashMap::new(),
             gauges: HashMap::new(),
             timers: HashMap::new(),
+            gauge_stats: HashMap::new(),
             timer_data: HashMap::new(),
+            derived_counters: HashMap::new(),
+            derived_gauges: HashMap::new(),
+            derived_timers: HashMap::new(),
             bad_messages: 0,
             total_messages: 0,
             last_message: time::get_time(),
             server_start_time: time::get_time(),
             flush_interval_seconds: flush_interval_seconds,
             delete_gauges_after_flush: delete_gauges,
+            processing_threads: 1,
+            gauge_modes: GaugeModes::new(),
+            rollups: Rollups::new(),
+            heavy_hitters: HeavyHitters::new(0, 0, false),
         }
     }
 
@@ -55,15 +78,15 @@ impl Buckets {
     /// # Examples
     ///
     /// ```
-    /// use buckets::Buckets;
-    /// use super::metric;
-    /// use std::str::FromStr;
+    /// use statsd::buckets::Buckets;
+    /// use statsd::metric::{Metric, MetricKind};
     ///
-    /// let metric = metric::Metric::FromStr("foo:1|c");
-    /// let mut bucket = Buckets::new();
-    /// bucket.add(metric);
+    /// let metric = Metric::new("foo", 1.0, MetricKind::Counter(1.0));
+    /// let mut bucket = Buckets::new(10., false);
+    /// bucket.add(&metric);
     /// ```
     pub fn add(&mut self, value: &Metric) {
+        self.heavy_hitters.observe(&value.name);
         let name = value.name.to_owned();
         match value.kind {
             MetricKind::Counter(rate) => {
@@ -71,7 +94,16 @@ impl Buckets {
                 *counter = *counter + value.value * (1.0 / rate);
             }
             MetricKind::Gauge => {
-                self.gauges.insert(name, value.value);
+                if self.gauge_modes.tracks_stats() {
+                    let mode = self.gauge_modes.mode_for(&name);
+                    let stats = self.gauge_stats
+                        .entry(name.clone())
+                        .and_modify(|stats| stats.add(value.value))
+                        .or_insert_with(|| GaugeStats::new(value.value));
+                    self.gauges.insert(name, stats.value(mode));
+                } else {
+                    self.gauges.insert(name, value.value);
+                }
             }
             MetricKind::Timer => {
                 let slot = self.timers.entry(name).or_insert(Vec::new());
@@ -104,11 +136,109 @@ impl Buckets {
         &self.gauges
     }
 
+    /// Get the counters of this flush, the received ones followed by those
+    /// derived by `process`. A derived counter replaces a received one of
+    /// the same name.
+    pub fn all_counters(&self) -> impl Iterator<Item = (&String, &f64)> {
+        let derived = &self.derived_counters;
+        self.counters.iter()
+            .filter(move |&(key, _)| !derived.contains_key(key))
+            .chain(derived.iter())
+    }
+
+    /// Set a counter value directly.
+    pub fn set_counter(&mut self, name: String, value: f64) {
+        self.counters.insert(name, value);
+    }
+
+    /// Set a counter for this flush only, it is removed on reset.
+    pub fn set_derived_counter(&mut self, name: String, value: f64) {
+        self.derived_counters.insert(name, value);
+    }
+
+    /// Set a gauge value directly, bypassing the gauge modes.
+    pub fn set_gauge(&mut self, name: String, value: f64) {
+        self.gauges.insert(name, value);
+    }
+
+    /// Get the gauges of this flush, the received ones followed by those
+    /// derived by `process`. A derived gauge replaces a received one of the
+    /// same name.
+    pub fn all_gauges(&self) -> impl Iterator<Item = (&String, &f64)> {
+        let derived = &self.derived_gauges;
+        self.gauges.iter()
+            .filter(move |&(key, _)| !derived.contains_key(key))
+            .chain(derived.iter())
+    }
+
+    /// Set a gauge for this flush only, it is removed on reset.
+    pub fn set_derived_gauge(&mut self, name: String, value: f64) {
+        self.derived_gauges.insert(name, value);
+    }
+
+    /// Get the statistics of gauges updated in the current interval.
+    ///
+    /// Only tracked when gauge modes or derived gauges are configured.
+    pub fn gauge_stats(&self) -> &HashMap<String, GaugeStats> {
+        &self.gauge_stats
+    }
+
+    /// Get the gauge aggregation configuration.
+    pub fn gauge_modes(&self) -> &GaugeModes {
+        &self.gauge_modes
+    }
+
+    /// Set how gauge updates within an interval are aggregated.
+    pub fn set_gauge_modes(&mut self, modes: GaugeModes) {
+        self.gauge_modes = modes;
+    }
+
+    /// Get the roll-up rules evaluated on flush.
+    pub fn rollups(&self) -> &Rollups {
+        &self.rollups
+    }
+
+    /// Set the roll-up rules evaluated on flush.
+    pub fn set_rollups(&mut self, rollups: Rollups) {
+        self.rollups = rollups;
+    }
+
+    /// Get the top-K series and prefix tracker.
+    pub fn heavy_hitters(&self) -> &HeavyHitters {
+        &self.heavy_hitters
+    }
+
+    /// Set the top-K series and prefix tracker.
+    pub fn set_heavy_hitters(&mut self, heavy_hitters: HeavyHitters) {
+        self.heavy_hitters = heavy_hitters;
+    }
+
     /// Get the timers as a borrowed reference.
     pub fn timers(&self) -> &HashMap<String, Vec<f64>> {
         &self.timers
     }
 
+    /// Get the timers of this flush, the received ones followed by those
+    /// derived by `process`. A derived timer replaces a received one of the
+    /// same name.
+    pub fn all_timers(&self) -> impl Iterator<Item = (&String, &Vec<f64>)> {
+        let derived = &self.derived_timers;
+        self.timers.iter()
+            .filter(move |&(key, _)| !derived.contains_key(key))
+            .chain(derived.iter())
+    }
+
+    /// Get the timers derived by `process` for this flush.
+    pub fn derived_timers(&self) -> &HashMap<String, Vec<f64>> {
+        &self.derived_timers
+    }
+
+    /// Set the samples of a timer for this flush only, it is removed on
+    /// reset.
+    pub fn set_derived_timer(&mut self, name: String, values: Vec<f64>) {
+        self.derived_timers.insert(name, values);
+    }
+
     /// Get the calculated timer data as a borrowed reference.
     pub fn timer_data(&self) -> &HashMap<String, f64> {
         &self.timer_data
@@ -147,10 +277,20 @@ impl Buckets {
         if self.delete_gauges_after_flush {
             self.gauges.clear();
         }
+        self.gauge_stats.clear();
+        self.clear_derived();
+        self.heavy_hitters.rotate();
         self.bad_messages = 0;
         self.total_messages = 0;
     }
 
+    /// Remove the series derived by `process`.
+    pub fn clear_derived(&mut self) {
+        self.derived_counters.clear();
+        self.derived_gauges.clear();
+        self.derived_timers.clear();
+    }
+
     /// Processes metrics adding in derived values.
     pub fn process(&mut self) {
         metric_processor::process(self)
@@ -160,22 +300,113 @@ impl Buckets {
         self.flush_interval_seconds
     }
 
+    /// Set the interval rates and derived timer data are calculated over.
+    pub fn set_flush_interval(&mut self, seconds: f64) {
+        self.flush_interval_seconds = seconds;
+    }
+
+    /// Keep only the series `keep` accepts, called with the key and the
+    /// metric type as `c`, `g` or `ms`. Derived data of dropped timers is
+    /// removed as well.
+    pub fn retain<F: Fn(&str, &str) -> bool>(&mut self, keep: F) {
+        self.counters.retain(|key, _| keep(key, "c"));
+        self.derived_counters.retain(|key, _| keep(key, "c"));
+        self.gauges.retain(|key, _| keep(key, "g"));
+        self.derived_gauges.retain(|key, _| keep(key, "g"));
+        let dropped: Vec<String> = self.timers.keys()
+            .chain(self.derived_timers.keys())
+            .filter(|key| !keep(key, "ms"))
+            .cloned()
+            .collect();
+        for key in dropped.iter() {
+            self.timers.remove(key);
+            self.derived_timers.remove(key);
+            for stat in TIMER_STATS.iter() {
+                self.timer_data.remove(&with_suffix(key, stat));
+            }
+        }
+    }
+
+    /// Add the series of a later flush into this one.
+    ///
+    /// Counters are summed, timer samples appended and gauges replaced by
+    /// their latest value, or by their mode over both flushes when gauge
+    /// statistics are tracked. The server's own `statsd.*` series are not
+    /// aggregated and keep the value of the later flush. Derived series are
+    /// not merged, `process` the merged buckets to compute them again.
+    pub fn merge(&mut self, other: &Buckets) {
+        for (key, value) in other.counters.iter() {
+            if is_internal(key) {
+                self.counters.insert(key.clone(), *value);
+            } else {
+                *self.counters.entry(key.clone()).or_insert(0.0) += *value;
+            }
+        }
+        for (key, value) in other.gauges.iter() {
+            self.gauges.insert(key.clone(), *value);
+        }
+        for (key, stats) in other.gauge_stats.iter() {
+            let merged = self.gauge_stats
+                .entry(key.clone())
+                .and_modify(|merged| merged.merge(stats))
+                .or_insert_with(|| stats.clone());
+            if !is_internal(key) {
+                self.gauges.insert(key.clone(), merged.value(self.gauge_modes.mode_for(key)));
+            }
+        }
+        for (key, values) in other.timers.iter() {
+            if is_internal(key) {
+                self.timers.insert(key.clone(), values.clone());
+            } else {
+                self.timers.entry(key.clone()).or_default().extend_from_slice(values);
+            }
+        }
+        self.clear_derived();
+        self.heavy_hitters = other.heavy_hitters.clone();
+        self.bad_messages += other.bad_messages;
+        self.total_messages += other.total_messages;
+        self.last_message = other.last_message;
+    }
+
+    /// Get the number of threads used to calculate timer data.
+    pub fn processing_threads(&self) -> usize {
+        self.processing_threads
+    }
+
+    /// Set the number of threads used to calculate timer data.
+    pub fn set_processing_threads(&mut self, threads: usize) {
+        self.processing_threads = cmp::max(1, threads);
+    }
+
     pub fn clone(&self) -> Buckets {
         Buckets {
             counters: self.counters.clone(),
             gauges: self.gauges.clone(),
             timers: self.timers.clone(),
+            gauge_stats: self.gauge_stats.clone(),
             timer_data: self.timer_data.clone(),
+            derived_counters: self.derived_counters.clone(),
+            derived_gauges: self.derived_gauges.clone(),
+            derived_timers: self.derived_timers.clone(),
             bad_messages: self.bad_messages,
             total_messages: self.total_messages,
             last_message: self.last_message,
             server_start_time: self.server_start_time,
             flush_interval_seconds: self.flush_interval_seconds,
             delete_gauges_after_flush: self.delete_gauges_after_flush,
+            processing_threads: self.processing_threads,
+            gauge_modes: self.gauge_modes.clone(),
+            rollups: self.rollups.clone(),
+            heavy_hitters: self.heavy_hitters.clone(),
         }
     }
 }
 
+/// Whether a series is one of the server's own `statsd.*` metrics.
+fn is_internal(key: &str) -> bool {
+    key.starts_with("statsd.")
+}
+
 
 // Tests
 //
@@ -185,6 +416,41 @@ mod test {
     use super::super::metric::{Metric, MetricKind};
     use time;
 
+    #[test]
+    fn test_merge() {
+        let mut first = Buckets::new(10., false);
+        first.add(&Metric::new("some.counter", 1.0, MetricKind::Counter(1.0)));
+        first.add(&Metric::new("some.gauge", 1.0, MetricKind::Gauge));
+        first.add(&Metric::new("some.timer", 1.0, MetricKind::Timer));
+        let mut second = Buckets::new(10., false);
+        second.add(&Metric::new("some.counter", 2.0, MetricKind::Counter(1.0)));
+        second.add(&Metric::new("some.gauge", 5.0, MetricKind::Gauge));
+        second.add(&Metric::new("some.timer", 3.0, MetricKind::Timer));
+
+        first.merge(&second);
+        assert_eq!(Some(&3.0), first.counters().get("some.counter"));
+        assert_eq!(Some(&5.0), first.gauges().get("some.gauge"));
+        assert_eq!(Some(&vec![1.0, 3.0]), first.timers().get("some.timer"));
+        assert_eq!(6, first.total_messages());
+    }
+
+    #[test]
+    fn test_merge_internal_and_gauge_modes() {
+        let modes = GaugeModes::parse("queue.=max", false).unwrap();
+        let mut first = Buckets::new(10., false);
+        first.set_gauge_modes(modes.clone());
+        first.add(&Metric::new("queue.depth", 9.0, MetricKind::Gauge));
+        first.process();
+        let mut second = Buckets::new(10., false);
+        second.set_gauge_modes(modes);
+        second.add(&Metric::new("queue.depth", 2.0, MetricKind::Gauge));
+        second.set_counter("statsd.processing_time".to_string(), 4.0);
+
+        first.merge(&second);
+        assert_eq!(Some(&9.0), first.gauges().get("queue.depth"));
+        assert_eq!(Some(&4.0), first.counters().get("statsd.processing_time"));
+    }
+
     #[test]
     fn test_bad_messages() {
         let mut buckets = Buckets::new(0., true);
@@ -264,6 +530,26 @@ mod test {
         assert_eq!(0, buckets.counters().len());
     }
 
+    #[test]
+    fn test_add_gauge_metric_modes() {
+        let mut buckets = Buckets::new(0., false);
+        buckets.set_gauge_modes(GaugeModes::parse("queue.=max,pool.=avg", false).unwrap());
+        for value in [3.0, 9.0, 1.0].iter() {
+            buckets.add(&Metric::new("queue.depth", *value, MetricKind::Gauge));
+            buckets.add(&Metric::new("pool.size", *value, MetricKind::Gauge));
+            buckets.add(&Metric::new("other", *value, MetricKind::Gauge));
+        }
+        assert_eq!(Some(&9.0), buckets.gauges.get("queue.depth"));
+        assert_eq!(Some(&(13.0 / 3.0)), buckets.gauges.get("pool.size"));
+        assert_eq!(Some(&1.0), buckets.gauges.get("other"));
+
+        // A new interval starts aggregating from scratch but keeps the old value.
+        buckets.reset();
+        assert_eq!(Some(&9.0), buckets.gauges.get("queue.depth"));
+        buckets.add(&Metric::new("queue.depth", 2.0, MetricKind::Gauge));
+        assert_eq!(Some(&2.0), buckets.gauges.get("queue.depth"));
+    }
+
     #[test]
     fn test_add_timer_metric() {
         let mut buckets = Buckets::new(0., true);
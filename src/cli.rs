@@ -15,7 +15,11 @@ Options:
   --zeromq-listen                Enable listening for metrics via ZeroMQ protocol
   --zeromq-port=<p>              The ZeroMQ port to bind to [default: 8130].
   --flush-interval=<p>           How frequently to flush metrics to the backends in seconds. [default: 10].
+  --processing-threads=<p>       Number of threads used to calculate timer data [default: 4].
   --console                      Enable the console backend.
+  --console-format=<p>           Print series as table, json or statsd. [default: table].
+  --console-filter=<p>           Only print series matching this glob, or regex as /regex/. [default: ].
+  --console-changed              Only print series that changed since the previous flush.
   --graphite                     Enable the graphite backend.
   --graphite-prefix=<p>          Set graphite global prefix [default: stats].
   --graphite-prefix-counter=<p>  Set graphite counter prefix [default: counters].
@@ -23,8 +27,21 @@ Options:
   --graphite-prefix-timer=<p>    Set graphite timer prefix [default: timers].
   --graphite-port=<p>            The port graphite/carbon is running on. [default: 2003].
   --graphite-host=<p>            The host graphite/carbon is running on. [default: 127.0.0.1].
+  --graphite-protocol=<p>        Send to graphite as plaintext or pickle. [default: plaintext].
+  --graphite-pickle-port=<p>     The port carbon's pickle receiver is running on. [default: 2004].
+  --graphite-pickle-batch-size=<p>  Maximum data points per pickle message. [default: 500].
+  --graphite-destinations=<p>    Carbon destinations as host:port[:instance] separated by comma, instead of the host and port. [default: ].
+  --graphite-routing=<p>         Send to all destinations or by consistent-hashing like carbon-relay. [default: all].
+  --graphite-replication-factor=<p>  Destinations each data point is sent to with consistent-hashing. [default: 1].
+  --graphite-diverse-replicas    Send the replicas of a data point to different servers.
+  --graphite-tagged              Send graphite 1.1 tagged series.
+  --graphite-tag-patterns=<p>    Name patterns turning segments into tags, e.g. app.<env>.<host>.* [default: ].
+  --graphite-queue-size=<p>      Unsent graphite flushes kept in memory for retry. [default: 30].
+  --graphite-spill-dir=<p>       Directory unsent graphite flushes overflow to. [default: ].
+  --graphite-spill-limit=<p>     Maximum unsent graphite flushes kept on disk. [default: 8640].
   --admin-host=<p>               The host to bind the management server on. [default: 127.0.0.1].
   --admin-port=<p>               The port to bind the management server to. [default: 8126].
+  --admin-shutdown               Allow the shutdown admin command to stop the server.
   --statsd                       Enable the statsd backend.
   --statsd-zmq                   Enable the zeromq statsd backend.
   --statsd-port=<p>              DEPRECATED The port other statsd is running on. [default: 0].
@@ -32,7 +49,70 @@ Options:
   --statsd-hosts=<p>             Other statsd hosts with ports, separated by comma. [default: 127.0.0.1:8125].
   --statsd-zmq-hosts=<p>         Other statsd zmq hosts with ports, separated by comma. [default: tcp://127.0.0.1:8130].
   --statsd-packet-size=<p>       The maximum statsd packet size that will be sent [default: 16384].
+  --statsd-shard                 Send each series to one of the statsd hosts by consistent hashing.
+  --statsd-multi-value           Send all samples of a timer on one line as key:v1:v2|ms.
+  --statsd-protocol=<p>          Relay to the statsd hosts over udp or tcp. [default: udp].
+  --statsd-queue-size=<p>        Unsent tcp writes kept per statsd host while it is unreachable. [default: 1000].
+  --repeater                     Repeat every received packet to other statsd servers.
+  --repeater-hosts=<p>           Hosts to repeat to as host:port, udp://host:port or tcp://host:port
+                                 separated by comma. [default: ].
+  --repeater-include=<p>         Only repeat metrics matching these globs, separated by comma. [default: ].
+  --repeater-exclude=<p>         Do not repeat metrics matching these globs, separated by comma. [default: ].
+  --prometheus                   Enable the prometheus /metrics endpoint backend.
+  --prometheus-host=<p>          The host to serve prometheus metrics on. [default: 0.0.0.0].
+  --prometheus-port=<p>          The port to serve prometheus metrics on. [default: 9102].
+  --influxdb                     Enable the influxdb line protocol backend.
+  --influxdb-url=<p>             Where to write to, as udp://host:port, tcp://host:port or an http:// write URL.
+                                 [default: http://127.0.0.1:8086/write?db=statsd].
+  --opentsdb                     Enable the opentsdb backend.
+  --opentsdb-host=<p>            The host opentsdb is running on. [default: 127.0.0.1].
+  --opentsdb-port=<p>            The port opentsdb is running on. [default: 4242].
+  --opentsdb-prefix=<p>          Set opentsdb global prefix [default: stats].
+  --opentsdb-tags=<p>            Tags added to every data point as key=value separated by comma.
+                                 Must include host, defaults to host=<hostname>. [default: ].
+  --otlp                         Enable the OpenTelemetry OTLP/HTTP backend.
+  --otlp-url=<p>                 The OTLP/HTTP metrics endpoint. [default: http://127.0.0.1:4318/v1/metrics].
+  --otlp-resource=<p>            Resource attributes as key=value separated by comma. [default: service.name=statsd].
+  --json-lines                   Enable the JSON lines backend.
+  --json-lines-output=<p>        File to write JSON lines to, - for stdout. [default: -].
+  --json-lines-per-flush         Write one JSON object per flush instead of one per series.
+  --json-lines-rotate-size=<p>   Rotate the output file at this many bytes, 0 to never rotate. [default: 0].
+  --json-lines-rotate-keep=<p>   Number of rotated output files to keep. [default: 5].
+  --archive                      Enable the local archive backend.
+  --archive-dir=<p>              Directory the archive writes one file per hour to. [default: archive].
+  --archive-retention=<p>        Hours of archive files to keep, 0 to keep all. [default: 168].
+  --archive-query=<p>            Print the archived series matching a glob and exit. [default: ].
+  --archive-from=<p>             Start of the archive query, as a unix timestamp, now or e.g. -6h. [default: -1h].
+  --archive-until=<p>            End of the archive query. [default: now].
+  --webhook                      Enable the HTTP webhook backend.
+  --webhook-urls=<p>             URLs to POST every flush to as JSON, separated by comma. [default: http://127.0.0.1:8080/statsd].
+  --webhook-headers=<p>          Headers sent with every request as Name: value separated by comma. [default: ].
+  --webhook-gzip                 Compress the requests with gzip.
+  --webhook-batch-size=<p>       Maximum bytes of JSON per request, 0 for one request per flush. [default: 1048576].
+  --webhook-retries=<p>          Times a failed request is retried. [default: 3].
+  --webhook-backoff=<p>          Milliseconds before the first retry, doubled for each next one. [default: 500].
+  --webhook-request-timeout=<p>  Seconds to wait for a response. [default: 5].
+  --config=<p>                   Load [[backend]] sections from a TOML file. [default: ].
+  --backend-include=<p>          Only send series matching a glob to a backend, as backend=glob pairs
+                                 separated by comma. [default: ].
+  --backend-exclude=<p>          Do not send series matching a glob to a backend, as backend=glob pairs
+                                 separated by comma. [default: ].
+  --backend-types=<p>            Only send these metric types to a backend, as backend=type pairs
+                                 separated by comma. Types are c, g and ms. [default: ].
+  --backend-intervals=<p>        Flush a backend less often, as backend=seconds pairs separated by comma.
+                                 Must be a multiple of the flush interval. [default: ].
+  --backend-timeouts=<p>         Flush deadline of a backend in seconds, as backend=seconds pairs
+                                 separated by comma. Defaults to the flush interval. [default: ].
   --delete-gauges                Delete gauges after flush. Default sents the old value.
+  --gauge-modes=<p>              Gauge aggregation per prefix as prefix=mode pairs separated by comma.
+                                 Modes are last, min, max, avg and sum. [default: ].
+  --gauge-derived                Also send .min, .max and .avg series for updated gauges.
+  --rollups=<p>                  Roll-up rules as function:pattern=target separated by comma.
+                                 Functions are sum, min, max and avg. [default: ].
+  --rules-file=<p>               Load metric rewrite, drop and allow rules from a file [default: ].
+  --top-k=<p>                    Track the top K series and prefixes by update count, 0 disables [default: 0].
+  --top-k-prefix-depth=<p>       Number of name segments in tracked prefixes [default: 2].
+  --top-k-metrics                Send top K counts as statsd.top_series and statsd.top_prefixes gauges.
   --benchmark                    Run benchmarks
 ";
 
@@ -44,8 +124,13 @@ pub struct Args {
     pub flag_zeromq_port: u16,
     pub flag_admin_port: u16,
     pub flag_admin_host: String,
+    pub flag_admin_shutdown: bool,
     pub flag_flush_interval: u64,
+    pub flag_processing_threads: usize,
     pub flag_console: bool,
+    pub flag_console_format: String,
+    pub flag_console_filter: String,
+    pub flag_console_changed: bool,
     pub flag_graphite: bool,
     pub flag_graphite_prefix: String,
     pub flag_graphite_prefix_counter: String,
@@ -53,6 +138,18 @@ pub struct Args {
     pub flag_graphite_prefix_timer: String,
     pub flag_graphite_port: u16,
     pub flag_graphite_host: String,
+    pub flag_graphite_protocol: String,
+    pub flag_graphite_pickle_port: u16,
+    pub flag_graphite_pickle_batch_size: usize,
+    pub flag_graphite_destinations: String,
+    pub flag_graphite_routing: String,
+    pub flag_graphite_replication_factor: usize,
+    pub flag_graphite_diverse_replicas: bool,
+    pub flag_graphite_tagged: bool,
+    pub flag_graphite_tag_patterns: String,
+    pub flag_graphite_queue_size: usize,
+    pub flag_graphite_spill_dir: String,
+    pub flag_graphite_spill_limit: usize,
     pub flag_statsd: bool,
     pub flag_statsd_zmq: bool,
     pub flag_statsd_port: u16,
@@ -60,8 +157,60 @@ pub struct Args {
     pub flag_statsd_hosts: String,
     pub flag_statsd_zmq_hosts: String,
     pub flag_statsd_packet_size: usize,
+    pub flag_statsd_shard: bool,
+    pub flag_statsd_multi_value: bool,
+    pub flag_statsd_protocol: String,
+    pub flag_statsd_queue_size: usize,
+    pub flag_repeater: bool,
+    pub flag_repeater_hosts: String,
+    pub flag_repeater_include: String,
+    pub flag_repeater_exclude: String,
+    pub flag_prometheus: bool,
+    pub flag_prometheus_host: String,
+    pub flag_prometheus_port: u16,
+    pub flag_influxdb: bool,
+    pub flag_influxdb_url: String,
+    pub flag_opentsdb: bool,
+    pub flag_opentsdb_host: String,
+    pub flag_opentsdb_port: u16,
+    pub flag_opentsdb_prefix: String,
+    pub flag_opentsdb_tags: String,
+    pub flag_otlp: bool,
+    pub flag_otlp_url: String,
+    pub flag_otlp_resource: String,
+    pub flag_json_lines: bool,
+    pub flag_json_lines_output: String,
+    pub flag_json_lines_per_flush: bool,
+    pub flag_json_lines_rotate_size: u64,
+    pub flag_json_lines_rotate_keep: usize,
+    pub flag_archive: bool,
+    pub flag_archive_dir: String,
+    pub flag_archive_retention: u64,
+    pub flag_archive_query: String,
+    pub flag_archive_from: String,
+    pub flag_archive_until: String,
+    pub flag_webhook: bool,
+    pub flag_webhook_urls: String,
+    pub flag_webhook_headers: String,
+    pub flag_webhook_gzip: bool,
+    pub flag_webhook_batch_size: usize,
+    pub flag_webhook_retries: usize,
+    pub flag_webhook_backoff: u64,
+    pub flag_webhook_request_timeout: f64,
+    pub flag_config: String,
+    pub flag_backend_include: String,
+    pub flag_backend_exclude: String,
+    pub flag_backend_types: String,
+    pub flag_backend_intervals: String,
+    pub flag_backend_timeouts: String,
     pub flag_delete_gauges: bool,
-    pub flag_help: bool,
+    pub flag_gauge_modes: String,
+    pub flag_gauge_derived: bool,
+    pub flag_rollups: String,
+    pub flag_rules_file: String,
+    pub flag_top_k: usize,
+    pub flag_top_k_prefix_depth: usize,
+    pub flag_top_k_metrics: bool,
     pub flag_benchmark: bool,
 }
 
@@ -0,0 +1,182 @@
+//! Gauge aggregation within a flush interval.
+//!
+//! By default a gauge keeps the last value it received. Prefixes can be
+//! configured to instead report the min, max, average or sum of every
+//! update received during the interval.
+
+use std::cmp;
+
+/// How multiple updates to a gauge within one interval are combined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GaugeMode {
+    Last,
+    Min,
+    Max,
+    Average,
+    Sum,
+}
+
+impl GaugeMode {
+    /// Parse a mode name as used on the command line.
+    pub fn parse(name: &str) -> Result<GaugeMode, String> {
+        match name {
+            "last" => Ok(GaugeMode::Last),
+            "min" => Ok(GaugeMode::Min),
+            "max" => Ok(GaugeMode::Max),
+            "avg" | "average" => Ok(GaugeMode::Average),
+            "sum" => Ok(GaugeMode::Sum),
+            x => Err(format!("Unknown gauge mode `{}`", x)),
+        }
+    }
+}
+
+
+/// Per prefix gauge modes.
+#[derive(Debug, Clone)]
+pub struct GaugeModes {
+    // Sorted by prefix length, longest first.
+    rules: Vec<(String, GaugeMode)>,
+    derived: bool,
+}
+
+impl GaugeModes {
+    /// Create a configuration where every gauge keeps the last value.
+    pub fn new() -> GaugeModes {
+        GaugeModes {
+            rules: Vec::new(),
+            derived: false,
+        }
+    }
+
+    /// Parse a comma separated list of `prefix=mode` pairs.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let modes = GaugeModes::parse("queue.=max,pool.size=avg", false).unwrap();
+    /// ```
+    pub fn parse(spec: &str, derived: bool) -> Result<GaugeModes, String> {
+        let mut modes = GaugeModes::new();
+        modes.derived = derived;
+        for rule in spec.split(',').map(|rule| rule.trim()).filter(|rule| !rule.is_empty()) {
+            let mut parts = rule.splitn(2, '=');
+            let prefix = parts.next().unwrap_or("").trim();
+            let mode = match parts.next() {
+                Some(mode) => GaugeMode::parse(mode.trim())?,
+                None => return Err(format!("Gauge mode rule `{}` must be prefix=mode", rule)),
+            };
+            modes.rules.push((prefix.to_string(), mode));
+        }
+        modes.rules.sort_by_key(|rule| cmp::Reverse(rule.0.len()));
+        Ok(modes)
+    }
+
+    /// Find the mode for a gauge name using the longest matching prefix.
+    pub fn mode_for(&self, name: &str) -> GaugeMode {
+        self.rules
+            .iter()
+            .find(|(prefix, _)| name.starts_with(prefix.as_str()))
+            .map(|&(_, mode)| mode)
+            .unwrap_or(GaugeMode::Last)
+    }
+
+    /// Whether `.min`, `.max` and `.avg` series are derived for gauges.
+    pub fn derived(&self) -> bool {
+        self.derived
+    }
+
+    /// Whether gauges need per interval statistics to be tracked.
+    pub fn tracks_stats(&self) -> bool {
+        self.derived || !self.rules.is_empty()
+    }
+}
+
+
+/// Statistics of the updates a gauge received during the current interval.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GaugeStats {
+    pub last: f64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: usize,
+}
+
+impl GaugeStats {
+    pub fn new(value: f64) -> GaugeStats {
+        GaugeStats {
+            last: value,
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.last = value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Combine with the statistics of a later interval.
+    pub fn merge(&mut self, later: &GaugeStats) {
+        self.last = later.last;
+        self.min = self.min.min(later.min);
+        self.max = self.max.max(later.max);
+        self.sum += later.sum;
+        self.count += later.count;
+    }
+
+    pub fn average(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+
+    /// The value reported for the gauge under the given mode.
+    pub fn value(&self, mode: GaugeMode) -> f64 {
+        match mode {
+            GaugeMode::Last => self.last,
+            GaugeMode::Min => self.min,
+            GaugeMode::Max => self.max,
+            GaugeMode::Average => self.average(),
+            GaugeMode::Sum => self.sum,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_modes() {
+        let modes = GaugeModes::parse("queue.=max, queue.depth.=sum,pool=avg", true).unwrap();
+        assert!(modes.derived());
+        assert_eq!(GaugeMode::Sum, modes.mode_for("queue.depth.web"));
+        assert_eq!(GaugeMode::Max, modes.mode_for("queue.size"));
+        assert_eq!(GaugeMode::Average, modes.mode_for("pool.size"));
+        assert_eq!(GaugeMode::Last, modes.mode_for("other"));
+    }
+
+    #[test]
+    fn test_parse_modes_invalid() {
+        assert!(GaugeModes::parse("queue.=median", false).is_err());
+        assert!(GaugeModes::parse("queue.", false).is_err());
+        assert!(!GaugeModes::parse("", false).unwrap().tracks_stats());
+    }
+
+    #[test]
+    fn test_stats_values() {
+        let mut stats = GaugeStats::new(4.0);
+        stats.add(10.0);
+        stats.add(1.0);
+        assert_eq!(1.0, stats.value(GaugeMode::Last));
+        assert_eq!(1.0, stats.value(GaugeMode::Min));
+        assert_eq!(10.0, stats.value(GaugeMode::Max));
+        assert_eq!(5.0, stats.value(GaugeMode::Average));
+        assert_eq!(15.0, stats.value(GaugeMode::Sum));
+    }
+}
@@ -0,0 +1,220 @@
+//! Consistent hashing of metric keys over a set of nodes.
+//!
+//! Every node is placed on the ring many times so keys spread evenly, and
+//! adding or removing a node only moves the keys of that node.
+
+use std::collections::HashSet;
+
+/// Points each node gets on the ring.
+const REPLICAS: usize = 160;
+
+/// Points each node gets on carbon's ring.
+const CARBON_REPLICAS: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    // Sorted (point, node index) pairs
+    points: Vec<(u64, usize)>,
+}
+
+/// 64 bit FNV-1a followed by a finalizer to spread similar keys.
+///
+/// The hash must be stable across builds and platforms, so all servers
+/// relaying to the same downstreams agree on where a key goes.
+pub fn hash(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.iter() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+impl HashRing {
+    /// Build a ring over the node names. Positions depend on the names
+    /// only, not on their order.
+    pub fn new(nodes: &[String]) -> HashRing {
+        let mut points = Vec::with_capacity(nodes.len() * REPLICAS);
+        for (idx, node) in nodes.iter().enumerate() {
+            for replica in 0..REPLICAS {
+                points.push((hash(format!("{}-{}", node, replica).as_bytes()), idx));
+            }
+        }
+        points.sort();
+        HashRing { points: points }
+    }
+
+    /// The index of the node owning `key`, `None` for an empty ring.
+    pub fn node_for(&self, key: &str) -> Option<usize> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let point = hash(key.as_bytes());
+        let pos = match self.points.binary_search_by(|&(p, _)| p.cmp(&point)) {
+            Ok(pos) => pos,
+            Err(pos) => pos % self.points.len(),
+        };
+        Some(self.points[pos].1)
+    }
+}
+
+
+/// The position of a key on carbon's ring, the first 16 bits of its md5.
+pub fn carbon_hash(key: &str) -> u32 {
+    let digest = md5::compute(key.as_bytes());
+    u32::from(digest[0]) << 8 | u32::from(digest[1])
+}
+
+/// A ring placing nodes like carbon's `carbon_ch` hash, so metrics go to
+/// the same carbon cache as they would through carbon-relay configured
+/// with the same `DESTINATIONS`.
+#[derive(Debug, Clone)]
+pub struct CarbonRing {
+    // Sorted (position, node index) pairs
+    points: Vec<(u32, usize)>,
+    servers: Vec<String>,
+}
+
+impl CarbonRing {
+    /// Build a ring over `(server, instance)` nodes, in the order of
+    /// carbon's `DESTINATIONS`. The order matters as colliding positions
+    /// are moved for the nodes added last.
+    pub fn new(nodes: &[(String, Option<String>)]) -> CarbonRing {
+        let mut taken = HashSet::new();
+        let mut points = Vec::with_capacity(nodes.len() * CARBON_REPLICAS);
+        for (idx, (server, instance)) in nodes.iter().enumerate() {
+            // carbon names a node by the repr of its Python tuple.
+            let node = match instance {
+                Some(instance) => format!("('{}', '{}')", server, instance),
+                None => format!("('{}', None)", server),
+            };
+            for replica in 0..CARBON_REPLICAS {
+                let mut position = carbon_hash(&format!("{}:{}", node, replica));
+                while !taken.insert(position) {
+                    position += 1;
+                }
+                points.push((position, idx));
+            }
+        }
+        points.sort();
+        CarbonRing {
+            points: points,
+            servers: nodes.iter().map(|(server, _)| server.clone()).collect(),
+        }
+    }
+
+    /// The indexes of up to `count` nodes for `key`, in the order of the
+    /// ring. With `diverse` at most one node per server is used.
+    pub fn nodes_for(&self, key: &str, count: usize, diverse: bool) -> Vec<usize> {
+        let mut nodes: Vec<usize> = Vec::with_capacity(count);
+        if self.points.is_empty() || count == 0 {
+            return nodes;
+        }
+        if self.servers.len() == 1 {
+            nodes.push(self.points[0].1);
+            return nodes;
+        }
+
+        let position = carbon_hash(key);
+        let len = self.points.len();
+        let mut index = match self.points.binary_search_by(|&(p, _)| p.cmp(&position)) {
+            Ok(pos) => pos,
+            Err(pos) => pos % len,
+        };
+        let last = (index + len - 1) % len;
+        let mut seen: Vec<usize> = Vec::with_capacity(self.servers.len());
+        while seen.len() < self.servers.len() && index != last {
+            let node = self.points[index].1;
+            if !seen.contains(&node) {
+                seen.push(node);
+                let server = &self.servers[node];
+                if !diverse || !nodes.iter().any(|&used| self.servers[used] == *server) {
+                    nodes.push(node);
+                    if nodes.len() >= count {
+                        break;
+                    }
+                }
+            }
+            index = (index + 1) % len;
+        }
+        nodes
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nodes(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("10.0.0.{}:8125", i)).collect()
+    }
+
+    #[test]
+    fn test_empty_ring() {
+        assert_eq!(None, HashRing::new(&[]).node_for("a.b"));
+    }
+
+    #[test]
+    fn test_keys_spread_evenly() {
+        let ring = HashRing::new(&nodes(4));
+        let mut counts = [0; 4];
+        for i in 0..10000 {
+            counts[ring.node_for(&format!("api.server{}.requests", i)).unwrap()] += 1;
+        }
+        for count in counts.iter() {
+            assert!(*count > 1800 && *count < 3200, "uneven spread {:?}", counts);
+        }
+    }
+
+    #[test]
+    fn test_adding_a_node_moves_few_keys() {
+        let before = HashRing::new(&nodes(4));
+        let after = HashRing::new(&nodes(5));
+        let mut moved = 0;
+        for i in 0..10000 {
+            let key = format!("api.server{}.requests", i);
+            let old = before.node_for(&key).unwrap();
+            let new = after.node_for(&key).unwrap();
+            if old != new {
+                // Keys only ever move to the new node.
+                assert_eq!(4, new);
+                moved += 1;
+            }
+        }
+        assert!(moved > 1200 && moved < 2800, "moved {}", moved);
+    }
+    #[test]
+    fn test_carbon_hash() {
+        // int(md5("a.b.c").hexdigest()[:4], 16)
+        assert_eq!(21823, carbon_hash("a.b.c"));
+    }
+
+    #[test]
+    fn test_carbon_ring_matches_carbon_relay() {
+        // Node orders computed by carbon's ConsistentHashRing.get_nodes
+        let ring = CarbonRing::new(&[("10.0.0.1".to_string(), Some("a".to_string())),
+                                     ("10.0.0.1".to_string(), Some("b".to_string())),
+                                     ("10.0.0.2".to_string(), None)]);
+        assert_eq!(vec![1, 2, 0], ring.nodes_for("stats.counters.api.requests", 3, false));
+        assert_eq!(vec![2, 1, 0], ring.nodes_for("stats.gauges.api.queue", 3, false));
+        assert_eq!(vec![0, 2, 1], ring.nodes_for("carbon.agents.x", 3, false));
+        assert_eq!(vec![1, 0], ring.nodes_for("stats.timers.db.latency.max", 2, false));
+        assert_eq!(vec![1], ring.nodes_for("a.b.c", 1, false));
+
+        // One node per server with diverse replicas
+        assert_eq!(vec![1, 2], ring.nodes_for("stats.timers.db.latency.max", 2, true));
+        assert_eq!(vec![1, 2], ring.nodes_for("a.b.c", 3, true));
+    }
+
+    #[test]
+    fn test_carbon_ring_single_node() {
+        let ring = CarbonRing::new(&[("10.0.0.1".to_string(), None)]);
+        assert_eq!(vec![0], ring.nodes_for("a.b.c", 2, false));
+        assert!(CarbonRing::new(&[]).nodes_for("a.b.c", 1, false).is_empty());
+    }
+}
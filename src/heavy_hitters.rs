@@ -0,0 +1,217 @@
+//! Tracks the series and prefixes receiving the most updates.
+//!
+//! Uses the Space-Saving algorithm so memory stays bounded no matter how
+//! many distinct series are seen in an interval. Counts of reported items
+//! may be overestimated by at most their `error`.
+
+use std::collections::{BTreeSet, HashMap};
+
+/// How many counters are kept per reported item.
+const CAPACITY_FACTOR: usize = 10;
+
+
+/// A heavy hitter with its estimated update count.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hitter {
+    pub name: String,
+    pub count: u64,
+    pub error: u64,
+}
+
+
+/// Space-Saving summary over a stream of names.
+#[derive(Debug, Clone)]
+pub struct SpaceSaving {
+    capacity: usize,
+    counts: HashMap<String, (u64, u64)>,
+    order: BTreeSet<(u64, String)>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> SpaceSaving {
+        SpaceSaving {
+            capacity: capacity,
+            counts: HashMap::with_capacity(capacity),
+            order: BTreeSet::new(),
+        }
+    }
+
+    /// Record one update of `name`.
+    pub fn observe(&mut self, name: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(entry) = self.counts.get_mut(name) {
+            self.order.remove(&(entry.0, name.to_string()));
+            entry.0 += 1;
+            self.order.insert((entry.0, name.to_string()));
+            return;
+        }
+
+        let (count, error) = if self.counts.len() < self.capacity {
+            (1, 0)
+        } else {
+            // Replace the smallest counter, inheriting its count as error.
+            let smallest = self.order.iter().next().cloned().unwrap();
+            self.order.remove(&smallest);
+            self.counts.remove(&smallest.1);
+            (smallest.0 + 1, smallest.0)
+        };
+        self.counts.insert(name.to_string(), (count, error));
+        self.order.insert((count, name.to_string()));
+    }
+
+    /// The `k` items with the highest counts, largest first.
+    pub fn top(&self, k: usize) -> Vec<Hitter> {
+        self.order
+            .iter()
+            .rev()
+            .take(k)
+            .map(|&(count, ref name)| Hitter {
+                name: name.clone(),
+                count: count,
+                error: self.counts[name].1,
+            })
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.clear();
+        self.order.clear();
+    }
+}
+
+
+/// Top-K series and prefixes for the current and previous interval.
+#[derive(Debug, Clone)]
+pub struct HeavyHitters {
+    k: usize,
+    prefix_depth: usize,
+    metrics: bool,
+    series: SpaceSaving,
+    prefixes: SpaceSaving,
+    last_series: Vec<Hitter>,
+    last_prefixes: Vec<Hitter>,
+}
+
+impl HeavyHitters {
+    /// Track the top `k` series and prefixes of `prefix_depth` segments.
+    ///
+    /// A `k` of zero disables tracking. When `metrics` is set the counts
+    /// are also reported as internal metrics on flush.
+    pub fn new(k: usize, prefix_depth: usize, metrics: bool) -> HeavyHitters {
+        HeavyHitters {
+            k: k,
+            prefix_depth: prefix_depth,
+            metrics: metrics,
+            series: SpaceSaving::new(k * CAPACITY_FACTOR),
+            prefixes: SpaceSaving::new(k * CAPACITY_FACTOR),
+            last_series: Vec::new(),
+            last_prefixes: Vec::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.k > 0
+    }
+
+    /// Whether the top counts are sent as internal metrics.
+    pub fn metrics(&self) -> bool {
+        self.enabled() && self.metrics
+    }
+
+    pub fn observe(&mut self, name: &str) {
+        if !self.enabled() {
+            return;
+        }
+        self.series.observe(name);
+        if self.prefix_depth > 0 {
+            let end = name.match_indices('.')
+                .nth(self.prefix_depth - 1)
+                .map(|(pos, _)| pos)
+                .unwrap_or_else(|| name.len());
+            self.prefixes.observe(&name[..end]);
+        }
+    }
+
+    /// Top series of the current interval.
+    pub fn top_series(&self) -> Vec<Hitter> {
+        self.series.top(self.k)
+    }
+
+    /// Top prefixes of the current interval.
+    pub fn top_prefixes(&self) -> Vec<Hitter> {
+        self.prefixes.top(self.k)
+    }
+
+    /// Top series of the last completed interval.
+    pub fn last_series(&self) -> &[Hitter] {
+        &self.last_series
+    }
+
+    /// Top prefixes of the last completed interval.
+    pub fn last_prefixes(&self) -> &[Hitter] {
+        &self.last_prefixes
+    }
+
+    /// Finish the current interval, keeping its report.
+    pub fn rotate(&mut self) {
+        if !self.enabled() {
+            return;
+        }
+        self.last_series = self.top_series();
+        self.last_prefixes = self.top_prefixes();
+        self.series.clear();
+        self.prefixes.clear();
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_space_saving_exact_when_under_capacity() {
+        let mut summary = SpaceSaving::new(10);
+        for name in ["a", "b", "a", "c", "a", "b"].iter() {
+            summary.observe(name);
+        }
+        let top = summary.top(2);
+        assert_eq!(Hitter { name: "a".to_string(), count: 3, error: 0 }, top[0]);
+        assert_eq!(Hitter { name: "b".to_string(), count: 2, error: 0 }, top[1]);
+    }
+
+    #[test]
+    fn test_space_saving_finds_heavy_hitter() {
+        let mut summary = SpaceSaving::new(4);
+        for i in 0..1000 {
+            summary.observe(&format!("noise.{}", i));
+            if i % 2 == 0 {
+                summary.observe("heavy");
+            }
+        }
+        let top = summary.top(1);
+        assert_eq!("heavy", top[0].name);
+        assert!(top[0].count >= 500);
+        assert!(top[0].count - top[0].error <= 500);
+    }
+
+    #[test]
+    fn test_heavy_hitters_prefixes_and_rotate() {
+        let mut hitters = HeavyHitters::new(2, 2, false);
+        hitters.observe("api.web1.requests");
+        hitters.observe("api.web1.errors");
+        hitters.observe("api.web2.requests");
+        hitters.observe("db");
+
+        let prefixes = hitters.top_prefixes();
+        assert_eq!("api.web1", prefixes[0].name);
+        assert_eq!(2, prefixes[0].count);
+
+        hitters.rotate();
+        assert!(hitters.top_series().is_empty());
+        assert_eq!(2, hitters.last_series().len());
+        assert_eq!("api.web1", hitters.last_prefixes()[0].name);
+    }
+}
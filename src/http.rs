@@ -0,0 +1,248 @@
+//! Minimal HTTP/1.1 client used by the push based backends.
+//!
+//! Only plain `http://` URLs are supported, each request uses a new
+//! connection.
+
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// The parts of an `http://host:port/path?query` URL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    /// Parse an `http://` URL. The port defaults to 80 and the path to `/`.
+    /// IPv6 hosts are written in brackets, like `http://[::1]:8086/`.
+    pub fn parse(url: &str) -> Result<Url, String> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => return Err(format!("Only http:// URLs are supported, got `{}`", url)),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        };
+        let invalid_port = || format!("Invalid port in URL `{}`", url);
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let end = bracketed.find(']')
+                .ok_or_else(|| format!("Unclosed IPv6 address in URL `{}`", url))?;
+            let port = match &bracketed[end + 1..] {
+                "" => 80,
+                rest => rest.strip_prefix(':')
+                    .and_then(|port| port.parse::<u16>().ok())
+                    .ok_or_else(invalid_port)?,
+            };
+            (&bracketed[..end], port)
+        } else {
+            match authority.rfind(':') {
+                Some(pos) => {
+                    let port = authority[pos + 1..].parse::<u16>().map_err(|_| invalid_port())?;
+                    (&authority[..pos], port)
+                }
+                None => (authority, 80),
+            }
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in URL `{}`", url));
+        }
+        Ok(Url {
+            host: host.to_string(),
+            port: port,
+            path: path.to_string(),
+        })
+    }
+
+    /// The `host:port` of the URL, with IPv6 hosts in brackets.
+    pub fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+
+/// The status and body of an HTTP response.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+}
+
+
+/// Send a POST request and wait for the response.
+pub fn post(url: &Url,
+            headers: &[(String, String)],
+            body: &[u8],
+            timeout: Duration)
+            -> io::Result<Response> {
+    let addr = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Cannot resolve host"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                              url.path, url.authority(), body.len());
+    for (name, value) in headers.iter() {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line.split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid HTTP status line"))?;
+
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        if name == "content-length" {
+            content_length = parts.next().and_then(|value| value.trim().parse::<usize>().ok());
+        }
+    }
+
+    let mut body = Vec::new();
+    match content_length {
+        Some(len) => {
+            body.resize(len, 0);
+            reader.read_exact(&mut body)?;
+        }
+        None => {
+            reader.read_to_end(&mut body)?;
+        }
+    }
+    Ok(Response {
+        status: status,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+
+/// A request received by `serve_one`.
+#[cfg(test)]
+pub struct Request {
+    pub request_line: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Accept a single request on a local port and answer with `status`.
+///
+/// Returns the port and a channel receiving the request, for testing
+/// backends against a mock HTTP server.
+#[cfg(test)]
+pub fn serve_one(status: u16) -> (u16, std::sync::mpsc::Receiver<Request>) {
+    serve(vec![status])
+}
+
+/// Accept one request per status on a local port, answering each with its
+/// status in turn.
+#[cfg(test)]
+pub fn serve(statuses: Vec<u16>) -> (u16, std::sync::mpsc::Receiver<Request>) {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (send, recv) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for status in statuses.into_iter() {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+
+            let mut headers = Vec::new();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                if reader.read_line(&mut header).unwrap() == 0 || header.trim().is_empty() {
+                    break;
+                }
+                let mut parts = header.splitn(2, ':');
+                let name = parts.next().unwrap_or("").trim().to_lowercase();
+                let value = parts.next().unwrap_or("").trim().to_string();
+                if name == "content-length" {
+                    content_length = value.parse().unwrap();
+                }
+                headers.push((name, value));
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let stream = reader.get_mut();
+            write!(stream, "HTTP/1.1 {} Mock\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            let _ = send.send(Request {
+                request_line: request_line.trim().to_string(),
+                headers: headers,
+                body: body,
+            });
+        }
+    });
+    (port, recv)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(Url { host: "localhost".to_string(), port: 8086, path: "/write?db=statsd".to_string() },
+                   Url::parse("http://localhost:8086/write?db=statsd").unwrap());
+        assert_eq!(Url { host: "example.com".to_string(), port: 80, path: "/".to_string() },
+                   Url::parse("http://example.com").unwrap());
+        assert!(Url::parse("https://example.com").is_err());
+
+        let url = Url::parse("http://[::1]:8086/write").unwrap();
+        assert_eq!(Url { host: "::1".to_string(), port: 8086, path: "/write".to_string() }, url);
+        assert_eq!("[::1]:8086", url.authority());
+        assert_eq!(80, Url::parse("http://[fe80::1]").unwrap().port);
+        assert!(Url::parse("http://[::1:8086/").is_err());
+        assert!(Url::parse("http://[::1]8086/").is_err());
+        assert!(Url::parse("http://example.com:http/").is_err());
+        assert!(Url::parse("http://:80/").is_err());
+    }
+
+    #[test]
+    fn test_post() {
+        let (port, requests) = serve_one(204);
+        let url = Url::parse(&format!("http://127.0.0.1:{}/write", port)).unwrap();
+        let headers = vec![("Content-Type".to_string(), "text/plain".to_string())];
+        let response = post(&url, &headers, b"hello", Duration::from_secs(5)).unwrap();
+        assert_eq!(204, response.status);
+        assert!(response.is_success());
+
+        let request = requests.recv().unwrap();
+        assert_eq!("POST /write HTTP/1.1", request.request_line);
+        assert!(request.headers.contains(&("content-type".to_string(), "text/plain".to_string())));
+        assert_eq!(b"hello".to_vec(), request.body);
+    }
+}
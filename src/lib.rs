@@ -0,0 +1,348 @@
+#[macro_use]
+extern crate serde_derive;
+extern crate tokio_core;
+extern crate futures;
+
+extern crate serde;
+extern crate time;
+extern crate docopt;
+extern crate toml;
+extern crate md5;
+extern crate flate2;
+
+use std::thread;
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::str;
+use std::time::Duration;
+use registry::Registry;
+
+
+// Local module imports.
+pub mod metric;
+mod cli;
+mod server;
+pub mod buckets;
+mod gauge;
+mod hash_ring;
+mod http;
+mod heavy_hitters;
+pub mod backend;
+mod management;
+mod metric_processor;
+mod pattern;
+pub mod registry;
+mod rollup;
+mod route;
+mod rules;
+mod statsd_batch;
+
+mod backends {
+    pub mod archive;
+    pub mod console;
+    pub mod graphite;
+    pub mod influxdb;
+    pub mod json_lines;
+    pub mod opentsdb;
+    pub mod otlp;
+    pub mod prometheus;
+    pub mod reliable;
+    pub mod repeater;
+    pub mod statsd;
+    pub mod statsd_zmq;
+    pub mod webhook;
+}
+
+/// Run the server with the backend types of `registry`.
+pub fn run(registry: Registry) {
+    let args = cli::parse_args();
+
+    if args.flag_benchmark {
+        backends::statsd_zmq::benchmarks();
+        metric_processor::benchmarks();
+        return;
+    }
+
+    if !args.flag_archive_query.is_empty() {
+        if let Err(e) = query_archive(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let backends = match configure_backends(&registry, &args) {
+        Ok(backends) => backends,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let workers: Vec<backend::Worker> = backends.into_iter().map(backend::Worker::spawn).collect();
+    let statuses: Vec<backend::SharedStatus> = workers.iter().map(|worker| worker.status()).collect();
+
+    let (event_send, event_recv) = sync_channel(1000000);
+    let flush_send = event_send.clone();
+    let udp_send = event_send.clone();
+    let tcp_send = event_send.clone();
+
+    let mut buckets = buckets::Buckets::new(
+        args.flag_flush_interval as f64,
+        args.flag_delete_gauges
+    );
+    buckets.set_processing_threads(args.flag_processing_threads);
+    match gauge::GaugeModes::parse(&args.flag_gauge_modes, args.flag_gauge_derived) {
+        Ok(modes) => buckets.set_gauge_modes(modes),
+        Err(e) => {
+            eprintln!("Invalid --gauge-modes: {}", e);
+            std::process::exit(1);
+        }
+    }
+    buckets.set_heavy_hitters(heavy_hitters::HeavyHitters::new(
+        args.flag_top_k,
+        args.flag_top_k_prefix_depth,
+        args.flag_top_k_metrics
+    ));
+    match rollup::Rollups::parse(&args.flag_rollups) {
+        Ok(rollups) => buckets.set_rollups(rollups),
+        Err(e) => {
+            eprintln!("Invalid --rollups: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let buckets_snapshot = Arc::new(Mutex::new(buckets.clone()));
+
+    let rules = if args.flag_rules_file.is_empty() {
+        rules::Rules::new()
+    } else {
+        match rules::Rules::load(&args.flag_rules_file) {
+            Ok(rules) => rules,
+            Err(e) => {
+                eprintln!("Invalid --rules-file: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+    let rules = Arc::new(rules);
+
+    let mut repeater = if args.flag_repeater {
+        match backends::repeater::Destination::parse_list(&args.flag_repeater_hosts) {
+            Ok(destinations) => {
+                let filter = backends::repeater::Filter::new(&args.flag_repeater_include,
+                                                             &args.flag_repeater_exclude);
+                Some(backends::repeater::Repeater::new(destinations, filter))
+            }
+            Err(e) => {
+                eprintln!("Invalid --repeater-hosts: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    println!("Starting statsd - {}",
+             time::at(buckets.start_time()).rfc822());
+    println!("Data server on 0.0.0.0:{}", args.flag_port);
+    println!("Admin server on {}:{}",
+             args.flag_admin_host,
+             args.flag_admin_port);
+
+    std::panic::set_hook(Box::new(|err| {
+        eprintln!("Panic error {:?}, exiting program.", err);
+        std::process::exit(77);
+    }));
+
+    // Setup the UDP server which publishes events to the event channel
+    let port = args.flag_port;
+    thread::spawn(move || {
+        server::udp_server(udp_send, port);
+    });
+
+    if args.flag_zeromq_listen {
+        let zmq_send = event_send.clone();
+        let port = args.flag_zeromq_port;
+        println!("ZeroMQ server on 0.0.0.0:{}", port);
+        thread::spawn(move || {
+            backends::statsd_zmq::statsd_zmq_event_emitter(port, zmq_send);
+        });
+    }
+
+    // Setup the TCP server for administration
+    let tcp_port = args.flag_admin_port;
+    let tcp_host = args.flag_admin_host;
+    thread::spawn(move || {
+        server::admin_server(tcp_send, tcp_port, &tcp_host);
+    });
+
+    // Run the timer that flushes metrics to the backends.
+    let flush_interval = args.flag_flush_interval;
+    thread::spawn(move || {
+        server::flush_timer_loop(flush_send, flush_interval);
+    });
+
+    // Main event loop.
+    loop {
+        let result = match event_recv.recv() {
+            Ok(res) => res,
+            Err(e) => panic!("Event channel has hung up: {:?}", e),
+        };
+
+        match result {
+            server::Event::TimerFlush => {
+                // Report the flushes of the previous interval, failing the
+                // ones still running past their deadline.
+                let now = time::get_time();
+                for worker in workers.iter() {
+                    worker.check_deadline(now);
+                }
+                backend::record_metrics(&mut buckets, &statuses);
+                buckets.process();
+                // Every worker flushes the same snapshot concurrently.
+                let flushed = Arc::new(buckets.clone());
+                for worker in workers.iter() {
+                    if let Err(e) = worker.flush(flushed.clone()) {
+                        eprintln!("Failed to flush {}: {}", worker.name(), e);
+                    }
+                }
+                buckets.reset();
+
+                let snapshot = buckets.clone();
+                *buckets_snapshot.lock().unwrap() = snapshot
+            }
+
+            server::Event::Shutdown => {
+                println!("Shutting down, flushing metrics one last time");
+                // Let the flushes still running finish first, so the last
+                // interval isn't skipped.
+                for worker in workers.iter() {
+                    if !worker.wait() {
+                        worker.check_deadline(time::get_time());
+                    }
+                }
+                backend::record_metrics(&mut buckets, &statuses);
+                buckets.process();
+                let flushed = Arc::new(buckets.clone());
+                for worker in workers.iter() {
+                    if let Err(e) = worker.flush(flushed.clone()) {
+                        eprintln!("Failed to flush {}: {}", worker.name(), e);
+                    }
+                }
+                for worker in workers.iter() {
+                    if let Err(e) = worker.shutdown() {
+                        eprintln!("Failed to shut down {}: {}", worker.name(), e);
+                    }
+                }
+                std::process::exit(0);
+            }
+
+            server::Event::UdpMessage(buf) => {
+                if let Some(ref mut repeater) = repeater {
+                    repeater.repeat(&buf);
+                }
+
+                // Create the metric and push it into the buckets.
+                if let Ok(val) = str::from_utf8(&buf) {
+                    match metric::Metric::parse(val) {
+                        Ok(metrics) => {
+                            for metric in metrics.into_iter() {
+                                if let Some(metric) = rules.apply(metric) {
+                                    buckets.add(&metric);
+                                }
+                            }
+                        }
+                        Err(_) => buckets.add_bad_message(),
+                    }
+                }
+            }
+
+            server::Event::ZmqBatch(batch) => {
+                let mut repeated = Vec::new();
+                batch.iterate_optimal(&mut |metric| {
+                    if repeater.is_some() {
+                        repeated.push(metric.to_line());
+                    }
+                    if let Some(metric) = rules.apply(metric) {
+                        buckets.add(&metric);
+                    }
+                });
+                if let Some(ref mut repeater) = repeater {
+                    repeater.repeat_lines(&repeated);
+                }
+            }
+
+            server::Event::TcpMessage(stream) => {
+                let cl_mutex = buckets_snapshot.clone();
+                let rules = rules.clone();
+                let statuses = statuses.clone();
+                let events = if args.flag_admin_shutdown { Some(event_send.clone()) } else { None };
+                thread::spawn(move || {
+                    management::exec(stream, cl_mutex, rules, statuses, events);
+                });
+            }
+        }
+    }
+}
+
+
+/// Print the archived series matching `--archive-query`, one
+/// `timestamp kind key value` line per data point.
+fn query_archive(args: &cli::Args) -> Result<(), String> {
+    let now = time::get_time().sec;
+    let from = backends::archive::parse_time(&args.flag_archive_from, now)?;
+    let until = backends::archive::parse_time(&args.flag_archive_until, now)?;
+    let pattern = pattern::Glob::new(&args.flag_archive_query);
+    let points = backends::archive::query(std::path::Path::new(&args.flag_archive_dir), &pattern, from, until)
+        .map_err(|e| format!("Could not read the archive in {}: {:?}", args.flag_archive_dir, e))?;
+    for point in points.iter() {
+        println!("{} {} {} {}", point.timestamp, point.kind, point.key, point.value);
+    }
+    Ok(())
+}
+
+/// Build and start the backends of the command line flags and the config
+/// file, with their routes and flush deadlines.
+fn configure_backends(registry: &Registry, args: &cli::Args) -> Result<Vec<backend::Managed>, String> {
+    let mut sections = registry::cli_sections(args);
+    if !args.flag_config.is_empty() {
+        let config = registry::load_config(&args.flag_config, args.flag_flush_interval)
+            .map_err(|e| format!("Invalid --config: {}", e))?;
+        sections.extend(config);
+    }
+
+    let mut routes = route::Routes::parse(&args.flag_backend_include,
+                                          &args.flag_backend_exclude,
+                                          &args.flag_backend_types,
+                                          &args.flag_backend_intervals,
+                                          args.flag_flush_interval)
+        .map_err(|e| format!("Invalid backend routes: {}", e))?;
+    let mut timeouts = backend::parse_timeouts(&args.flag_backend_timeouts)
+        .map_err(|e| format!("Invalid --backend-timeouts: {}", e))?;
+    for section in sections.iter() {
+        routes.add_section(section)?;
+        if let Some(seconds) = section.float("timeout")? {
+            timeouts.insert(section.name.clone(), backend::timeout(&section.name, seconds)?);
+        }
+    }
+    for name in routes.backends().into_iter().chain(timeouts.keys().map(|name| name.as_str())) {
+        if !sections.iter().any(|section| section.name == name) {
+            return Err(format!("Options are configured for {}, which is not enabled", name));
+        }
+    }
+
+    let mut backends: Vec<backend::Managed> = Vec::with_capacity(sections.len());
+    for section in sections.iter() {
+        if backends.iter().any(|backend| backend.name() == section.name) {
+            return Err(format!("Backend name {} is used more than once", section.name));
+        }
+        let mut backend = backend::Managed::new(&section.name, registry.build(section)?);
+        backend.set_route(routes.get(&section.name));
+        let timeout = timeouts.get(&section.name)
+            .cloned()
+            .unwrap_or(Duration::from_secs(args.flag_flush_interval));
+        backend.set_timeout(timeout);
+        backend.start().map_err(|e| format!("Cannot start the {} backend: {}", section.name, e))?;
+        backends.push(backend);
+    }
+    Ok(backends)
+}
@@ -1,13 +1,23 @@
+use crate::backend::SharedStatus;
 use crate::buckets::Buckets;
+use crate::rules::Rules;
+use crate::server::Event;
 use time;
 use std::net::TcpStream;
 use std::io::{BufReader, BufRead, Write};
 use std::fmt::Write as fmtWrite;
+use std::sync::mpsc::SyncSender;
 use std::sync::{Arc, Mutex};
 
 /// Handle the management commands
 /// returning the response to send back.
-pub fn exec(stream: TcpStream, buckets_mutex: Arc<Mutex<Buckets>>) {
+///
+/// The `shutdown` command is only available when `events` is given.
+pub fn exec(stream: TcpStream,
+            buckets_mutex: Arc<Mutex<Buckets>>,
+            rules: Arc<Rules>,
+            statuses: Vec<SharedStatus>,
+            events: Option<SyncSender<Event>>) {
     let mut reader = BufReader::new(stream);
     let mut done = false;
 
@@ -37,6 +47,12 @@ pub fn exec(stream: TcpStream, buckets_mutex: Arc<Mutex<Buckets>>) {
                 out.push_str("counters - print counter data.\n");
                 out.push_str("gauges   - print gauge data.\n");
                 out.push_str("timers   - print timer data.\n");
+                out.push_str("rules    - print ingest rule hit counts.\n");
+                out.push_str("top      - print the most updated series and prefixes.\n");
+                out.push_str("backends - print the flush status of each backend.\n");
+                if events.is_some() {
+                    out.push_str("shutdown - flush one last time and stop the server.\n");
+                }
                 out.push_str("quit     - close this connection.\n");
             }
             "stats" => {
@@ -66,6 +82,42 @@ pub fn exec(stream: TcpStream, buckets_mutex: Arc<Mutex<Buckets>>) {
                 }
                 write!(out, "END\n\n").unwrap();
             }
+            "top" => {
+                let buckets = latest_snapshot();
+                let hitters = buckets.heavy_hitters();
+                if !hitters.enabled() {
+                    writeln!(out, "top-k tracking is disabled, see --top-k").unwrap();
+                }
+                writeln!(out, "series:").unwrap();
+                for hitter in hitters.last_series().iter() {
+                    writeln!(out, " {}: {} (error {})", hitter.name, hitter.count, hitter.error).unwrap();
+                }
+                writeln!(out, "prefixes:").unwrap();
+                for hitter in hitters.last_prefixes().iter() {
+                    writeln!(out, " {}: {} (error {})", hitter.name, hitter.count, hitter.error).unwrap();
+                }
+                write!(out, "END\n\n").unwrap();
+            }
+            "rules" => {
+                out.push_str(&rules.format_hits());
+                write!(out, "END\n\n").unwrap();
+            }
+            "backends" => {
+                for status in statuses.iter() {
+                    out.push_str(&status.lock().unwrap().format());
+                }
+                write!(out, "END\n\n").unwrap();
+            }
+            "shutdown" => {
+                match events {
+                    Some(ref events) => {
+                        events.send(Event::Shutdown).expect("Cannot send shutdown to channel");
+                        write!(out, "Shutting down\n\n").unwrap();
+                        done = true
+                    }
+                    None => writeln!(out, "ERROR - shutdown is disabled, see --admin-shutdown").unwrap(),
+                }
+            }
             "quit" => {
                 write!(out, "Good bye!\n\n").unwrap();
                 done = true
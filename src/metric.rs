@@ -50,10 +50,28 @@ impl Metric {
         }
     }
 
+    /// Format the metric as a statsd line, without a trailing newline.
+    pub fn to_line(&self) -> String {
+        let kind = match self.kind {
+            MetricKind::Counter(rate) if rate != 1.0 => format!("c|@{}", rate),
+            MetricKind::Counter(_) => "c".to_string(),
+            MetricKind::Gauge => "g".to_string(),
+            MetricKind::Timer => "ms".to_string(),
+        };
+        statsd_line(&self.name, &self.value.to_string(), &kind)
+    }
+
     /// Valid message formats are:
     ///
     /// - `<str:metric_name>:<f64:value>|<str:type>`
     /// - `<str:metric_name>:<f64:value>|c|@<f64:sample_rate>`
+    /// - `<str:metric_name>:<f64:value>|<str:type>|#<str:tag>:<str:value>,...`
+    /// - `<str:metric_name>:<f64:value>:<f64:value>...|<str:type>`
+    ///
+    /// A line with several values creates one metric for each value.
+    ///
+    /// Tagged metrics are named `<metric_name>;<tag>=<value>` with the tags
+    /// sorted, the same as Graphite's tagged series.
     ///
     /// Multiple metrics can be sent in a single UDP packet
     /// separated by newlines.
@@ -62,7 +80,7 @@ impl Metric {
 
         for line in source.lines() {
             match Metric::parse_line(line) {
-                Ok(metric) => results.push(metric),
+                Ok(metrics) => results.extend(metrics),
                 Err(e) => return Err(e),
             }
         }
@@ -72,8 +90,8 @@ impl Metric {
         Ok(results)
     }
 
-    /// Parses a metric from each line in a packet.
-    fn parse_line(line: &str) -> Result<Metric, ParseError> {
+    /// Parses the metrics from each line in a packet.
+    fn parse_line(line: &str) -> Result<Vec<Metric>, ParseError> {
         // track position in string
         let mut idx = 0;
 
@@ -94,60 +112,162 @@ impl Metric {
             name = &name[1..name.len() - 1];
         }
 
-        // Get the float val
-        let value = match line[idx..].find('|') {
+        // Get the float values
+        let mut values = Vec::with_capacity(1);
+        match line[idx..].find('|') {
             Some(pos) => {
                 let start = idx;
                 idx += pos + 1;
-                match line[start..idx - 1].parse::<f64>() {
-                    Ok(value) => value,
-                    _ => return Err(ParseError::SyntaxError(
-                        "Cannot parse a number in a metric", idx)),
+                for value in line[start..idx - 1].split(':') {
+                    match value.parse::<f64>() {
+                        Ok(value) => values.push(value),
+                        _ => return Err(ParseError::SyntaxError(
+                            "Cannot parse a number in a metric", idx)),
+                    }
                 }
             }
             _ => return Err(ParseError::SyntaxError("Metrics require a value.", idx)),
         };
-        let kind_name = match line[idx..].find('|') {
-            Some(pos) => {
-                let start = idx;
-                idx += pos;
-                line[start..idx].to_string()
-            }
-            _ => line[idx..].to_string(),
-        };
-
-        // Get kind parts, use deref/ref tricks
-        // to get types to match
-        let kind = match &*kind_name {
-            "ms" => MetricKind::Timer,
-            "g" => MetricKind::Gauge,
-            "c" => {
-                let rate: f64 = match line[idx..].find('@') {
-                    Some(pos) => {
-                        idx += pos + 1;
-                        match line[idx..].parse::<f64>() {
-                            Ok(value) => value,
-                            _ => return Err(ParseError::SyntaxError(
-                                "Cannot parse sample size in counter", idx)),
-                        }
-                    }
-                    _ => 1.0,
+        // The type is followed by optional `|@rate` and `|#tags` sections.
+        let mut sections = line[idx..].split('|');
+        let kind_name = sections.next().unwrap_or("");
+        idx += kind_name.len();
+        let mut rate = 1.0;
+        let mut tags = Vec::new();
+        for section in sections {
+            idx += 1;
+            if let Some(sample) = section.strip_prefix('@') {
+                rate = match sample.parse::<f64>() {
+                    Ok(value) => value,
+                    _ => return Err(ParseError::SyntaxError(
+                        "Cannot parse sample size in counter", idx + 1)),
                 };
-                MetricKind::Counter(rate)
+            } else if let Some(spec) = section.strip_prefix('#') {
+                tags = parse_tags(spec);
             }
-            _ => return Err(ParseError::SyntaxError("Unknown metric type.", idx)),
+            idx += section.len();
+        }
+
+        let kind = || match kind_name {
+            "ms" => Ok(MetricKind::Timer),
+            "g" => Ok(MetricKind::Gauge),
+            "c" => Ok(MetricKind::Counter(rate)),
+            _ => Err(ParseError::SyntaxError("Unknown metric type.", idx)),
         };
-        Ok(Metric::new(name, value, kind))
+        let name = if tags.is_empty() {
+            name.to_string()
+        } else {
+            tagged_name(name, &tags)
+        };
+        values.into_iter()
+            .map(|value| Ok(Metric::new(name.clone(), value, kind()?)))
+            .collect()
+    }
+}
+
+/// Parse a comma separated list of `key:value` tags.
+///
+/// Tags without a value get the value `true`.
+fn parse_tags(source: &str) -> Vec<(String, String)> {
+    source.split(',')
+        .map(|tag| tag.trim())
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| {
+            let mut parts = tag.splitn(2, ':');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("true");
+            (clean_tag(key), clean_tag(value))
+        })
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
+
+/// Remove the characters used to separate tags in a metric name.
+fn clean_tag(tag: &str) -> String {
+    tag.trim().replace(&[';', '='][..], "_")
+}
+
+/// Build the name of a tagged metric, `<name>;<tag>=<value>;...`.
+pub fn tagged_name(name: &str, tags: &[(String, String)]) -> String {
+    let mut tags: Vec<&(String, String)> = tags.iter().collect();
+    tags.sort();
+    let mut result = name.to_string();
+    for (key, value) in tags.iter() {
+        result.push(';');
+        result.push_str(key);
+        result.push('=');
+        result.push_str(value);
+    }
+    result
+}
+
+/// Build the statsd line `<name>:<values>|<type>` of a possibly tagged
+/// metric name, with the tags as `|#<tag>:<value>,...`.
+///
+/// # Examples
+///
+/// ```
+/// use statsd::metric::statsd_line;
+///
+/// assert_eq!("requests:1|c|#host:web1", statsd_line("requests;host=web1", "1", "c"));
+/// ```
+pub fn statsd_line(key: &str, values: &str, kind: &str) -> String {
+    let (name, tags) = split_tags(key);
+    let mut line = format!("{}:{}|{}", name, values, kind);
+    for (idx, &(tag, value)) in tags.iter().enumerate() {
+        line.push_str(if idx == 0 { "|#" } else { "," });
+        line.push_str(tag);
+        line.push(':');
+        line.push_str(value);
     }
+    line
+}
+
+/// Split a metric name into the bare name and its tags.
+///
+/// # Examples
+///
+/// ```
+/// use statsd::metric::split_tags;
+///
+/// let (name, tags) = split_tags("requests;host=web1");
+/// assert_eq!("requests", name);
+/// assert_eq!(vec![("host", "web1")], tags);
+/// ```
+pub fn split_tags(key: &str) -> (&str, Vec<(&str, &str)>) {
+    let mut parts = key.split(';');
+    let name = parts.next().unwrap_or("");
+    let tags = parts
+        .map(|tag| {
+            let mut kv = tag.splitn(2, '=');
+            (kv.next().unwrap_or(""), kv.next().unwrap_or(""))
+        })
+        .collect();
+    (name, tags)
 }
 
+/// Append a suffix to a metric name, keeping any tags at the end.
+///
+/// # Examples
+///
+/// ```
+/// use statsd::metric::with_suffix;
+///
+/// assert_eq!("requests.max;host=web1", with_suffix("requests;host=web1", "max"));
+/// ```
+pub fn with_suffix(key: &str, suffix: &str) -> String {
+    match key.find(';') {
+        Some(pos) => format!("{}.{}{}", &key[..pos], suffix, &key[pos..]),
+        None => format!("{}.{}", key, suffix),
+    }
+}
 
 
 // Tests
 //
 #[cfg(test)]
 mod tests {
-    use crate::metric::{Metric, MetricKind};
+    use crate::metric::{Metric, MetricKind, split_tags, statsd_line, with_suffix};
     use std::collections::HashMap;
 
     #[test]
@@ -183,6 +303,15 @@ mod tests {
         assert_eq!(13.2, res[1].value);
     }
 
+    #[test]
+    fn test_metric_multiple_values() {
+        let res = Metric::parse("a.b:1:2.5:3|ms|#host:web1").unwrap();
+        assert_eq!(3, res.len());
+        assert_eq!("a.b;host=web1", res[2].name);
+        assert_eq!(vec![1.0, 2.5, 3.0], res.iter().map(|m| m.value).collect::<Vec<f64>>());
+        assert!(Metric::parse("a.b:1::3|ms").is_err());
+    }
+
     #[test]
     fn test_metric_valid() {
         let mut valid = HashMap::new();
@@ -198,6 +327,12 @@ mod tests {
                      Metric::new("thing.total", 12.0, MetricKind::Counter(1.0)));
         valid.insert("thing.total:5.6|c|@123",
                      Metric::new("thing.total", 5.6, MetricKind::Counter(123.0)));
+        valid.insert("thing.total:5.6|c|@0.5|#host:web1,env:prod",
+                     Metric::new("thing.total;env=prod;host=web1", 5.6, MetricKind::Counter(0.5)));
+        valid.insert("foo.test:12.3|ms|#host:web1",
+                     Metric::new("foo.test;host=web1", 12.3, MetricKind::Timer));
+        valid.insert("test:1|g|#canary",
+                     Metric::new("test;canary=true", 1.0, MetricKind::Gauge));
 
         for (input, expected) in valid.iter() {
             let result = Metric::parse(*input);
@@ -213,6 +348,15 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_metric_to_line() {
+        for line in ["thing.total:5.6|c|@0.5|#env:prod,host:web1", "a.b:12|c", "a.b:1.5|ms", "t:1|g|#canary:true"].iter() {
+            let metric = Metric::parse(line).unwrap().remove(0);
+            assert_eq!(*line, metric.to_line());
+        }
+        assert_eq!("a.b:1:2|ms", statsd_line("a.b", "1:2", "ms"));
+    }
+
     #[test]
     fn test_metric_invalid() {
         let invalid = vec!["",
@@ -229,4 +373,17 @@ mod tests {
             assert!(result.is_err());
         }
     }
+
+    #[test]
+    fn test_split_tags() {
+        assert_eq!(("foo.test", vec![]), split_tags("foo.test"));
+        assert_eq!(("foo.test", vec![("env", "prod"), ("host", "web1")]),
+                   split_tags("foo.test;env=prod;host=web1"));
+    }
+
+    #[test]
+    fn test_with_suffix() {
+        assert_eq!("foo.test.max", with_suffix("foo.test", "max"));
+        assert_eq!("foo.test.max;host=web1", with_suffix("foo.test;host=web1", "max"));
+    }
 }
@@ -1,51 +1,75 @@
 use super::buckets::Buckets;
-use super::metric::{Metric, MetricKind};
+use super::metric::{Metric, MetricKind, with_suffix};
+use super::rollup::Rolled;
+use std::cmp;
 use std::collections::HashMap;
+use std::thread;
 use time;
 
+/// Minimum number of timer keys handed to each worker thread.
+///
+/// Below this the cost of spawning a thread outweighs the sorting work,
+/// so small buckets are always summarised on the calling thread.
+const MIN_KEYS_PER_WORKER: usize = 1000;
+
+/// Suffixes of the derived values produced for each timer.
+pub const TIMER_STATS: [&str; 10] = [
+    "count", "count_ps", "max", "mean", "median", "min",
+    "stddev", "upper_90", "upper_95", "upper_99",
+];
+
 /// Creates derived values from metric data.
 ///
 /// Creates:
 ///
+/// - roll-up series.
 /// - timer percentile data.
+/// - gauge min/max/avg data, when enabled.
+/// - top-K series and prefix counts, when enabled.
 /// - internal processing metrics
 pub fn process(buckets: &mut Buckets) {
     let start_time = time::get_time();
+    buckets.clear_derived();
 
-    let mut timer_data = HashMap::new();
-
-    // Add the various derived values for timers.
-    for (key, values) in buckets.timers().iter() {
-        if values.len() > 0 {
-            let mut v = values.clone();
-            v.sort_by(|a, b| a.partial_cmp(b).unwrap());
-
-            let len = v.len() as f64;
-            let sum = v.iter().fold(0.0, |sum, x| sum + x);
-            let mean = sum / len;
-
-            // Get population standard deviation
-            let sum_diff = v.iter().fold(0.0, |sum, x| sum + (x - mean).powi(2));
-            let stddev = (sum_diff / len).sqrt();
-
-            let median = percentile(&v, 0.5);
-            let upper_90 = percentile(&v, 0.90);
-            let upper_95 = percentile(&v, 0.95);
-            let upper_99 = percentile(&v, 0.99);
-            let count_per_second = len as f64 / buckets.flush_interval();
-
-            timer_data.insert(format!("{}.min", key), v[0]);
-            timer_data.insert(format!("{}.max", key), v[v.len() - 1]);
-            timer_data.insert(format!("{}.count", key), len);
-            timer_data.insert(format!("{}.count_ps", key), count_per_second as f64);
-            timer_data.insert(format!("{}.mean", key), mean);
-            timer_data.insert(format!("{}.median", key), median);
-            timer_data.insert(format!("{}.stddev", key), stddev);
-            timer_data.insert(format!("{}.upper_90", key), upper_90);
-            timer_data.insert(format!("{}.upper_95", key), upper_95);
-            timer_data.insert(format!("{}.upper_99", key), upper_99);
+    if !buckets.rollups().is_empty() {
+        for rolled in buckets.rollups().evaluate(buckets).into_iter() {
+            match rolled {
+                Rolled::Counter(key, value) => buckets.set_derived_counter(key, value),
+                Rolled::Gauge(key, value) => buckets.set_derived_gauge(key, value),
+                Rolled::Timer(key, values) => buckets.set_derived_timer(key, values),
+            }
         }
     }
+
+    if buckets.gauge_modes().derived() {
+        let mut gauge_data = Vec::with_capacity(buckets.gauge_stats().len() * 3);
+        for (key, stats) in buckets.gauge_stats().iter() {
+            gauge_data.push((with_suffix(key, "min"), stats.min));
+            gauge_data.push((with_suffix(key, "max"), stats.max));
+            gauge_data.push((with_suffix(key, "avg"), stats.average()));
+        }
+        for (key, value) in gauge_data.into_iter() {
+            buckets.set_derived_gauge(key, value);
+        }
+    }
+
+    if buckets.heavy_hitters().metrics() {
+        let series = buckets.heavy_hitters().top_series();
+        let prefixes = buckets.heavy_hitters().top_prefixes();
+        for hitter in series.into_iter() {
+            buckets.set_derived_gauge(format!("statsd.top_series.{}", hitter.name), hitter.count as f64);
+        }
+        for hitter in prefixes.into_iter() {
+            buckets.set_derived_gauge(format!("statsd.top_prefixes.{}", hitter.name), hitter.count as f64);
+        }
+    }
+
+    let mut timer_data = summarise_timers(buckets.timers(),
+                                          buckets.flush_interval(),
+                                          buckets.processing_threads());
+    timer_data.extend(summarise_timers(buckets.derived_timers(),
+                                       buckets.flush_interval(),
+                                       buckets.processing_threads()));
     buckets.set_timer_data(timer_data);
 
     let duration = time::get_time() - start_time;
@@ -56,6 +80,92 @@ pub fn process(buckets: &mut Buckets) {
 }
 
 
+/// Calculate the derived values for every timer.
+///
+/// Large timer sets are partitioned by sorted key across up to `threads`
+/// worker threads. Each key is summarised independently, so the result
+/// is identical to the single threaded computation.
+pub fn summarise_timers(timers: &HashMap<String, Vec<f64>>,
+                    flush_interval: f64,
+                    threads: usize)
+                    -> HashMap<String, f64> {
+    let workers = cmp::max(1, cmp::min(threads, timers.len() / MIN_KEYS_PER_WORKER));
+    let mut timer_data = HashMap::with_capacity(timers.len() * TIMER_STATS.len());
+
+    if workers == 1 {
+        for (key, values) in timers.iter() {
+            summarise_timer(key, values.clone(), flush_interval, &mut timer_data);
+        }
+        return timer_data;
+    }
+
+    let mut keys: Vec<&String> = timers.keys().collect();
+    keys.sort();
+    // Rounded up so there are at most `workers` chunks, there are keys as
+    // there is more than one worker. Not div_ceil, which the pinned
+    // toolchain doesn't have yet.
+    let chunk_size = (keys.len() - 1) / workers + 1;
+
+    let handles: Vec<thread::JoinHandle<HashMap<String, f64>>> = keys
+        .chunks(chunk_size)
+        .map(|chunk| {
+            let work: Vec<(String, Vec<f64>)> = chunk.iter()
+                .map(|key| ((*key).clone(), timers[*key].clone()))
+                .collect();
+            thread::spawn(move || {
+                let mut data = HashMap::with_capacity(work.len() * TIMER_STATS.len());
+                for (key, values) in work.into_iter() {
+                    summarise_timer(&key, values, flush_interval, &mut data);
+                }
+                data
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        timer_data.extend(handle.join().expect("Timer processing thread panicked"));
+    }
+    timer_data
+}
+
+
+/// Add the various derived values for a single timer.
+fn summarise_timer(key: &str,
+                   mut v: Vec<f64>,
+                   flush_interval: f64,
+                   timer_data: &mut HashMap<String, f64>) {
+    if v.is_empty() {
+        return;
+    }
+    v.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let len = v.len() as f64;
+    let sum = v.iter().fold(0.0, |sum, x| sum + x);
+    let mean = sum / len;
+
+    // Get population standard deviation
+    let sum_diff = v.iter().fold(0.0, |sum, x| sum + (x - mean).powi(2));
+    let stddev = (sum_diff / len).sqrt();
+
+    let median = percentile(&v, 0.5);
+    let upper_90 = percentile(&v, 0.90);
+    let upper_95 = percentile(&v, 0.95);
+    let upper_99 = percentile(&v, 0.99);
+    let count_per_second = len / flush_interval;
+
+    timer_data.insert(with_suffix(key, "min"), v[0]);
+    timer_data.insert(with_suffix(key, "max"), v[v.len() - 1]);
+    timer_data.insert(with_suffix(key, "count"), len);
+    timer_data.insert(with_suffix(key, "count_ps"), count_per_second);
+    timer_data.insert(with_suffix(key, "mean"), mean);
+    timer_data.insert(with_suffix(key, "median"), median);
+    timer_data.insert(with_suffix(key, "stddev"), stddev);
+    timer_data.insert(with_suffix(key, "upper_90"), upper_90);
+    timer_data.insert(with_suffix(key, "upper_95"), upper_95);
+    timer_data.insert(with_suffix(key, "upper_99"), upper_99);
+}
+
+
 /// Extract the value at the given percentile.
 /// If vector has an even length, two values will be
 /// averaged together.
@@ -71,11 +181,48 @@ fn percentile(values: &Vec<f64>, tile: f64) -> f64 {
 
 
 
+fn benchmark_timer_processing(keys: usize, threads: usize) {
+    let mut buckets = Buckets::new(10.0, false);
+    buckets.set_processing_threads(threads);
+    for i in 0..keys {
+        for sample in 0..20 {
+            buckets.add(&Metric::new(format!("timer_some_metric_{}", i),
+                                     ((i * 31 + sample * 17) % 1000) as f64,
+                                     MetricKind::Timer));
+        }
+    }
+
+    let rounds = 10;
+    let before = std::time::Instant::now();
+    for _ in 0..rounds {
+        let data = summarise_timers(buckets.timers(),
+                                    buckets.flush_interval(),
+                                    buckets.processing_threads());
+        assert_eq!(keys * TIMER_STATS.len(), data.len());
+    }
+    let after = std::time::Instant::now();
+    let res = after - before;
+    println!("{} timer summaries of {} keys with {} threads done in {} ms",
+             rounds, keys, threads, res.as_millis());
+}
+
+pub fn benchmarks() {
+    for keys in [1000, 10000, 50000].iter() {
+        benchmark_timer_processing(*keys, 1);
+        benchmark_timer_processing(*keys, 4);
+        benchmark_timer_processing(*keys, 8);
+    }
+}
+
+
 #[cfg(test)]
 mod test {
     use super::*;
     use super::super::buckets::Buckets;
+    use super::super::gauge::GaugeModes;
+    use super::super::heavy_hitters::HeavyHitters;
     use super::super::metric::{Metric, MetricKind};
+    use super::super::rollup::Rollups;
 
     fn make_buckets() -> Buckets {
         let mut buckets = Buckets::new(0., true);
@@ -157,4 +304,89 @@ mod test {
 
         assert_eq!(Some(&0.0), buckets.counters().get("statsd.processing_time"));
     }
+
+    #[test]
+    fn test_process_derived_gauges() {
+        let mut buckets = Buckets::new(0., false);
+        buckets.set_gauge_modes(GaugeModes::parse("", true).unwrap());
+        for value in [4.0, 8.0, 3.0].iter() {
+            buckets.add(&Metric::new("queue.depth", *value, MetricKind::Gauge));
+        }
+        process(&mut buckets);
+
+        let gauges: HashMap<&String, &f64> = buckets.all_gauges().collect();
+        assert_eq!(4, gauges.len());
+        assert_eq!(Some(&&3.0), gauges.get(&"queue.depth".to_string()));
+        assert_eq!(Some(&&3.0), gauges.get(&"queue.depth.min".to_string()));
+        assert_eq!(Some(&&8.0), gauges.get(&"queue.depth.max".to_string()));
+        assert_eq!(Some(&&5.0), gauges.get(&"queue.depth.avg".to_string()));
+        assert_eq!(None, buckets.gauges().get("queue.depth.max"));
+
+        // The derived gauges are not sent again after an idle interval.
+        buckets.reset();
+        process(&mut buckets);
+        let keys: Vec<&String> = buckets.all_gauges().map(|(key, _)| key).collect();
+        assert_eq!(vec!["queue.depth"], keys);
+    }
+
+    #[test]
+    fn test_process_rollups() {
+        let mut buckets = Buckets::new(10., true);
+        buckets.set_rollups(Rollups::parse("sum:api.*.time=api.all.time,sum:api.*.hits=api.all.hits").unwrap());
+        buckets.add(&Metric::new("api.web1.time", 5.0, MetricKind::Timer));
+        buckets.add(&Metric::new("api.web2.time", 1.0, MetricKind::Timer));
+        buckets.add(&Metric::new("api.web2.time", 3.0, MetricKind::Timer));
+        buckets.add(&Metric::new("api.web1.hits", 2.0, MetricKind::Counter(1.0)));
+        process(&mut buckets);
+
+        assert_eq!(Some(&3.0), buckets.timer_data().get("api.all.time.count"));
+        assert_eq!(Some(&1.0), buckets.timer_data().get("api.all.time.min"));
+        assert_eq!(Some(&5.0), buckets.timer_data().get("api.all.time.max"));
+        assert!(buckets.all_timers().any(|(key, _)| key == "api.all.time"));
+        assert!(buckets.all_counters().any(|(key, value)| key == "api.all.hits" && *value == 2.0));
+
+        // Roll-ups are not stored with the received series.
+        assert_eq!(None, buckets.timers().get("api.all.time"));
+        assert_eq!(None, buckets.counters().get("api.all.hits"));
+        buckets.reset();
+        assert!(!buckets.all_timers().any(|(key, _)| key == "api.all.time"));
+        assert!(!buckets.all_counters().any(|(key, _)| key == "api.all.hits"));
+    }
+
+    #[test]
+    fn test_process_heavy_hitter_metrics() {
+        let mut buckets = Buckets::new(10., true);
+        buckets.set_heavy_hitters(HeavyHitters::new(1, 1, true));
+        buckets.add(&Metric::new("api.requests", 1.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("api.requests", 1.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("api.errors", 1.0, MetricKind::Counter(1.0)));
+        process(&mut buckets);
+
+        let gauges: HashMap<&String, &f64> = buckets.all_gauges().collect();
+        assert_eq!(Some(&&2.0), gauges.get(&"statsd.top_series.api.requests".to_string()));
+        assert_eq!(Some(&&3.0), gauges.get(&"statsd.top_prefixes.api".to_string()));
+        assert_eq!(None, gauges.get(&"statsd.top_series.api.errors".to_string()));
+        assert!(!buckets.counters().keys().any(|key| key.starts_with("statsd.top_")));
+
+        // Series that dropped out of the top K are not sent again.
+        buckets.reset();
+        assert!(!buckets.all_gauges().any(|(key, _)| key.starts_with("statsd.top_")));
+    }
+
+    #[test]
+    fn test_parallel_timer_data_matches_serial() {
+        let mut timers = HashMap::new();
+        for i in 0..5000 {
+            let values: Vec<f64> = (0..(i % 7 + 1))
+                .map(|s| ((i * 13 + s * 7) % 101) as f64 / 3.0)
+                .collect();
+            timers.insert(format!("timer.{}", i), values);
+        }
+        timers.insert("timer.empty".to_string(), vec![]);
+
+        let serial = summarise_timers(&timers, 10.0, 1);
+        let parallel = summarise_timers(&timers, 10.0, 4);
+        assert_eq!(5000 * TIMER_STATS.len(), serial.len());
+        assert_eq!(serial, parallel);
+    }
 }
@@ -0,0 +1,101 @@
+//! Glob patterns over metric names.
+//!
+//! - `*` matches any run of characters within one name segment.
+//! - `**` matches any run of characters, including `.`.
+//! - `?` matches a single character other than `.`.
+
+/// A compiled metric name glob.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glob {
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(char),
+    Any,
+    Star,
+    DoubleStar,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' => {
+                    if chars.peek() == Some(&'*') {
+                        chars.next();
+                        tokens.push(Token::DoubleStar);
+                    } else {
+                        tokens.push(Token::Star);
+                    }
+                }
+                '?' => tokens.push(Token::Any),
+                c => tokens.push(Token::Literal(c)),
+            }
+        }
+        Glob { tokens: tokens }
+    }
+
+    /// Check whether the whole name matches the pattern.
+    pub fn matches(&self, name: &str) -> bool {
+        let name: Vec<char> = name.chars().collect();
+        match_tokens(&self.tokens, &name)
+    }
+}
+
+fn match_tokens(tokens: &[Token], name: &[char]) -> bool {
+    match tokens.first() {
+        None => name.is_empty(),
+        Some(&Token::Literal(c)) => {
+            !name.is_empty() && name[0] == c && match_tokens(&tokens[1..], &name[1..])
+        }
+        Some(&Token::Any) => {
+            !name.is_empty() && name[0] != '.' && match_tokens(&tokens[1..], &name[1..])
+        }
+        Some(&Token::Star) => {
+            for i in 0..name.len() + 1 {
+                if match_tokens(&tokens[1..], &name[i..]) {
+                    return true;
+                }
+                if i < name.len() && name[i] == '.' {
+                    return false;
+                }
+            }
+            false
+        }
+        Some(&Token::DoubleStar) => {
+            (0..name.len() + 1).any(|i| match_tokens(&tokens[1..], &name[i..]))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        let glob = Glob::new("api.*.requests");
+        assert!(glob.matches("api.web1.requests"));
+        assert!(glob.matches("api..requests"));
+        assert!(!glob.matches("api.web1.eu.requests"));
+        assert!(!glob.matches("api.web1.requests.count"));
+
+        let glob = Glob::new("db.shard*.lag");
+        assert!(glob.matches("db.shard1.lag"));
+        assert!(glob.matches("db.shard.lag"));
+        assert!(!glob.matches("db.replica1.lag"));
+
+        let glob = Glob::new("app.**");
+        assert!(glob.matches("app.web.requests"));
+        assert!(!glob.matches("other.app.web"));
+
+        let glob = Glob::new("host?.cpu");
+        assert!(glob.matches("host1.cpu"));
+        assert!(!glob.matches("host12.cpu"));
+    }
+}
@@ -0,0 +1,614 @@
+//! Construct backends by type name from config sections.
+//!
+//! Every enabled backend is described by a section: its type, a unique
+//! instance name and the options of that type. Sections come from the
+//! `[[backend]]` tables of the config file, and from the command line flags
+//! of the built-in backends. The same type may be configured several times
+//! under different names.
+//!
+//! Applications using this crate as a library can register their own
+//! backend types before running the server:
+//!
+//! ```ignore
+//! let mut registry = statsd::registry::Registry::with_builtins();
+//! registry.register("kafka", |section| {
+//!     let brokers = section.string("brokers", "127.0.0.1:9092")?;
+//!     Ok(Box::new(Kafka::new(&brokers)))
+//! });
+//! statsd::run(registry);
+//! ```
+
+use crate::backend;
+use crate::backend::Backend;
+use crate::backends::archive;
+use crate::backends::console;
+use crate::backends::graphite;
+use crate::backends::influxdb;
+use crate::backends::json_lines;
+use crate::backends::opentsdb;
+use crate::backends::otlp;
+use crate::backends::prometheus;
+use crate::backends::statsd;
+use crate::backends::statsd_zmq;
+use crate::backends::webhook;
+use crate::cli::Args;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use toml;
+
+
+/// The options of every backend that set its route and flush deadline.
+const ROUTE_OPTIONS: [&str; 5] = ["include", "exclude", "types", "interval", "timeout"];
+
+/// The options of one backend instance.
+#[derive(Debug, Clone)]
+pub struct Section {
+    /// The backend type, e.g. `graphite`.
+    pub kind: String,
+    /// The unique instance name, used by routes, status and metrics.
+    pub name: String,
+    /// The server flush interval in seconds.
+    pub flush_interval: u64,
+    values: toml::value::Table,
+    // The options looked up so far, to catch misspelled ones
+    read: RefCell<HashSet<String>>,
+}
+
+impl Section {
+    /// Create an empty section named after its type.
+    pub fn new(kind: &str, flush_interval: u64) -> Section {
+        Section {
+            kind: kind.to_string(),
+            name: kind.to_string(),
+            flush_interval: flush_interval,
+            values: toml::value::Table::new(),
+            read: RefCell::new(HashSet::new()),
+        }
+    }
+
+    /// Create a section from a `[[backend]]` table, which must have a
+    /// `type` and may have a `name`.
+    pub fn from_table(mut values: toml::value::Table, flush_interval: u64) -> Result<Section, String> {
+        let kind = match values.remove("type") {
+            Some(toml::Value::String(kind)) => kind,
+            _ => return Err("Every backend section needs a type".to_string()),
+        };
+        let name = match values.remove("name") {
+            Some(toml::Value::String(name)) => name,
+            None => kind.clone(),
+            Some(_) => return Err(format!("The name of a {} backend must be a string", kind)),
+        };
+        Ok(Section {
+            kind: kind,
+            name: name,
+            flush_interval: flush_interval,
+            values: values,
+            read: RefCell::new(HashSet::new()),
+        })
+    }
+
+    /// Set an option.
+    pub fn set<V: Into<toml::Value>>(&mut self, key: &str, value: V) -> &mut Section {
+        self.values.insert(key.to_string(), value.into());
+        self
+    }
+
+    fn get(&self, key: &str) -> Option<&toml::Value> {
+        self.read.borrow_mut().insert(key.to_string());
+        self.values.get(key)
+    }
+
+    /// The options that were set but never looked up, sorted.
+    pub fn unused(&self) -> Vec<String> {
+        let read = self.read.borrow();
+        let mut unused: Vec<String> = self.values.keys()
+            .filter(|key| !read.contains(*key) && !ROUTE_OPTIONS.contains(&key.as_str()))
+            .cloned()
+            .collect();
+        unused.sort();
+        unused
+    }
+
+    fn invalid(&self, key: &str, expected: &str) -> String {
+        format!("Option `{}` of backend {} must be {}", key, self.name, expected)
+    }
+
+    pub fn string(&self, key: &str, default: &str) -> Result<String, String> {
+        match self.get(key) {
+            None => Ok(default.to_string()),
+            Some(toml::Value::String(value)) => Ok(value.clone()),
+            Some(_) => Err(self.invalid(key, "a string")),
+        }
+    }
+
+    pub fn boolean(&self, key: &str, default: bool) -> Result<bool, String> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(&toml::Value::Boolean(value)) => Ok(value),
+            Some(_) => Err(self.invalid(key, "true or false")),
+        }
+    }
+
+    pub fn unsigned(&self, key: &str, default: u64) -> Result<u64, String> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(&toml::Value::Integer(value)) if value >= 0 => Ok(value as u64),
+            Some(_) => Err(self.invalid(key, "a positive integer")),
+        }
+    }
+
+    pub fn port(&self, key: &str, default: u16) -> Result<u16, String> {
+        let port = self.unsigned(key, u64::from(default))?;
+        if port > u64::from(u16::MAX) {
+            return Err(self.invalid(key, "a port number"));
+        }
+        Ok(port as u16)
+    }
+
+    /// An optional number, integers are accepted as well.
+    pub fn float(&self, key: &str) -> Result<Option<f64>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(&toml::Value::Float(value)) => Ok(Some(value)),
+            Some(&toml::Value::Integer(value)) => Ok(Some(value as f64)),
+            Some(_) => Err(self.invalid(key, "a number")),
+        }
+    }
+
+    /// A list given as an array of strings or a string separated by comma.
+    pub fn list(&self, key: &str) -> Result<Vec<String>, String> {
+        let values = match self.get(key) {
+            None => return Ok(Vec::new()),
+            Some(toml::Value::String(value)) => value.split(',').map(|v| v.to_string()).collect(),
+            Some(toml::Value::Array(values)) => {
+                values.iter()
+                    .map(|value| value.as_str().map(|v| v.to_string()).ok_or_else(|| self.invalid(key, "a list of strings")))
+                    .collect::<Result<Vec<String>, String>>()?
+            }
+            Some(_) => return Err(self.invalid(key, "a list of strings")),
+        };
+        Ok(values.into_iter()
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .collect())
+    }
+}
+
+
+/// Builds a backend from its section.
+pub type Constructor = Box<dyn Fn(&Section) -> Result<Box<dyn Backend>, String>>;
+
+/// The backend types that can be configured, by type name.
+pub struct Registry {
+    constructors: HashMap<String, Constructor>,
+}
+
+impl Registry {
+    /// A registry without any backend types.
+    pub fn new() -> Registry {
+        Registry { constructors: HashMap::new() }
+    }
+
+    /// A registry with the backends built into the server.
+    pub fn with_builtins() -> Registry {
+        let mut registry = Registry::new();
+        registry.register("console", build_console);
+        registry.register("graphite", build_graphite);
+        registry.register("statsd", build_statsd);
+        registry.register("statsd_zmq", build_statsd_zmq);
+        registry.register("prometheus", build_prometheus);
+        registry.register("influxdb", build_influxdb);
+        registry.register("opentsdb", build_opentsdb);
+        registry.register("otlp", build_otlp);
+        registry.register("json_lines", build_json_lines);
+        registry.register("archive", build_archive);
+        registry.register("webhook", build_webhook);
+        registry
+    }
+
+    /// Register a backend type, replacing any type of the same name.
+    pub fn register<F>(&mut self, kind: &str, constructor: F)
+        where F: Fn(&Section) -> Result<Box<dyn Backend>, String> + 'static
+    {
+        self.constructors.insert(kind.to_string(), Box::new(constructor));
+    }
+
+    /// Construct the backend a section describes.
+    pub fn build(&self, section: &Section) -> Result<Box<dyn Backend>, String> {
+        let constructor = self.constructors.get(&section.kind)
+            .ok_or_else(|| format!("Unknown backend type `{}` for {}", section.kind, section.name))?;
+        let backend = constructor(section).map_err(|e| format!("Invalid {} backend: {}", section.name, e))?;
+        let unused = section.unused();
+        if !unused.is_empty() {
+            return Err(format!("Unknown options for {} backend {}: {}", section.kind, section.name, unused.join(", ")));
+        }
+        Ok(backend)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::with_builtins()
+    }
+}
+
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    backend: Vec<toml::value::Table>,
+}
+
+/// Read the `[[backend]]` sections of a TOML config file.
+pub fn load_config(path: &str, flush_interval: u64) -> Result<Vec<Section>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    parse_config(&text, flush_interval)
+}
+
+fn parse_config(text: &str, flush_interval: u64) -> Result<Vec<Section>, String> {
+    let config: ConfigFile = toml::from_str(text).map_err(|e| e.to_string())?;
+    config.backend
+        .into_iter()
+        .map(|table| Section::from_table(table, flush_interval))
+        .collect()
+}
+
+/// The sections of the built-in backends enabled by command line flags.
+pub(crate) fn cli_sections(args: &Args) -> Vec<Section> {
+    let flush_interval = args.flag_flush_interval;
+    let mut sections = Vec::new();
+    if args.flag_console {
+        let mut section = Section::new("console", flush_interval);
+        section.set("format", args.flag_console_format.as_str())
+            .set("filter", args.flag_console_filter.as_str())
+            .set("changed", args.flag_console_changed);
+        sections.push(section);
+    }
+    if args.flag_graphite {
+        let mut section = Section::new("graphite", flush_interval);
+        section.set("prefix", args.flag_graphite_prefix.as_str())
+            .set("prefix_counter", args.flag_graphite_prefix_counter.as_str())
+            .set("prefix_gauge", args.flag_graphite_prefix_gauge.as_str())
+            .set("prefix_timer", args.flag_graphite_prefix_timer.as_str())
+            .set("host", args.flag_graphite_host.as_str())
+            .set("port", i64::from(args.flag_graphite_port))
+            .set("protocol", args.flag_graphite_protocol.as_str())
+            .set("pickle_port", i64::from(args.flag_graphite_pickle_port))
+            .set("pickle_batch_size", args.flag_graphite_pickle_batch_size as i64)
+            .set("destinations", args.flag_graphite_destinations.as_str())
+            .set("routing", args.flag_graphite_routing.as_str())
+            .set("replication_factor", args.flag_graphite_replication_factor as i64)
+            .set("diverse_replicas", args.flag_graphite_diverse_replicas)
+            .set("tagged", args.flag_graphite_tagged)
+            .set("tag_patterns", args.flag_graphite_tag_patterns.as_str())
+            .set("queue_size", args.flag_graphite_queue_size as i64)
+            .set("spill_dir", args.flag_graphite_spill_dir.as_str())
+            .set("spill_limit", args.flag_graphite_spill_limit as i64);
+        sections.push(section);
+    }
+    if args.flag_statsd {
+        let mut hosts = "".to_string();
+        let stats_deprecated_host = format!("{}:{},", args.flag_statsd_host, args.flag_statsd_port);
+        if stats_deprecated_host != "127.0.0.1:0," {
+            eprintln!("--statsd-host and --statsd-port flags are deprecated, please use --statsd-hosts instead.");
+            hosts += &stats_deprecated_host;
+        }
+        hosts += &args.flag_statsd_hosts;
+        let mut section = Section::new("statsd", flush_interval);
+        section.set("hosts", hosts)
+            .set("packet_size", args.flag_statsd_packet_size as i64)
+            .set("shard", args.flag_statsd_shard)
+            .set("multi_value", args.flag_statsd_multi_value)
+            .set("protocol", args.flag_statsd_protocol.as_str())
+            .set("queue_size", args.flag_statsd_queue_size as i64);
+        sections.push(section);
+    }
+    if args.flag_statsd_zmq {
+        let mut section = Section::new("statsd_zmq", flush_interval);
+        section.set("hosts", args.flag_statsd_zmq_hosts.as_str());
+        sections.push(section);
+    }
+    if args.flag_prometheus {
+        let mut section = Section::new("prometheus", flush_interval);
+        section.set("host", args.flag_prometheus_host.as_str())
+            .set("port", i64::from(args.flag_prometheus_port));
+        sections.push(section);
+    }
+    if args.flag_influxdb {
+        let mut section = Section::new("influxdb", flush_interval);
+        section.set("url", args.flag_influxdb_url.as_str());
+        sections.push(section);
+    }
+    if args.flag_opentsdb {
+        let mut section = Section::new("opentsdb", flush_interval);
+        section.set("host", args.flag_opentsdb_host.as_str())
+            .set("port", i64::from(args.flag_opentsdb_port))
+            .set("prefix", args.flag_opentsdb_prefix.as_str())
+            .set("tags", args.flag_opentsdb_tags.as_str());
+        sections.push(section);
+    }
+    if args.flag_otlp {
+        let mut section = Section::new("otlp", flush_interval);
+        section.set("url", args.flag_otlp_url.as_str())
+            .set("resource", args.flag_otlp_resource.as_str());
+        sections.push(section);
+    }
+    if args.flag_json_lines {
+        let mut section = Section::new("json_lines", flush_interval);
+        section.set("output", args.flag_json_lines_output.as_str())
+            .set("per_flush", args.flag_json_lines_per_flush)
+            .set("rotate_size", args.flag_json_lines_rotate_size as i64)
+            .set("rotate_keep", args.flag_json_lines_rotate_keep as i64);
+        sections.push(section);
+    }
+    if args.flag_archive {
+        let mut section = Section::new("archive", flush_interval);
+        section.set("dir", args.flag_archive_dir.as_str())
+            .set("retention", args.flag_archive_retention as i64);
+        sections.push(section);
+    }
+    if args.flag_webhook {
+        let mut section = Section::new("webhook", flush_interval);
+        section.set("urls", args.flag_webhook_urls.as_str())
+            .set("headers", args.flag_webhook_headers.as_str())
+            .set("gzip", args.flag_webhook_gzip)
+            .set("batch_size", args.flag_webhook_batch_size as i64)
+            .set("retries", args.flag_webhook_retries as i64)
+            .set("backoff", args.flag_webhook_backoff as i64)
+            .set("request_timeout", args.flag_webhook_request_timeout);
+        sections.push(section);
+    }
+    sections
+}
+
+
+fn build_console(section: &Section) -> Result<Box<dyn Backend>, String> {
+    let format = console::Format::parse(&section.string("format", "table")?)?;
+    let filter = console::Filter::parse(&section.string("filter", "")?)?;
+    Ok(Box::new(console::Console::new(format, filter, section.boolean("changed", false)?)))
+}
+
+fn build_graphite(section: &Section) -> Result<Box<dyn Backend>, String> {
+    let mut backend = graphite::Graphite::new(
+        &section.string("host", "127.0.0.1")?, section.port("port", 2003)?,
+        &section.string("prefix", "stats")?, &section.string("prefix_counter", "counters")?,
+        &section.string("prefix_gauge", "gauges")?, &section.string("prefix_timer", "timers")?,
+        section.flush_interval as i32
+    )?;
+    let pickle_port = section.port("pickle_port", 2004)?;
+    let pickle_batch_size = section.unsigned("pickle_batch_size", 500)? as usize;
+    match section.string("protocol", "plaintext")?.as_str() {
+        "plaintext" => {}
+        "pickle" => backend.use_pickle(pickle_port, pickle_batch_size),
+        x => return Err(format!("Unknown protocol `{}`, use plaintext or pickle", x)),
+    }
+    let destinations = section.list("destinations")?;
+    if !destinations.is_empty() {
+        backend.use_destinations(&destinations.join(","))?;
+    }
+    let replication_factor = section.unsigned("replication_factor", 1)? as usize;
+    let diverse_replicas = section.boolean("diverse_replicas", false)?;
+    match section.string("routing", "all")?.as_str() {
+        "all" => {}
+        "consistent-hashing" => backend.use_consistent_hashing(replication_factor, diverse_replicas),
+        x => return Err(format!("Unknown routing `{}`, use all or consistent-hashing", x)),
+    }
+    let tag_patterns = section.list("tag_patterns")?;
+    if section.boolean("tagged", false)? {
+        backend.use_tags(graphite::TagPattern::parse_list(&tag_patterns.join(","))?);
+    }
+    let spill_dir = section.string("spill_dir", "")?;
+    let spill_path = if spill_dir.is_empty() { None } else { Some(PathBuf::from(&spill_dir)) };
+    backend.set_retry_queue(&section.name,
+                            section.unsigned("queue_size", 30)? as usize,
+                            spill_path,
+                            section.unsigned("spill_limit", 8640)? as usize)
+        .map_err(|e| format!("Cannot use spill directory `{}`: {:?}", spill_dir, e))?;
+    Ok(Box::new(backend))
+}
+
+/// Sorted, deduplicated hosts.
+fn hosts(section: &Section, default: &str) -> Result<Vec<String>, String> {
+    let mut hosts = section.list("hosts")?;
+    if hosts.is_empty() {
+        hosts.push(default.to_string());
+    }
+    hosts.sort();
+    hosts.dedup();
+    Ok(hosts)
+}
+
+fn build_statsd(section: &Section) -> Result<Box<dyn Backend>, String> {
+    let hosts = hosts(section, "127.0.0.1:8125")?;
+    let packet_size = section.unsigned("packet_size", 16384)? as usize;
+    let queue_size = section.unsigned("queue_size", 1000)? as usize;
+    let mut backend = match section.string("protocol", "udp")?.as_str() {
+        "udp" => statsd::Statsd::new(hosts, packet_size),
+        "tcp" => statsd::Statsd::new_tcp(hosts, packet_size, queue_size)?,
+        x => return Err(format!("Unknown protocol `{}`, use udp or tcp", x)),
+    };
+    if section.boolean("shard", false)? {
+        backend.use_sharding();
+    }
+    if section.boolean("multi_value", false)? {
+        backend.use_multi_value();
+    }
+    Ok(Box::new(backend))
+}
+
+fn build_statsd_zmq(section: &Section) -> Result<Box<dyn Backend>, String> {
+    Ok(Box::new(statsd_zmq::StatsdZmq::new(hosts(section, "tcp://127.0.0.1:8130")?)))
+}
+
+fn build_prometheus(section: &Section) -> Result<Box<dyn Backend>, String> {
+    Ok(Box::new(prometheus::Prometheus::new(&section.string("host", "0.0.0.0")?,
+                                            section.port("port", 9102)?)))
+}
+
+fn build_influxdb(section: &Section) -> Result<Box<dyn Backend>, String> {
+    let url = section.string("url", "http://127.0.0.1:8086/write?db=statsd")?;
+    Ok(Box::new(influxdb::InfluxDb::new(&url)?))
+}
+
+fn build_opentsdb(section: &Section) -> Result<Box<dyn Backend>, String> {
+    let tags = opentsdb::parse_tags(&section.list("tags")?.join(","))?;
+    Ok(Box::new(opentsdb::OpenTsdb::new(
+        &section.string("host", "127.0.0.1")?, section.port("port", 4242)?,
+        &section.string("prefix", "stats")?, tags, section.flush_interval as i32
+    )?))
+}
+
+fn build_otlp(section: &Section) -> Result<Box<dyn Backend>, String> {
+    let resource = otlp::parse_attributes(&section.list("resource")?.join(","))?;
+    let url = section.string("url", "http://127.0.0.1:4318/v1/metrics")?;
+    Ok(Box::new(otlp::Otlp::new(&url, resource)?))
+}
+
+fn build_json_lines(section: &Section) -> Result<Box<dyn Backend>, String> {
+    let output = json_lines::Output::new(&section.string("output", "-")?,
+                                         section.unsigned("rotate_size", 0)?,
+                                         section.unsigned("rotate_keep", 5)? as usize);
+    Ok(Box::new(json_lines::JsonLines::new(output, section.boolean("per_flush", false)?)))
+}
+
+fn build_archive(section: &Section) -> Result<Box<dyn Backend>, String> {
+    Ok(Box::new(archive::Archive::new(PathBuf::from(section.string("dir", "archive")?),
+                                      section.unsigned("retention", 168)?)))
+}
+
+fn build_webhook(section: &Section) -> Result<Box<dyn Backend>, String> {
+    let mut urls = section.list("urls")?;
+    if urls.is_empty() {
+        urls.push("http://127.0.0.1:8080/statsd".to_string());
+    }
+    let mut backend = webhook::Webhook::new(&urls)?;
+    backend.set_headers(webhook::parse_headers(&section.list("headers")?)?);
+    if section.boolean("gzip", false)? {
+        backend.use_gzip();
+    }
+    backend.set_batch_size(section.unsigned("batch_size", 1048576)? as usize);
+    backend.set_retries(section.unsigned("retries", 3)? as usize,
+                        Duration::from_millis(section.unsigned("backoff", 500)?));
+    backend.set_timeout(backend::timeout(&section.name, section.float("request_timeout")?.unwrap_or(5.0))?);
+    Ok(Box::new(backend))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buckets::Buckets;
+
+    #[test]
+    fn builds_builtins() {
+        let registry = Registry::with_builtins();
+        for kind in ["console", "graphite", "statsd", "prometheus", "influxdb",
+                     "opentsdb", "otlp", "json_lines", "archive", "webhook"].iter() {
+            let backend = registry.build(&Section::new(kind, 10)).unwrap();
+            assert_eq!(*kind, backend.name());
+        }
+        assert!(registry.build(&Section::new("carrier_pigeon", 10)).is_err());
+    }
+
+    #[test]
+    fn invalid_options() {
+        let registry = Registry::with_builtins();
+        let mut section = Section::new("graphite", 10);
+        section.set("protocol", "morse");
+        assert!(registry.build(&section).is_err());
+
+        let mut section = Section::new("graphite", 10);
+        section.set("destinations", "127.0.0.1:2004:a,127.0.0.1").set("routing", "consistent-hashing");
+        assert!(registry.build(&section).is_err());
+        section.set("destinations", vec!["127.0.0.1:2004:a", "127.0.0.1:2104:b"]);
+        assert!(registry.build(&section).is_ok());
+        section.set("routing", "random");
+        assert!(registry.build(&section).is_err());
+
+        let mut section = Section::new("console", 10);
+        section.set("changed", "yes");
+        assert_eq!("Invalid console backend: Option `changed` of backend console must be true or false",
+                   registry.build(&section).err().unwrap());
+    }
+
+    #[test]
+    fn parses_config_sections() {
+        let sections = parse_config(r#"
+            [[backend]]
+            type = "graphite"
+            name = "graphite-eu"
+            host = "127.0.0.2"
+            prefix = "stats.eu"
+            include = ["api.**", "db.**"]
+
+            [[backend]]
+            type = "graphite"
+            name = "graphite-us"
+            host = "127.0.0.3"
+            include = "api.**"
+        "#, 10).unwrap();
+        assert_eq!(2, sections.len());
+        assert_eq!("graphite", sections[0].kind);
+        assert_eq!("graphite-eu", sections[0].name);
+        assert_eq!("stats.eu", sections[0].string("prefix", "stats").unwrap());
+        assert_eq!("stats", sections[1].string("prefix", "stats").unwrap());
+        assert_eq!(vec!["api.**", "db.**"], sections[0].list("include").unwrap());
+        assert_eq!(vec!["api.**"], sections[1].list("include").unwrap());
+
+        let registry = Registry::with_builtins();
+        for section in sections.iter() {
+            assert!(registry.build(section).is_ok());
+        }
+
+        assert!(parse_config("[[backend]]\nhost = \"x\"\n", 10).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        let registry = Registry::with_builtins();
+        let sections = parse_config(r#"
+            [[backend]]
+            type = "graphite"
+            hots = "127.0.0.2"
+            prefx = "stats.eu"
+            interval = 60
+            timeout = 2.5
+        "#, 10).unwrap();
+        assert_eq!("Unknown options for graphite backend graphite: hots, prefx",
+                   registry.build(&sections[0]).err().unwrap());
+
+        let mut section = Section::new("graphite", 10);
+        section.set("pickle_port", 2104).set("include", "api.**");
+        assert!(registry.build(&section).is_ok());
+    }
+
+    struct Custom;
+
+    impl Backend for Custom {
+        fn name(&self) -> &str {
+            "custom"
+        }
+
+        fn flush_buckets(&mut self, _buckets: &Buckets) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn registers_custom_backends() {
+        let mut registry = Registry::new();
+        registry.register("custom", |section| {
+            if section.boolean("broken", false)? {
+                return Err("broken".to_string());
+            }
+            Ok(Box::new(Custom))
+        });
+        assert_eq!("custom", registry.build(&Section::new("custom", 10)).unwrap().name());
+        let mut section = Section::new("custom", 10);
+        section.set("broken", true);
+        assert!(registry.build(&section).is_err());
+    }
+}
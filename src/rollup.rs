@@ -0,0 +1,201 @@
+//! Roll-up rules aggregate many series into a single series at flush time.
+//!
+//! A rule such as `sum:api.*.requests=api.all.requests` adds every counter
+//! and gauge matching the pattern into the target. Timer samples of all
+//! matching timers are always merged, so the derived timer data of the
+//! target covers every sample.
+
+use super::buckets::Buckets;
+use super::pattern::Glob;
+
+/// How the values of the matched series are combined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RollupFunction {
+    Sum,
+    Min,
+    Max,
+    Average,
+}
+
+impl RollupFunction {
+    pub fn parse(name: &str) -> Result<RollupFunction, String> {
+        match name {
+            "sum" => Ok(RollupFunction::Sum),
+            "min" => Ok(RollupFunction::Min),
+            "max" => Ok(RollupFunction::Max),
+            "avg" | "average" => Ok(RollupFunction::Average),
+            x => Err(format!("Unknown roll-up function `{}`", x)),
+        }
+    }
+
+    fn aggregate<'a, I: Iterator<Item = &'a f64>>(&self, values: I) -> Option<f64> {
+        let mut count = 0;
+        let mut result = 0.0;
+        for value in values {
+            result = match *self {
+                _ if count == 0 => *value,
+                RollupFunction::Sum | RollupFunction::Average => result + value,
+                RollupFunction::Min => result.min(*value),
+                RollupFunction::Max => result.max(*value),
+            };
+            count += 1;
+        }
+        match (count, *self) {
+            (0, _) => None,
+            (_, RollupFunction::Average) => Some(result / count as f64),
+            _ => Some(result),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct RollupRule {
+    pub function: RollupFunction,
+    pub pattern: Glob,
+    pub target: String,
+}
+
+
+/// A value produced by evaluating the roll-up rules.
+#[derive(Debug, PartialEq)]
+pub enum Rolled {
+    Counter(String, f64),
+    Gauge(String, f64),
+    Timer(String, Vec<f64>),
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Rollups {
+    rules: Vec<RollupRule>,
+}
+
+impl Rollups {
+    pub fn new() -> Rollups {
+        Rollups { rules: Vec::new() }
+    }
+
+    /// Parse a comma separated list of `function:pattern=target` rules.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let rollups = Rollups::parse("sum:api.*.requests=api.all.requests").unwrap();
+    /// ```
+    pub fn parse(spec: &str) -> Result<Rollups, String> {
+        let mut rollups = Rollups::new();
+        for rule in spec.split(',').map(|rule| rule.trim()).filter(|rule| !rule.is_empty()) {
+            let invalid = || format!("Roll-up rule `{}` must be function:pattern=target", rule);
+            let colon = rule.find(':').ok_or_else(invalid)?;
+            let equals = rule.rfind('=').ok_or_else(invalid)?;
+            if equals < colon {
+                return Err(invalid());
+            }
+            let pattern = rule[colon + 1..equals].trim();
+            let target = rule[equals + 1..].trim();
+            if pattern.is_empty() || target.is_empty() {
+                return Err(invalid());
+            }
+            rollups.rules.push(RollupRule {
+                function: RollupFunction::parse(rule[..colon].trim())?,
+                pattern: Glob::new(pattern),
+                target: target.to_string(),
+            });
+        }
+        Ok(rollups)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Evaluate every rule against the buckets.
+    ///
+    /// Targets of any rule are never used as inputs, so a target matching
+    /// its own pattern does not feed back into the next flush.
+    pub fn evaluate(&self, buckets: &Buckets) -> Vec<Rolled> {
+        let mut rolled = Vec::new();
+        let is_source = |rule: &RollupRule, key: &str| {
+            rule.pattern.matches(key) && !self.rules.iter().any(|r| r.target == key)
+        };
+
+        for rule in self.rules.iter() {
+            let counter = rule.function.aggregate(
+                buckets.counters().iter()
+                    .filter(|&(key, _)| is_source(rule, key))
+                    .map(|(_, value)| value));
+            if let Some(value) = counter {
+                rolled.push(Rolled::Counter(rule.target.clone(), value));
+            }
+
+            let gauge = rule.function.aggregate(
+                buckets.gauges().iter()
+                    .filter(|&(key, _)| is_source(rule, key))
+                    .map(|(_, value)| value));
+            if let Some(value) = gauge {
+                rolled.push(Rolled::Gauge(rule.target.clone(), value));
+            }
+
+            let mut timers: Vec<(&String, &Vec<f64>)> = buckets.timers().iter()
+                .filter(|&(key, _)| is_source(rule, key))
+                .collect();
+            if !timers.is_empty() {
+                timers.sort_by(|a, b| a.0.cmp(b.0));
+                let samples = timers.iter().flat_map(|&(_, values)| values.iter().cloned()).collect();
+                rolled.push(Rolled::Timer(rule.target.clone(), samples));
+            }
+        }
+        rolled
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::metric::{Metric, MetricKind};
+
+    #[test]
+    fn test_parse_rules() {
+        let rollups = Rollups::parse("sum:api.*.requests=api.all.requests, max:db.shard*.lag=db.lag").unwrap();
+        assert_eq!(2, rollups.rules.len());
+        assert_eq!(RollupFunction::Max, rollups.rules[1].function);
+        assert_eq!(Glob::new("db.shard*.lag"), rollups.rules[1].pattern);
+        assert_eq!("db.lag", rollups.rules[1].target);
+
+        assert!(Rollups::parse("").unwrap().is_empty());
+        assert!(Rollups::parse("median:a.*=b").is_err());
+        assert!(Rollups::parse("sum:a.*").is_err());
+        assert!(Rollups::parse("sum:=b").is_err());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut buckets = Buckets::new(10., false);
+        buckets.add(&Metric::new("api.web1.requests", 3.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("api.web2.requests", 4.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("api.all.requests", 100.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("db.shard1.lag", 7.0, MetricKind::Gauge));
+        buckets.add(&Metric::new("db.shard2.lag", 2.0, MetricKind::Gauge));
+        buckets.add(&Metric::new("api.web1.requests", 12.0, MetricKind::Timer));
+        buckets.add(&Metric::new("api.web2.requests", 10.0, MetricKind::Timer));
+
+        let rollups = Rollups::parse("sum:api.*.requests=api.all.requests,max:db.shard*.lag=db.lag").unwrap();
+        let rolled = rollups.evaluate(&buckets);
+        assert_eq!(3, rolled.len());
+        assert!(rolled.contains(&Rolled::Counter("api.all.requests".to_string(), 7.0)));
+        assert!(rolled.contains(&Rolled::Timer("api.all.requests".to_string(), vec![12.0, 10.0])));
+        assert!(rolled.contains(&Rolled::Gauge("db.lag".to_string(), 7.0)));
+    }
+
+    #[test]
+    fn test_aggregate_functions() {
+        let values = [4.0, 1.0, 7.0];
+        assert_eq!(Some(12.0), RollupFunction::Sum.aggregate(values.iter()));
+        assert_eq!(Some(1.0), RollupFunction::Min.aggregate(values.iter()));
+        assert_eq!(Some(7.0), RollupFunction::Max.aggregate(values.iter()));
+        assert_eq!(Some(4.0), RollupFunction::Average.aggregate(values.iter()));
+        assert_eq!(None, RollupFunction::Sum.aggregate([].iter()));
+    }
+}
@@ -0,0 +1,315 @@
+//! Per-backend routing of flushed series.
+//!
+//! A route selects the series a backend receives by name and metric type,
+//! and can flush the backend less often than the server does. Slower
+//! flushes aggregate the base flushes in between: counters are summed,
+//! timer samples are merged and gauges keep their latest value.
+
+use crate::buckets::Buckets;
+use crate::metric::split_tags;
+use crate::metric_processor;
+use crate::pattern::Glob;
+use crate::registry::Section;
+use std::collections::HashMap;
+
+/// The series a backend receives and how often.
+#[derive(Debug, Clone, Default)]
+pub struct Route {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+    // Metric types as c, g or ms, empty for all
+    types: Vec<String>,
+    // Flush every this many base flushes
+    every: usize,
+}
+
+impl Route {
+    /// Whether the route sends everything on every flush.
+    pub fn is_passthrough(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty() && self.types.is_empty() && self.every <= 1
+    }
+
+    fn accepts(&self, key: &str, kind: &str) -> bool {
+        let (name, _) = split_tags(key);
+        (self.types.is_empty() || self.types.iter().any(|t| t == kind)) &&
+            (self.include.is_empty() || self.include.iter().any(|glob| glob.matches(name))) &&
+            !self.exclude.iter().any(|glob| glob.matches(name))
+    }
+}
+
+
+/// The routes of every backend by backend name.
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    routes: HashMap<String, Route>,
+}
+
+/// Split `backend=value` pairs separated by comma.
+pub fn pairs<'a>(spec: &'a str, flag: &str) -> Result<Vec<(&'a str, &'a str)>, String> {
+    spec.split(',')
+        .map(|pair| pair.trim())
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(backend), Some(value)) if !backend.is_empty() && !value.is_empty() => {
+                    Ok((backend.trim(), value.trim()))
+                }
+                _ => Err(format!("{} `{}` must be backend=value", flag, pair)),
+            }
+        })
+        .collect()
+}
+
+impl Routes {
+    pub fn new() -> Routes {
+        Routes { routes: HashMap::new() }
+    }
+
+    /// Parse the routes from `backend=value` pairs separated by comma.
+    ///
+    /// A backend may be given several times to add more patterns or types.
+    /// Intervals are in seconds and must be a multiple of the flush interval.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let routes = Routes::parse("graphite=api.**", "graphite=api.debug.**", "influxdb=ms",
+    ///                            "graphite=60", 10).unwrap();
+    /// ```
+    pub fn parse(include: &str,
+                 exclude: &str,
+                 types: &str,
+                 intervals: &str,
+                 flush_interval: u64)
+                 -> Result<Routes, String> {
+        let mut routes = Routes::new();
+        for (backend, pattern) in pairs(include, "--backend-include")? {
+            routes.entry(backend).include.push(Glob::new(pattern));
+        }
+        for (backend, pattern) in pairs(exclude, "--backend-exclude")? {
+            routes.entry(backend).exclude.push(Glob::new(pattern));
+        }
+        for (backend, kind) in pairs(types, "--backend-types")? {
+            routes.add_type(backend, kind)?;
+        }
+        for (backend, seconds) in pairs(intervals, "--backend-intervals")? {
+            let seconds: u64 = seconds.parse()
+                .map_err(|_| format!("Invalid interval `{}` for {}", seconds, backend))?;
+            routes.set_interval(backend, seconds, flush_interval)?;
+        }
+        Ok(routes)
+    }
+
+    /// Add the `include`, `exclude`, `types` and `interval` options of a
+    /// backend section.
+    pub fn add_section(&mut self, section: &Section) -> Result<(), String> {
+        for pattern in section.list("include")? {
+            self.entry(&section.name).include.push(Glob::new(&pattern));
+        }
+        for pattern in section.list("exclude")? {
+            self.entry(&section.name).exclude.push(Glob::new(&pattern));
+        }
+        for kind in section.list("types")? {
+            self.add_type(&section.name, &kind)?;
+        }
+        let interval = section.unsigned("interval", 0)?;
+        if interval > 0 {
+            self.set_interval(&section.name, interval, section.flush_interval)?;
+        }
+        Ok(())
+    }
+
+    fn add_type(&mut self, backend: &str, kind: &str) -> Result<(), String> {
+        match kind {
+            "c" | "g" | "ms" => {
+                self.entry(backend).types.push(kind.to_string());
+                Ok(())
+            }
+            x => Err(format!("Unknown metric type `{}` for {}, use c, g or ms", x, backend)),
+        }
+    }
+
+    fn set_interval(&mut self, backend: &str, seconds: u64, flush_interval: u64) -> Result<(), String> {
+        if seconds == 0 || flush_interval == 0 || seconds % flush_interval != 0 {
+            return Err(format!("The interval of {} must be a multiple of the flush interval of {} seconds",
+                               backend, flush_interval));
+        }
+        self.entry(backend).every = (seconds / flush_interval) as usize;
+        Ok(())
+    }
+
+    fn entry(&mut self, backend: &str) -> &mut Route {
+        self.routes.entry(backend.to_string()).or_default()
+    }
+
+    /// The route of a backend, everything on every flush when not configured.
+    pub fn get(&self, backend: &str) -> Route {
+        self.routes.get(backend).cloned().unwrap_or_default()
+    }
+
+    /// The backends routes are configured for.
+    pub fn backends(&self) -> Vec<&str> {
+        self.routes.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+
+/// Applies a route to the flushes of one backend.
+pub struct Router {
+    route: Route,
+    pending: Option<Buckets>,
+    flushes: usize,
+}
+
+impl Router {
+    pub fn new(route: Route) -> Router {
+        Router {
+            route: route,
+            pending: None,
+            flushes: 0,
+        }
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.route.is_passthrough()
+    }
+
+    /// The buckets to send to the backend for this flush, `None` while a
+    /// slower interval is still aggregating.
+    pub fn route(&mut self, buckets: &Buckets) -> Option<Buckets> {
+        let mut selected = buckets.clone();
+        let route = &self.route;
+        selected.retain(|key, kind| route.accepts(key, kind));
+        if self.route.every <= 1 {
+            return Some(selected);
+        }
+
+        self.flushes += 1;
+        match self.pending {
+            Some(ref mut pending) => pending.merge(&selected),
+            None => self.pending = Some(selected),
+        }
+        if self.flushes < self.route.every {
+            return None;
+        }
+        self.drain()
+    }
+
+    /// Take what was aggregated so far, with timer data and the other
+    /// derived series computed over the whole slower interval.
+    pub fn drain(&mut self) -> Option<Buckets> {
+        self.flushes = 0;
+        let mut pending = self.pending.take()?;
+        pending.set_flush_interval(pending.flush_interval() * self.route.every as f64);
+        metric_processor::process(&mut pending);
+        let route = &self.route;
+        pending.retain(|key, kind| route.accepts(key, kind));
+        Some(pending)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metric::{Metric, MetricKind};
+    use crate::rollup::Rollups;
+
+    fn make_buckets() -> Buckets {
+        let mut buckets = Buckets::new(10., false);
+        buckets.add(&Metric::new("api.requests", 2.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("api.debug.requests", 1.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("api.queue", 4.0, MetricKind::Gauge));
+        buckets.add(&Metric::new("api.latency;host=web1", 10.0, MetricKind::Timer));
+        buckets.add(&Metric::new("db.latency", 20.0, MetricKind::Timer));
+        buckets.process();
+        buckets
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Routes::parse("graphite", "", "", "", 10).is_err());
+        assert!(Routes::parse("", "", "influxdb=timer", "", 10).is_err());
+        assert!(Routes::parse("", "", "", "graphite=15", 10).is_err());
+        assert!(Routes::parse("", "", "", "graphite=60", 10).is_ok());
+    }
+
+    #[test]
+    fn test_section_routes() {
+        let mut section = Section::new("graphite", 10);
+        section.set("include", "api.**").set("types", vec!["c", "g"]).set("interval", 60);
+        let mut routes = Routes::new();
+        routes.add_section(&section).unwrap();
+
+        let flushed = Router::new(routes.get("graphite")).route(&make_buckets());
+        assert!(flushed.is_none());
+        let route = routes.get("graphite");
+        assert!(route.accepts("api.requests", "c"));
+        assert!(!route.accepts("api.latency", "ms"));
+        assert!(!route.accepts("db.queries", "c"));
+
+        section.set("interval", 15);
+        assert!(Routes::new().add_section(&section).is_err());
+    }
+
+    #[test]
+    fn test_filters_by_name_and_type() {
+        let routes = Routes::parse("graphite=api.**", "graphite=api.debug.**", "influxdb=ms", "", 10).unwrap();
+        assert!(routes.get("console").is_passthrough());
+
+        let buckets = make_buckets();
+        let graphite = Router::new(routes.get("graphite")).route(&buckets).unwrap();
+        assert_eq!(vec!["api.requests"], graphite.counters().keys().collect::<Vec<_>>());
+        assert!(graphite.gauges().contains_key("api.queue"));
+        assert!(graphite.timers().contains_key("api.latency;host=web1"));
+        assert!(graphite.timer_data().contains_key("api.latency.count;host=web1"));
+        assert!(!graphite.timer_data().contains_key("db.latency.count"));
+
+        let influxdb = Router::new(routes.get("influxdb")).route(&buckets).unwrap();
+        assert!(influxdb.counters().is_empty());
+        assert!(influxdb.gauges().is_empty());
+        assert_eq!(2, influxdb.timers().len());
+    }
+
+    #[test]
+    fn test_slower_interval_aggregates() {
+        let routes = Routes::parse("", "", "", "graphite=30", 10).unwrap();
+        let mut router = Router::new(routes.get("graphite"));
+        let mut buckets = make_buckets();
+        assert!(router.route(&buckets).is_none());
+
+        buckets.add(&Metric::new("api.queue", 6.0, MetricKind::Gauge));
+        assert!(router.route(&buckets).is_none());
+
+        buckets.add(&Metric::new("db.latency", 40.0, MetricKind::Timer));
+        let flushed = router.route(&buckets).unwrap();
+        assert_eq!(30.0, flushed.flush_interval());
+        assert_eq!(Some(&6.0), flushed.counters().get("api.requests"));
+        assert_eq!(Some(&6.0), flushed.gauges().get("api.queue"));
+        assert_eq!(Some(&vec![20.0, 20.0, 20.0, 40.0]), flushed.timers().get("db.latency"));
+        assert_eq!(Some(&40.0), flushed.timer_data().get("db.latency.max"));
+        assert_eq!(Some(&4.0), flushed.timer_data().get("db.latency.count"));
+
+        assert!(router.route(&buckets).is_none());
+        assert!(router.drain().is_some());
+        assert!(router.drain().is_none());
+    }
+
+    #[test]
+    fn test_slower_interval_derives_series() {
+        let routes = Routes::parse("graphite=api.**", "", "", "graphite=20", 10).unwrap();
+        let mut router = Router::new(routes.get("graphite"));
+        let mut buckets = make_buckets();
+        buckets.set_rollups(Rollups::parse("sum:api.*=api.all,sum:db.*=db.all").unwrap());
+        buckets.process();
+        assert!(router.route(&buckets).is_none());
+        buckets.process();
+        let flushed = router.route(&buckets).unwrap();
+
+        // Roll-ups cover both flushes, internal series are not summed.
+        assert!(flushed.all_counters().any(|(key, value)| key == "api.all" && *value == 4.0));
+        assert!(!flushed.all_counters().any(|(key, _)| key == "db.all"));
+        assert_eq!(None, flushed.counters().get("statsd.processing_time"));
+    }
+}
@@ -0,0 +1,215 @@
+//! Ingest rules rewrite, drop or retype metrics before they reach the buckets.
+//!
+//! Rules are loaded from a file with one rule per line:
+//!
+//! ```text
+//! # Comments and blank lines are ignored.
+//! rename ^app\.(\w+)\.requests$ requests.$1
+//! drop   ^debug\.
+//! allow  ^(requests|db)\.
+//! type   ^queue\..*\.depth$ g
+//! ```
+//!
+//! Rules are applied in file order. `rename` replaces the name (capture
+//! groups can be referenced as `$1`) and continues with the new name.
+//! `drop` discards the metric. `type` forces the metric kind to `c`, `g`
+//! or `ms`. When any `allow` rule exists, a metric has to match at least
+//! one of them or it is discarded.
+
+use crate::metric::{Metric, MetricKind};
+use regex::Regex;
+use std::fmt::Write;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug)]
+enum Action {
+    Rename(String),
+    Drop,
+    Allow,
+    ForceType(MetricKind),
+}
+
+#[derive(Debug)]
+struct Rule {
+    line: String,
+    pattern: Regex,
+    action: Action,
+    hits: AtomicUsize,
+}
+
+/// The ordered set of ingest rules and their hit counters.
+///
+/// Counters are atomic so the admin console can read them while
+/// the main loop applies the rules.
+#[derive(Debug)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    has_allow: bool,
+    not_allowed: AtomicUsize,
+}
+
+impl Rules {
+    /// Create an empty rule set that passes every metric through.
+    pub fn new() -> Rules {
+        Rules {
+            rules: Vec::new(),
+            has_allow: false,
+            not_allowed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Load the rules from a file.
+    pub fn load(path: &str) -> Result<Rules, String> {
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read rules file {}: {}", path, e))?;
+        Rules::parse(&source)
+    }
+
+    /// Parse rules from the text of a rules file.
+    pub fn parse(source: &str) -> Result<Rules, String> {
+        let mut rules = Rules::new();
+        for (number, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let invalid = |reason: &str| format!("Invalid rule on line {}: {}", number + 1, reason);
+
+            let action = match (parts[0], parts.len()) {
+                ("rename", 3) => Action::Rename(parts[2].to_string()),
+                ("drop", 2) => Action::Drop,
+                ("allow", 2) => Action::Allow,
+                ("type", 3) => Action::ForceType(match parts[2] {
+                    "c" => MetricKind::Counter(1.0),
+                    "g" => MetricKind::Gauge,
+                    "ms" => MetricKind::Timer,
+                    _ => return Err(invalid("type must be one of c, g or ms")),
+                }),
+                ("rename", _) | ("type", _) => return Err(invalid("expected 2 arguments")),
+                ("drop", _) | ("allow", _) => return Err(invalid("expected 1 argument")),
+                (x, _) => return Err(invalid(&format!("unknown action `{}`", x))),
+            };
+            let pattern = Regex::new(parts[1]).map_err(|e| invalid(&e.to_string()))?;
+
+            if let Action::Allow = action {
+                rules.has_allow = true;
+            }
+            rules.rules.push(Rule {
+                line: line.to_string(),
+                pattern: pattern,
+                action: action,
+                hits: AtomicUsize::new(0),
+            });
+        }
+        Ok(rules)
+    }
+
+    /// Apply the rules to a metric.
+    ///
+    /// Returns `None` when the metric should be discarded.
+    pub fn apply(&self, mut metric: Metric) -> Option<Metric> {
+        let mut allowed = !self.has_allow;
+        for rule in self.rules.iter() {
+            if !rule.pattern.is_match(&metric.name) {
+                continue;
+            }
+            rule.hits.fetch_add(1, Ordering::Relaxed);
+            match rule.action {
+                Action::Rename(ref replacement) => {
+                    metric.name = rule.pattern.replace(&metric.name, replacement.as_str()).into_owned();
+                }
+                Action::Drop => return None,
+                Action::Allow => allowed = true,
+                Action::ForceType(ref kind) => {
+                    metric.kind = match (kind, metric.kind) {
+                        // A sampled counter keeps its sample rate.
+                        (&MetricKind::Counter(_), MetricKind::Counter(rate)) => MetricKind::Counter(rate),
+                        (&MetricKind::Counter(_), _) => MetricKind::Counter(1.0),
+                        (&MetricKind::Gauge, _) => MetricKind::Gauge,
+                        (&MetricKind::Timer, _) => MetricKind::Timer,
+                    };
+                }
+            }
+        }
+        if allowed {
+            Some(metric)
+        } else {
+            self.not_allowed.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+
+    /// Format the rules with their hit counters for the admin console.
+    pub fn format_hits(&self) -> String {
+        let mut out = String::new();
+        for rule in self.rules.iter() {
+            writeln!(out, " {}: {}", rule.line, rule.hits.load(Ordering::Relaxed)).unwrap();
+        }
+        if self.has_allow {
+            writeln!(out, " not allowed: {}", self.not_allowed.load(Ordering::Relaxed)).unwrap();
+        }
+        out
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn apply(rules: &Rules, name: &str, kind: MetricKind) -> Option<Metric> {
+        rules.apply(Metric::new(name, 1.0, kind))
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Rules::parse("rename ^a").is_err());
+        assert!(Rules::parse("drop ^a ^b").is_err());
+        assert!(Rules::parse("type ^a x").is_err());
+        assert!(Rules::parse("keep ^a").is_err());
+        assert!(Rules::parse("drop (").is_err());
+        assert!(Rules::parse("# comment\n\n").unwrap().rules.is_empty());
+    }
+
+    #[test]
+    fn test_rename_drop_and_type() {
+        let rules = Rules::parse("
+            rename ^app\\.(\\w+)\\.requests$ requests.$1
+            drop ^debug\\.
+            type ^queue\\. g
+            type ^hits\\. c
+        ").unwrap();
+
+        let metric = apply(&rules, "app.web.requests", MetricKind::Timer).unwrap();
+        assert_eq!("requests.web", metric.name);
+
+        assert!(apply(&rules, "debug.thing", MetricKind::Timer).is_none());
+
+        let metric = apply(&rules, "queue.depth", MetricKind::Counter(0.5)).unwrap();
+        assert_eq!("Gauge", format!("{:?}", metric.kind));
+
+        let metric = rules.apply(Metric::parse("hits.api:1|c|@0.1").unwrap().remove(0)).unwrap();
+        assert_eq!("Counter(s=0.1)", format!("{:?}", metric.kind));
+        let metric = apply(&rules, "hits.db", MetricKind::Timer).unwrap();
+        assert_eq!("Counter(s=1)", format!("{:?}", metric.kind));
+
+        let output = rules.format_hits();
+        assert!(output.contains("rename ^app\\.(\\w+)\\.requests$ requests.$1: 1\n"));
+        assert!(output.contains("drop ^debug\\.: 1\n"));
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let rules = Rules::parse("
+            rename ^legacy\\. app.
+            allow ^app\\.
+        ").unwrap();
+
+        assert!(apply(&rules, "app.requests", MetricKind::Gauge).is_some());
+        assert!(apply(&rules, "legacy.requests", MetricKind::Gauge).is_some());
+        assert!(apply(&rules, "other.requests", MetricKind::Gauge).is_none());
+        assert!(rules.format_hits().contains(" not allowed: 1\n"));
+    }
+}
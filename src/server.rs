@@ -19,6 +19,7 @@ pub enum Event {
     TcpMessage(TcpStream),
     ZmqBatch(UnpackedZmqBatch),
     TimerFlush,
+    Shutdown,
 }
 
 pub struct LineCodec;